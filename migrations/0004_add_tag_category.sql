@@ -0,0 +1,2 @@
+create unique index if not exists tag_type_type_uindex
+    on tag_type (type);
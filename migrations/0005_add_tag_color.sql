@@ -0,0 +1,2 @@
+alter table tag
+    add color TEXT;
@@ -0,0 +1,10 @@
+create table if not exists tag_alias
+(
+    alias TEXT    not null
+        constraint tag_alias_pk
+            primary key,
+    tag   INTEGER not null
+        constraint tag_alias_tag_id_fk
+            references tag
+            on update cascade on delete cascade
+);
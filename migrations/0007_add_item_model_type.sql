@@ -0,0 +1,2 @@
+alter table item
+    add model_type TEXT default '' not null;
@@ -0,0 +1,2 @@
+alter table item
+    add nsfw_level integer;
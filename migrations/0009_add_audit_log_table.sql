@@ -0,0 +1,14 @@
+create table if not exists audit_log
+(
+    id         integer                                  not null
+        constraint audit_log_pk
+            primary key autoincrement,
+    action     text                                     not null,
+    item       integer,
+    actor      text,
+    detail     text    default ''                       not null,
+    created_at integer default (strftime('%s', 'now')) not null
+);
+
+create index if not exists audit_log_item_index
+    on audit_log (item);
@@ -0,0 +1 @@
+alter table item add deleted_at integer;
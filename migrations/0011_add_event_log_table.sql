@@ -0,0 +1,9 @@
+create table if not exists event_log
+(
+    id         integer                                  not null
+        constraint event_log_pk
+            primary key autoincrement,
+    level      integer                                  not null,
+    msg        text                                     not null,
+    created_at integer default (strftime('%s', 'now')) not null
+);
@@ -0,0 +1,4 @@
+alter table item add sha256 text;
+
+create index if not exists item_sha256_index
+    on item (sha256);
@@ -0,0 +1 @@
+alter table item add source_url text;
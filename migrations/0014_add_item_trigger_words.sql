@@ -0,0 +1 @@
+alter table item add trigger_words text;
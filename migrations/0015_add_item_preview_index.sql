@@ -0,0 +1 @@
+alter table item add preview_index integer not null default 0;
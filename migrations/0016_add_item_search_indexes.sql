@@ -0,0 +1,7 @@
+-- `item (path, base_label)` and `tag_item` are indexed by their unique constraints already.
+-- Searches sort and paginate by `COALESCE(updated_at, 0)`, then by id which is the rowid of the index.
+create index if not exists item_is_checked_updated_at_index
+    on item (is_checked, COALESCE(updated_at, 0));
+
+create index if not exists item_blake3_index
+    on item (blake3);
@@ -1,49 +1,103 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
 mod config;
+mod error;
 mod item;
+mod job;
 mod maintenance;
+mod rate_limit;
 mod tag;
-mod job;
+mod version;
 
-use crate::civitai::{calculate_blake3, CivitaiFileMetadata, PREVIEW_EXT};
-use crate::db::item::insert_or_update;
-use crate::db::tag::add_tag_from_model_info;
+use crate::civitai::{
+    calculate_blake3, calculate_hashes, infer_model_type, model_page_url, parse_nsfw_level, CivitaiFileMetadata,
+};
+use crate::config::{Config, DisplayName};
+use crate::db::audit::AuditAction;
+use crate::db::item::{
+    get_unchanged_hashes, insert_or_update, set_nsfw_level, set_trigger_words, ItemUpsert, UpsertResult,
+};
+use crate::db::tag::{add_tag_from_model_info, add_tag_item, resolve_aliases};
 use crate::db::DBPool;
-use actix_web::web;
+use crate::BASE_PATH_PREFIX;
+use actix_web::http::header::Header;
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpRequest};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
-use std::time::UNIX_EPOCH;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs;
-use tracing::error;
-use crate::BASE_PATH_PREFIX;
-use crate::config::Config;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
 
-pub const TRASH_DIR: &str = ".trash";
+pub(crate) use maintenance::spawn_scheduled_scan;
+pub(crate) use version::VERSION;
+
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
 
 pub fn scope_config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api")
+            .wrap(from_fn(rate_limit::limit))
             .configure(maintenance::scope)
             .configure(item::scope)
             .configure(tag::scope)
             .configure(job::scope)
-            .configure(config::scope),
+            .configure(config::scope)
+            .configure(version::scope),
     );
 }
 
 #[derive(Deserialize, Default)]
 pub struct SearchQuery {
-    /// Highest priority
-    id: Option<i64>,
+    /// Highest priority. Repeatable or comma separated to fetch several items in the requested order.
+    #[serde(default)]
+    id: Vec<String>,
 
     page: Option<i64>,
     count: Option<i64>,
+    /// Cursor of the last seen item. Used instead of `page` if set.
+    after: Option<String>,
     #[serde(default)]
     pub(crate) search: String,
     tag_only: Option<bool>,
     duplicate_only: Option<bool>,
+    /// Show only one item of each group of identical files. Its `duplicate_count` is the size of the group.
+    group_duplicates: Option<bool>,
+    /// Only items without any tag
+    untagged_only: Option<bool>,
+    /// Epoch millis or ISO date (`2025-01-31` or RFC 3339). Inclusive.
+    added_after: Option<String>,
+    /// Epoch millis or ISO date (`2025-01-31` or RFC 3339). Exclusive.
+    added_before: Option<String>,
+    /// Model type on Civitai like `Checkpoint`, `LORA`
+    model_type: Option<String>,
+    /// Highest NSFW level to show. 0 to show all. Use config value if not set.
+    max_nsfw_level: Option<i64>,
+    /// Only search in these model paths. Repeatable. All model paths if empty.
+    #[serde(default)]
+    base_label: Vec<String>,
+    /// File extension of models like `ckpt`, with or without the leading dot
+    ext: Option<String>,
+    /// `summary` to leave `info` and `description` of items empty. `full` by default.
+    /// A page of 100 items with 30 KB `.json` sidecars is 8 KB instead of 709 KB in summary.
+    fields: Option<String>,
+    /// Only return `tags` and `total`. `items` is always empty.
+    facets_only: Option<bool>,
+    /// Name to show as `name` of items. Use config value if not set.
+    display_name: Option<DisplayName>,
+    /// Number of tags to return, most used first. 0 to return all. Use config value if not set.
+    tag_limit: Option<i64>,
+    /// Number of tags to skip, to get more tags
+    tag_offset: Option<i64>,
+    /// Check if model files of items exist on disk, as of listing their directories for previews
+    check_exists: Option<bool>,
+    /// Return query plans and timing of the search in `debug`. Only if it's allowed in config.
+    explain: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -55,32 +109,45 @@ struct DeleteRequest {
 #[derive(Serialize, Default)]
 struct CommonResponse {
     msg: String,
-    err: Option<String>,
 }
 
 impl CommonResponse {
-    pub fn from_err(err: &str) -> Self {
-        Self {
-            err: Some(err.to_string()),
-            ..Self::default()
-        }
+    pub fn from_msg(msg: &str) -> Self {
+        Self { msg: msg.to_string() }
     }
+}
 
-    pub fn from_msg(msg: &str) -> Self {
-        Self {
-            msg: msg.to_string(),
-            ..Self::default()
-        }
+/// Basic auth user of a request, recorded as actor of audit log
+fn request_actor(req: &HttpRequest) -> Option<String> {
+    Authorization::<Basic>::parse(req)
+        .ok()
+        .map(|auth| auth.into_scheme().user_id().to_string())
+        .filter(|user| !user.is_empty())
+}
+
+/// Write audit log of an item. Failure is only logged so it does not fail the change itself.
+async fn audit(db_pool: &DBPool, action: AuditAction, item: Option<i64>, actor: Option<&str>, detail: &str) {
+    if let Err(e) = crate::db::audit::add(&db_pool.sqlite_pool, action, item, actor, detail).await {
+        error!("Failed to write audit log: {e}");
     }
 }
 
-fn get_relative_path(base_path: &str, path: &Path) -> Result<String, anyhow::Error> {
+pub(crate) fn get_relative_path(base_path: &str, path: &Path) -> Result<String, anyhow::Error> {
     let base = PathBuf::from(base_path);
     let path = path.strip_prefix(&base)?;
     Ok(path.to_str().unwrap_or_default().to_string())
 }
 
-async fn save_model_info(db_pool: &DBPool, path: &Path, label: &str, relative_path: &str) {
+/// Index a model file. Tags and NSFW level are only derived again if the file is changed or `force` is set, e.g.
+/// after its Civitai info is refreshed. Return id of the item, or `None` if it can't be saved.
+pub(crate) async fn save_model_info(
+    config: &Config,
+    db_pool: &DBPool,
+    path: &Path,
+    label: &str,
+    relative_path: &str,
+    force: bool,
+) -> Option<i64> {
     let mut item_json_file = PathBuf::from(path);
     item_json_file.set_extension("json");
     let mut model_json_file = PathBuf::from(path);
@@ -92,27 +159,11 @@ async fn save_model_info(db_pool: &DBPool, path: &Path, label: &str, relative_pa
     let model_parsed: Value = serde_json::from_str(&model_info).unwrap_or_default();
 
     let base_model = item_parsed["baseModel"].as_str().unwrap_or_default();
-
-    let mut blake3 = item_parsed["files"][0]["hashes"]["BLAKE3"]
-        .as_str()
-        .unwrap_or_default()
-        .to_string()
-        .to_lowercase();
-    let mut file_metadata =
-        serde_json::from_value::<CivitaiFileMetadata>(item_parsed["files"][0]["metadata"].clone()).unwrap_or_default();
-    if let Some(files) = item_parsed["files"].as_array() {
-        // If there are more than 1 file, find the metadata by hash
-        if files.len() > 1 {
-            blake3 = calculate_blake3(path).unwrap_or_default().to_lowercase();
-            for file in files.iter() {
-                let hash = file["hashes"]["BLAKE3"].as_str().unwrap_or_default().to_lowercase();
-                if blake3 == hash {
-                    file_metadata =
-                        serde_json::from_value::<CivitaiFileMetadata>(file["metadata"].clone()).unwrap_or_default();
-                }
-            }
-        }
-    }
+    // Local models without metadata are typed by their directory
+    let model_type = match model_parsed["type"].as_str() {
+        Some(model_type) => model_type,
+        None => infer_model_type(config, Path::new(relative_path)).unwrap_or_default(),
+    };
     let name = path
         .file_name()
         .unwrap_or_default()
@@ -122,32 +173,165 @@ async fn save_model_info(db_pool: &DBPool, path: &Path, label: &str, relative_pa
 
     // Read file metadata on disk
     let mut modified_time = 0;
-    if let Ok(local_metadata) = fs::metadata(path).await {
-        if let Ok(modified) = local_metadata.modified() {
-            modified_time = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    if let Ok(local_metadata) = fs::metadata(path).await
+        && let Ok(modified) = local_metadata.modified()
+    {
+        modified_time = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    }
+
+    let files = item_parsed["files"].as_array().map(Vec::as_slice).unwrap_or_default();
+    // Don't hash an unmodified file again
+    let unchanged = get_unchanged_hashes(&db_pool.sqlite_pool, relative_path, label, modified_time as i64)
+        .await
+        .unwrap_or_default();
+    let mut sha256 = unchanged
+        .as_ref()
+        .and_then(|(_, sha256)| sha256.clone())
+        .unwrap_or_default();
+    let mut blake3 = if files.len() > 1 {
+        // If there are more than 1 file, find the hash of this file
+        match unchanged {
+            Some((blake3, _)) => blake3,
+            None if config.compute_sha256 => {
+                let (blake3, file_sha256) = hash_file(path, true).await;
+                sha256 = file_sha256;
+                blake3
+            }
+            None => hash_file(path, false).await.0.to_lowercase(),
+        }
+    } else {
+        item_parsed["files"][0]["hashes"]["BLAKE3"]
+            .as_str()
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+
+    // Find the metadata of this file by hash
+    let file_info = files
+        .iter()
+        .find(|file| {
+            file["hashes"]["BLAKE3"]
+                .as_str()
+                .is_some_and(|hash| hash.eq_ignore_ascii_case(&blake3))
+        })
+        .or(files.first());
+    if let Some(civitai_sha256) = file_info.and_then(|file| file["hashes"]["SHA256"].as_str()) {
+        sha256 = civitai_sha256.to_lowercase();
+    }
+    if sha256.is_empty() && config.compute_sha256 {
+        let (file_blake3, file_sha256) = hash_file(path, true).await;
+        sha256 = file_sha256;
+        if blake3.is_empty() {
+            blake3 = file_blake3;
+        }
+    }
+
+    let source_url = match (item_parsed["modelId"].as_i64(), item_parsed["id"].as_i64()) {
+        (Some(model_id), Some(version_id)) => Some(model_page_url(model_id, version_id)),
+        _ => None,
+    };
+
+    let item = ItemUpsert {
+        name: Some(name.as_str()),
+        model_name: model_parsed["name"].as_str().unwrap_or_default(),
+        path: relative_path,
+        base_label: label,
+        blake3: blake3.as_str(),
+        sha256: Some(sha256.as_str()).filter(|sha256| !sha256.is_empty()),
+        source_url: source_url.as_deref(),
+        updated_at_ms: modified_time as i64,
+        model_type,
+    };
+    let id = match insert_or_update(db_pool, &item).await {
+        Ok(UpsertResult::Unchanged(id)) if !force => {
+            // Trigger words file is not tracked by the item, so it may be changed alone
+            save_txt_trigger_words(config, db_pool, path, id, &item_parsed).await;
+            return Some(id);
+        }
+        Ok(result) => result.id(),
+        Err(e) => {
+            error!("Failed to insert item: {}", e);
+            return None;
         }
+    };
+
+    let nsfw_level = parse_nsfw_level(&item_parsed, &model_parsed);
+    if let Err(e) = set_nsfw_level(db_pool, id, nsfw_level).await {
+        error!("Failed to save NSFW level: {}", e);
+    }
+
+    let file_metadata = file_info
+        .and_then(|file| serde_json::from_value::<CivitaiFileMetadata>(file["metadata"].clone()).ok())
+        .unwrap_or_default();
+    let tags = vec![base_model.to_string()];
+    if let Err(e) = add_tag_from_model_info(db_pool, id, &tags, &model_parsed, &file_metadata).await {
+        error!("Failed to insert tag: {}", e);
     }
 
-    match insert_or_update(
-        &db_pool.sqlite_pool,
-        Some(name.as_str()),
-        relative_path,
-        label,
-        blake3.as_str(),
-        modified_time as i64,
-    )
+    save_txt_trigger_words(config, db_pool, path, id, &item_parsed).await;
+    Some(id)
+}
+
+/// Save trigger words of the `.txt` file next to model `path` if its Civitai metadata `item_parsed` has none.
+/// They are added as tags only when they are changed.
+async fn save_txt_trigger_words(config: &Config, db_pool: &DBPool, path: &Path, id: i64, item_parsed: &Value) {
+    // Community packs ship trigger words in a plain text file instead of Civitai metadata
+    let has_trained_words = item_parsed["trainedWords"]
+        .as_array()
+        .is_some_and(|words| !words.is_empty());
+    let trigger_words = if config.txt_trigger_words && !has_trained_words {
+        let text = fs::read_to_string(path.with_extension("txt")).await.unwrap_or_default();
+        parse_trigger_words(&text)
+    } else {
+        Vec::new()
+    };
+    let joined = Some(trigger_words.join(", ")).filter(|words| !words.is_empty());
+    let changed = match set_trigger_words(&db_pool.sqlite_pool, id, joined.as_deref()).await {
+        Ok(changed) => changed,
+        Err(e) => {
+            error!("Failed to save trigger words: {}", e);
+            return;
+        }
+    };
+    if changed && config.txt_trigger_words_as_tags && !trigger_words.is_empty() {
+        let added = match resolve_aliases(&db_pool.sqlite_pool, trigger_words).await {
+            Ok(tags) => match db_pool.sqlite_pool.acquire().await {
+                Ok(mut conn) => add_tag_item(&mut conn, id, &tags).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match added {
+            Ok(()) => db_pool.count_cache.invalidate(),
+            Err(e) => error!("Failed to tag trigger words: {}", e),
+        }
+    }
+}
+
+/// Hash a model file in a blocking thread, because it can take long for a large file. Return (BLAKE3, SHA256).
+/// SHA256 is only computed if `with_sha256` is set. Hashes are empty if the file can't be read.
+async fn hash_file(path: &Path, with_sha256: bool) -> (String, String) {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        if with_sha256 {
+            calculate_hashes(&path).unwrap_or_default()
+        } else {
+            (calculate_blake3(&path).unwrap_or_default(), String::new())
+        }
+    })
     .await
-    {
-        Ok(id) => {
-            let tags = vec![base_model.to_string()];
-            if let Err(e) =
-                add_tag_from_model_info(&db_pool.sqlite_pool, id, &tags, &model_parsed, &file_metadata).await
-            {
-                error!("Failed to insert tag: {}", e);
-            }
+    .unwrap_or_default()
+}
+
+/// Trigger words separated by commas or new lines, without duplicates
+fn parse_trigger_words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    for word in text.split([',', '\n']).map(str::trim).filter(|word| !word.is_empty()) {
+        if !words.iter().any(|w| w == word) {
+            words.push(word.to_string());
         }
-        Err(e) => error!("Failed to insert item: {}", e),
     }
+    words
 }
 
 /// Return abs path of (model, json) and http path of preview
@@ -156,7 +340,7 @@ fn get_abs_path(config: &Config, label: &str, rel_path: &str) -> (String, String
         (String::new(), String::new(), String::new(), String::new());
     if let Some(base_path) = config.model_paths.get(label) {
         let base_path = PathBuf::from(base_path);
-        let model_path = base_path.join(rel_path);
+        let model_path = long_path(&base_path.join(rel_path));
         model = model_path.to_str().unwrap_or_default().to_string();
 
         let mut json_path = model_path.clone();
@@ -169,9 +353,355 @@ fn get_abs_path(config: &Config, label: &str, rel_path: &str) -> (String, String
 
         let img_path = PathBuf::from(format!("/{}{}", BASE_PATH_PREFIX, label));
         let mut preview_path = img_path.join(rel_path);
-        preview_path.set_extension(PREVIEW_EXT);
+        preview_path.set_extension(config.preview_ext());
         preview = preview_path.to_str().unwrap_or_default().to_string();
     }
 
     (model, json, model_json, preview)
 }
+
+async fn move_to_dir(files: &[PathBuf], dir: &Path) -> anyhow::Result<()> {
+    for file in files {
+        let file_name = file.file_name().unwrap_or_default();
+        if !file_name.is_empty() {
+            let dest = dir.join(file_name);
+            fs::rename(long_path(file), long_path(&dest)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Path which can exceed `MAX_PATH` on Windows. Absolute paths are prefixed with `\\?\` (or `\\?\UNC\` for
+/// network shares), which also disables normalization, so the path is made absolute and normalized first.
+#[cfg(windows)]
+pub(crate) fn long_path(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let path_str = path.to_string_lossy().into_owned();
+    if path_str.starts_with(r"\\?\") || path_str.starts_with(r"\\.\") {
+        path
+    } else if let Some(share) = path_str.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{share}"))
+    } else if path.is_absolute() {
+        PathBuf::from(format!(r"\\?\{path_str}"))
+    } else {
+        path
+    }
+}
+
+/// Path which can exceed `MAX_PATH` on Windows. Other platforms have no such limit.
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// List files which have the same name as the model file, including gallery images. Other model files are excluded.
+/// The model file may be gone already, then only the files which were left behind are listed.
+fn list_same_filename(path: &Path, config: &Config) -> std::io::Result<Vec<PathBuf>> {
+    if path.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let stem = path.file_stem().unwrap_or_default(); // "filename"
+
+    // Entries are joined to `dir` so that they are comparable with `path` even if `dir` is prefixed
+    let matches = std::fs::read_dir(long_path(dir))?
+        .filter_map(Result::ok)
+        .map(|entry| dir.join(entry.file_name()))
+        .filter(|p| p.is_file() && (p.file_stem() == Some(stem) || gallery_index(p, stem).is_some()))
+        .filter(|p| p == path || !config.is_model_file(p))
+        .collect();
+
+    Ok(matches)
+}
+
+/// Resolve `path` to its real path and return it if it's inside one of model paths.
+/// `path` may not exist yet, then its nearest existing ancestor is resolved. `..` after that is rejected.
+pub(crate) fn resolve_in_model_paths(path: &Path, config: &Config) -> Option<PathBuf> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        rest.push(existing.file_name()?);
+        existing = existing.parent()?;
+    }
+
+    let mut real_path = std::fs::canonicalize(existing).ok()?;
+    real_path.extend(rest.iter().rev());
+    config
+        .model_paths
+        .values()
+        .filter_map(|base_path| std::fs::canonicalize(base_path).ok())
+        .any(|base_path| real_path.starts_with(base_path))
+        .then_some(real_path)
+}
+
+/// Remove trashed files of items deleted more than `trash_retention_days` ago, then remove the items.
+/// Return number of purged items.
+pub(crate) async fn purge_expired_trash(config: &Config, db_pool: &DBPool) -> anyhow::Result<usize> {
+    if config.trash_retention_days == 0 {
+        return Ok(0);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let deleted_before = now - (config.trash_retention_days * 24 * 3600) as i64;
+    let items = crate::db::item::list_deleted_before(&db_pool.sqlite_pool, deleted_before).await?;
+
+    let mut purged = 0;
+    for item in items {
+        let Some(trash_path) = config.trash_path(&item.base_label) else {
+            continue;
+        };
+        let model_path = Path::new(&item.path);
+        let Some(stem) = model_path.file_stem() else {
+            continue;
+        };
+        // Only files of this item, not of newer items with the same name
+        let model_file = trash_path.join(trash_file_name(model_path, stem, item.id));
+        let mut files = list_same_filename(&model_file, config).unwrap_or_default();
+        files.push(model_file.with_extension("model.json"));
+        for file in files.iter().filter(|f| f.is_file()) {
+            if let Err(e) = fs::remove_file(file).await {
+                error!("Failed to remove {}: {}", file.display(), e);
+            }
+        }
+
+        crate::db::item::delete(db_pool, item.id).await?;
+        purged += 1;
+    }
+
+    Ok(purged)
+}
+
+/// Name of a file of item `id` in the trash directory, which is flat. `~<id>` is added to the stem of the model file
+/// so that items with the same file name from different directories don't overwrite or purge files of each other.
+fn trash_file_name(file: &Path, model_stem: &OsStr, id: i64) -> String {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let stem = model_stem.to_string_lossy();
+    match name.strip_prefix(stem.as_ref()) {
+        Some(rest) => format!("{stem}~{id}{rest}"),
+        None => format!("{id}~{name}"),
+    }
+}
+
+/// Move a model file of item `id` with its sidecar files to `trash_dir`, named by `trash_file_name`.
+/// Moved files are added to `moved`, even if it fails halfway.
+async fn move_files_to_trash(
+    config: &Config,
+    id: i64,
+    model_file: &Path,
+    trash_dir: &Path,
+    moved: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let mut files = list_same_filename(model_file, config).unwrap_or_default();
+    // Remove *.model.json file
+    let model_json = model_file.with_extension("model.json");
+    if model_json.is_file() {
+        files.push(model_json);
+    }
+
+    let stem = model_file.file_stem().unwrap_or_default();
+    for file in files {
+        let dest = trash_dir.join(trash_file_name(&file, stem, id));
+        if let Err(e) = fs::rename(long_path(&file), long_path(&dest)).await {
+            error!("Failed to move {} to trash directory: {}", file.display(), e);
+            return Err(format!("Failed to move {} to trash directory: {e}", file.display()));
+        }
+        moved.push(file);
+    }
+    Ok(())
+}
+
+/// Purge expired trash periodically. Disabled if `trash_retention_days` is 0.
+pub(crate) fn spawn_trash_purger(config: &Config, db_pool: Arc<DBPool>) -> Option<JoinHandle<()>> {
+    if config.trash_retention_days == 0 {
+        return None;
+    }
+
+    let config = config.clone();
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match purge_expired_trash(&config, &db_pool).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} expired items from trash", purged),
+                Err(e) => error!("Failed to purge expired trash: {}", e),
+            }
+        }
+    }))
+}
+
+/// Return index of gallery image `<stem>.<index>.<ext>`
+fn gallery_index(path: &Path, stem: &OsStr) -> Option<usize> {
+    let file_stem = path.file_stem()?.to_str()?;
+    let index = file_stem.strip_prefix(stem.to_str()?)?.strip_prefix('.')?;
+    index.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use std::collections::HashSet;
+
+    #[test]
+    fn resolve_in_model_paths_accepts_paths_inside() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("models");
+        std::fs::create_dir_all(model_path.join("loras")).unwrap();
+        let config = Config::with_model_path(&model_path);
+
+        let real_model_path = std::fs::canonicalize(&model_path).unwrap();
+        assert_eq!(
+            resolve_in_model_paths(&model_path.join("loras"), &config),
+            Some(real_model_path.join("loras"))
+        );
+        assert_eq!(
+            resolve_in_model_paths(&model_path.join("new/dir/model.safetensors"), &config),
+            Some(real_model_path.join("new/dir/model.safetensors"))
+        );
+    }
+
+    #[test]
+    fn resolve_in_model_paths_rejects_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("models");
+        std::fs::create_dir_all(model_path.join("loras")).unwrap();
+        std::fs::create_dir_all(dir.path().join("outside")).unwrap();
+        let config = Config::with_model_path(&model_path);
+
+        assert_eq!(resolve_in_model_paths(&model_path.join("../outside"), &config), None);
+        assert_eq!(
+            resolve_in_model_paths(&model_path.join("loras/../../outside"), &config),
+            None
+        );
+        assert_eq!(
+            resolve_in_model_paths(&model_path.join("new/../../outside"), &config),
+            None
+        );
+        assert_eq!(resolve_in_model_paths(&model_path.join(".."), &config), None);
+        assert_eq!(resolve_in_model_paths(dir.path(), &config), None);
+    }
+
+    #[tokio::test]
+    async fn expired_trash_is_purged_without_newer_items_of_same_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("models");
+        let config = Config {
+            trash_retention_days: 1,
+            ..Config::with_model_path(&model_path)
+        };
+        let db_pool = DBPool::memory().await;
+        let trash_dir = config.trash_path("test").unwrap();
+        std::fs::create_dir_all(&trash_dir).unwrap();
+
+        let mut ids = Vec::new();
+        for sub_dir in ["old", "new"] {
+            let rel_path = format!("{sub_dir}/model.safetensors");
+            let model_file = model_path.join(&rel_path);
+            std::fs::create_dir_all(model_file.parent().unwrap()).unwrap();
+            for ext in ["safetensors", "json", "png"] {
+                std::fs::write(model_file.with_extension(ext), sub_dir).unwrap();
+            }
+            let id = db::insert_test_item(&db_pool, &rel_path, sub_dir, 0).await;
+            crate::db::item::mark_obsolete(&db_pool, &[id]).await.unwrap();
+            move_files_to_trash(&config, id, &model_file, &trash_dir, &mut Vec::new())
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+        let (old_id, new_id) = (ids[0], ids[1]);
+        sqlx::query("UPDATE item SET deleted_at = 0 WHERE id = ?")
+            .bind(old_id)
+            .execute(&db_pool.sqlite_pool)
+            .await
+            .unwrap();
+
+        assert_eq!(purge_expired_trash(&config, &db_pool).await.unwrap(), 1);
+
+        let mut trashed = std::fs::read_dir(&trash_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        trashed.sort();
+        let expected = ["json", "png", "safetensors"].map(|ext| format!("model~{new_id}.{ext}"));
+        assert_eq!(trashed, expected);
+        assert_eq!(std::fs::read_to_string(trash_dir.join(&expected[0])).unwrap(), "new");
+        assert!(crate::db::item::get_by_id(&db_pool.sqlite_pool, old_id).await.is_err());
+        assert!(crate::db::item::get_by_id(&db_pool.sqlite_pool, new_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn txt_trigger_words_are_saved_without_trained_words() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            txt_trigger_words_as_tags: true,
+            ..Config::with_model_path(dir.path())
+        };
+        let db_pool = DBPool::memory().await;
+        for name in ["pack", "civitai"] {
+            std::fs::write(dir.path().join(format!("{name}.safetensors")), name).unwrap();
+            std::fs::write(
+                dir.path().join(format!("{name}.txt")),
+                "cute style, Anime\nchibi,, cute style\n",
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.path().join("civitai.json"), r#"{"trainedWords": ["trained"]}"#).unwrap();
+
+        let save = |name: &str| {
+            let relative_path = format!("{name}.safetensors");
+            let path = dir.path().join(&relative_path);
+            let (config, db_pool) = (&config, &db_pool);
+            async move {
+                save_model_info(config, db_pool, &path, "test", &relative_path, true)
+                    .await
+                    .unwrap()
+            }
+        };
+        let pack = save("pack").await;
+        assert_eq!(
+            db::item::get_trigger_words(&db_pool.sqlite_pool, pack)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("cute style, Anime, chibi")
+        );
+        let tags = db::tag::list_tags(&db_pool.sqlite_pool, HashSet::from([pack]))
+            .await
+            .unwrap();
+        let mut tags = tags.into_iter().map(|tag| tag.tag).collect::<Vec<_>>();
+        tags.sort();
+        assert_eq!(tags, ["anime", "chibi", "cute_style"]);
+
+        // Edited trigger words are saved by a scan, which doesn't index the unchanged model again
+        std::fs::write(dir.path().join("pack.txt"), "cute style, sketch").unwrap();
+        let relative_path = "pack.safetensors";
+        save_model_info(
+            &config,
+            &db_pool,
+            &dir.path().join(relative_path),
+            "test",
+            relative_path,
+            false,
+        )
+        .await;
+        assert_eq!(
+            db::item::get_trigger_words(&db_pool.sqlite_pool, pack)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("cute style, sketch")
+        );
+
+        // Trigger words of Civitai metadata are preferred
+        let civitai = save("civitai").await;
+        assert_eq!(
+            db::item::get_trigger_words(&db_pool.sqlite_pool, civitai)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -1,34 +1,273 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
+use crate::api::error::ApiError;
 use crate::api::CommonResponse;
 use crate::config::Config;
-use crate::ConfigData;
+use crate::db::DBPool;
+use crate::{db, ConfigData};
 use actix_web::web::Data;
 use actix_web::{get, post, web, Responder};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::error;
 
 pub fn scope(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::scope("/config").service(get).service(update));
+    cfg.service(
+        web::scope("/config")
+            .service(get)
+            .service(update)
+            .service(labels)
+            .service(disk)
+            .service(rename_label)
+            .service(civitai_key),
+    );
 }
 
+#[derive(Serialize)]
+struct ConfigResponse {
+    #[serde(flatten)]
+    config: Config,
+    /// Address which the server is listening on
+    effective_bind: String,
+    /// Bind address in config differs from the effective one
+    restart_required: bool,
+}
+
+#[derive(Serialize)]
+struct DiskUsage {
+    /// Labels of model paths on this filesystem
+    labels: Vec<String>,
+    total_bytes: u64,
+    available_bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct CivitaiKeyRequest {
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct RenameLabelRequest {
+    old: String,
+    new: String,
+}
+
+#[derive(Serialize)]
+struct RenameLabelResponse {
+    /// Number of items which are moved to the new label
+    updated: u64,
+}
+
+/// Current config with secrets redacted
 #[get("")]
 async fn get(config_data: Data<ConfigData>) -> impl Responder {
-    let config = (*config_data.config.read().await).clone();
-    web::Json(config)
+    let config = config_data.config.read().await;
+    web::Json(ConfigResponse {
+        config: config.redacted(),
+        effective_bind: config_data.bind_addr.clone(),
+        restart_required: config.bind_addr() != config_data.bind_addr,
+    })
+}
+
+/// Labels of model paths, sorted by name
+#[get("labels")]
+async fn labels(config_data: Data<ConfigData>) -> impl Responder {
+    let config = config_data.config.read().await;
+    let mut labels = config.model_paths.keys().cloned().collect::<Vec<_>>();
+    labels.sort();
+    web::Json(labels)
+}
+
+/// Total and available space of filesystems of model paths. Model paths on the same filesystem are grouped.
+#[get("disk")]
+async fn disk(config_data: Data<ConfigData>) -> impl Responder {
+    let config = config_data.config.read().await;
+    let mut model_labels = config.model_paths.keys().collect::<Vec<_>>();
+    model_labels.sort();
+
+    let mut disks: Vec<(Option<u64>, DiskUsage)> = Vec::new();
+    for label in model_labels {
+        let path = Path::new(&config.model_paths[label]);
+        let (Ok(total_bytes), Ok(available_bytes)) = (fs2::total_space(path), fs2::available_space(path)) else {
+            error!("Failed to get disk space of {}", path.display());
+            continue;
+        };
+        let device = device_id(path);
+        match disks.iter_mut().find(|(d, _)| device.is_some() && *d == device) {
+            Some((_, usage)) => usage.labels.push(label.clone()),
+            None => disks.push((
+                device,
+                DiskUsage {
+                    labels: vec![label.clone()],
+                    total_bytes,
+                    available_bytes,
+                },
+            )),
+        }
+    }
+
+    web::Json(disks.into_iter().map(|(_, usage)| usage).collect::<Vec<_>>())
+}
+
+/// Id of the filesystem which `path` is on
+#[cfg(unix)]
+fn device_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> Option<u64> {
+    None
 }
 
 #[post("update")]
-async fn update(config_data: Data<ConfigData>, data: web::Json<Config>) -> impl Responder {
+async fn update(config_data: Data<ConfigData>, data: web::Json<Config>) -> Result<impl Responder, ApiError> {
+    let mut new_config = data.into_inner();
+    new_config
+        .validate()
+        .map_err(|e| ApiError::BadRequest(format!("Invalid config: {e}")))?;
     let mut config = config_data.config.write().await;
-    *config = data.into_inner();
-    if let Err(e) = config.save(&config_data.config_path, true) {
-        web::Json(CommonResponse {
-            err: Some(format!("Failed to save config: {e}")),
-            ..Default::default()
-        })
-    } else {
-        web::Json(CommonResponse {
-            msg: "Config updated".to_string(),
-            ..Default::default()
-        })
+    new_config
+        .restore_secrets(&config)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid config: {e}")))?;
+    *config = new_config;
+    config
+        .save(&config_data.config_path, true)
+        .map_err(|e| ApiError::Internal(format!("Failed to save config: {e}")))?;
+    Ok(web::Json(CommonResponse::from_msg("Config updated")))
+}
+
+/// Rename label of a model path in config and in DB, so that indexed items are still found.
+/// If config is renamed already, only items in DB are moved to the new label.
+#[post("rename_label")]
+async fn rename_label(
+    config_data: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<RenameLabelRequest>,
+) -> Result<impl Responder, ApiError> {
+    let old = data.old.trim();
+    let new = data.new.trim();
+    if old.is_empty() || new.is_empty() || old == new {
+        return Err(ApiError::BadRequest(
+            "Old and new labels must be different and not empty".to_string(),
+        ));
+    }
+
+    // Keep the lock until config is saved so that no one else changes model paths meanwhile
+    let mut config = config_data.config.write().await;
+    let path = match (config.model_paths.get(old), config.model_paths.get(new)) {
+        (Some(old_path), Some(new_path)) if old_path != new_path => {
+            return Err(ApiError::Conflict(format!(
+                "Label {new} already exists with path {new_path}"
+            )));
+        }
+        (Some(old_path), _) => Some(old_path.clone()),
+        (None, Some(_)) => None,
+        (None, None) => return Err(ApiError::NotFound(format!("Label {old} not found"))),
+    };
+
+    let updated = db::item::rename_label(&db_pool, old, new).await?;
+
+    if let Some(path) = path {
+        config.model_paths.remove(old);
+        config.model_paths.insert(new.to_string(), path);
+        if let Some(dirs) = config.civitai.label_download_dirs.remove(old) {
+            config.civitai.label_download_dirs.insert(new.to_string(), dirs);
+        }
+        config
+            .save(&config_data.config_path, true)
+            .map_err(|e| ApiError::Internal(format!("Failed to save config: {e}")))?;
+    }
+
+    Ok(web::Json(RenameLabelResponse { updated }))
+}
+
+/// Set Civitai API key. The key is never returned by config endpoints.
+#[post("civitai_key")]
+async fn civitai_key(
+    config_data: Data<ConfigData>,
+    data: web::Json<CivitaiKeyRequest>,
+) -> Result<impl Responder, ApiError> {
+    let mut config = config_data.config.write().await;
+    config.civitai.api_key = data.api_key.trim().to_string();
+    config
+        .save(&config_data.config_path, true)
+        .map_err(|e| ApiError::Internal(format!("Failed to save config: {e}")))?;
+    Ok(web::Json(CommonResponse::from_msg("Civitai API key updated")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use std::collections::HashMap;
+    use tokio::sync::RwLock;
+
+    #[actix_web::test]
+    async fn rename_label_moves_items_and_config() {
+        let db_pool = DBPool::memory().await;
+        for (path, label) in [
+            ("a.safetensors", "old"),
+            ("b.safetensors", "old"),
+            ("c.safetensors", "other"),
+        ] {
+            sqlx::query("INSERT INTO item (path, base_label) VALUES (?, ?)")
+                .bind(path)
+                .bind(label)
+                .execute(&db_pool.sqlite_pool)
+                .await
+                .unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            model_paths: HashMap::from([
+                ("old".to_string(), "/models/a".to_string()),
+                ("other".to_string(), "/models/b".to_string()),
+            ]),
+            ..Config::default()
+        };
+        let config_data = Data::new(ConfigData {
+            config: RwLock::new(config),
+            config_path: dir.path().join("config.ron"),
+            bind_addr: String::new(),
+        });
+        let db_pool = Data::new(db_pool);
+        let app = test::init_service(
+            App::new()
+                .app_data(config_data.clone())
+                .app_data(db_pool.clone())
+                .configure(scope),
+        )
+        .await;
+
+        let rename = |old: &str, new: &str| {
+            test::TestRequest::post()
+                .uri("/config/rename_label")
+                .set_json(serde_json::json!({"old": old, "new": new}))
+                .to_request()
+        };
+
+        let res: serde_json::Value = test::call_and_read_body_json(&app, rename("old", "new")).await;
+        assert_eq!(res["updated"], 2);
+        let pool = &db_pool.sqlite_pool;
+        assert_eq!(db::item::list_by_label(pool, Some("new")).await.unwrap().len(), 2);
+        assert!(db::item::list_by_label(pool, Some("old")).await.unwrap().is_empty());
+        assert_eq!(db::item::list_by_label(pool, Some("other")).await.unwrap().len(), 1);
+
+        let config = config_data.config.read().await;
+        assert_eq!(config.model_paths.get("new").map(String::as_str), Some("/models/a"));
+        assert!(!config.model_paths.contains_key("old"));
+        drop(config);
+        let saved = Config::load(&config_data.config_path).unwrap();
+        assert!(saved.model_paths.contains_key("new"));
+
+        // Label of another path can't be taken
+        let res = test::call_service(&app, rename("new", "other")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = test::call_service(&app, rename("missing", "another")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
     }
 }
@@ -1,54 +1,144 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
-use crate::api::{get_abs_path, CommonResponse, DeleteRequest, SearchQuery, TRASH_DIR};
-use crate::civitai::{download_file, file_type, get_extension_from_url, get_item_info, FileType, PREVIEW_EXT};
-use crate::db::job::{add_job, update_job, JobState};
-use crate::db::tag::{update_item_note, update_tag_item, TagCount};
+use crate::api::error::ApiError;
+use crate::api::{
+    audit, gallery_index, get_abs_path, list_same_filename, long_path, move_files_to_trash, request_actor,
+    resolve_in_model_paths, trash_file_name, CommonResponse, SearchQuery,
+};
+use crate::civitai::{
+    air_identifier, auth_headers, calculate_blake3, calculate_hashes, check_disk_space, download_file,
+    expand_filename_template, file_type, find_case_collision, get_item_info, get_model, get_version_info,
+    model_page_url, save_info, CivitaiError, FileType,
+};
+use crate::config::{CaseCollision, Config, DisplayName};
+use crate::db::audit::AuditAction;
+use crate::db::item::{Item, QueryDebug, SearchFilter};
+use crate::db::job::{add_job, update_job, update_job_desc, JobState};
+use crate::db::tag::{normalize_tag, update_tags_and_note, TagCount};
 use crate::db::DBPool;
+use crate::gen_params::parse_png;
+use crate::render::markdown_to_html;
 use crate::ui::Broadcaster;
 use crate::{api, db, ConfigData};
+use actix_files::NamedFile;
+use actix_multipart::form::tempfile::TempFile;
+use actix_multipart::form::text::Text;
+use actix_multipart::form::{MultipartForm, MultipartFormConfig};
+use actix_multipart::MultipartError;
+use actix_web::error::PayloadError;
+use actix_web::http::header;
+use actix_web::mime;
 use actix_web::web::Data;
-use actix_web::{get, post, rt, web, Responder};
+use actix_web::{get, post, rt, web, Either, HttpRequest, HttpResponse, Responder};
 use actix_web_lab::extract::Query;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use reqwest::Client;
+use futures_util::stream::{self, StreamExt};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::cmp::max;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
 use tokio::fs;
-use tracing::error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{error, warn};
+
+const CIVITAI_HOST: &str = "civitai.com";
+/// Maximum number of redirects to follow when proxying images
+const MAX_PROXY_REDIRECTS: usize = 5;
+/// Delays between retries of fetching metadata of a downloaded model.
+/// Retries are not persisted. If the server restarts meanwhile, the model stays indexed without metadata until it's
+/// synced with Civitai again.
+const METADATA_RETRY_DELAYS: [Duration; 5] = [
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+    Duration::from_secs(900),
+    Duration::from_secs(3600),
+];
 
 pub fn scope(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/item")
+            .app_data(MultipartFormConfig::default().error_handler(multipart_error))
             .service(get_items)
             .service(saved_location)
             .service(civitai_download)
+            .service(update_version)
             .service(delete)
-            .service(update),
+            .service(update)
+            .service(upload_preview)
+            .service(set_preview)
+            .service(description_html)
+            .service(details)
+            .service(by_sha256)
+            .service(rehash)
+            .service(export_sidecar)
+            .service(gen_params)
+            .service(image_proxy)
+            .service(civitai_search),
     );
 }
 
 #[derive(Serialize)]
 struct SearchResponse {
     items: Vec<ModelInfo>,
+    /// Total number of matched items
+    total: i64,
     total_page: i64,
     tags: Vec<TagCount>,
-    err: Option<String>,
+    /// Number of distinct tags of matched items. More tags can be requested with `tag_offset` if it's larger than
+    /// the number of returned tags.
+    total_tags: i64,
+    /// Cursor to get the next page with `after`. Not set on the last page.
+    next_cursor: Option<String>,
+    /// Set if the request was changed, e.g. `count` was clamped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    /// Queries of the search with their plans. Only set if `explain` is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<Vec<QueryDebug>>,
 }
 
 #[derive(Serialize, Default)]
 struct ModelInfo {
     id: i64,
+    /// File name or model name, depending on `display_name`
     name: String,
+    /// Name of the model file
+    file_name: String,
     path: String,
     preview: String,
     video_preview: Option<String>,
+    /// Main preview and other example images
+    gallery: Vec<String>,
     info: String,
     description: String,
     note: String,
+    model_type: String,
+    nsfw_level: Option<i64>,
+    /// Preview is above the allowed NSFW level and should be blurred
+    blur_preview: bool,
+    /// Lowercase BLAKE3 hash of the model file
+    blake3: String,
+    /// Number of items with the same hash, including this one. 1 means unique.
+    duplicate_count: i64,
+    /// Lowercase file extension without the leading dot
+    extension: String,
+    /// Base label of the item is not in `model_paths` anymore, so its files can't be found
+    unavailable: bool,
+    /// Modified time of the model file in epoch millis, which is used to filter by date
+    updated_at: Option<i64>,
+    /// Model file exists on disk. Only set if `check_exists` is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exists: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -56,12 +146,21 @@ struct ItemUpdate {
     item_id: i64,
     tags: String,
     note: String,
+    /// Description which is saved in *.model.json. Keep it unchanged if not set.
+    description: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct ItemUpdateResponse {
+    description: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct SavedLocationQuery {
     model_type: String,
     blake3: Option<String>,
+    /// Label of the model path to download to
+    label: Option<String>,
 }
 
 #[derive(Serialize, Default)]
@@ -77,104 +176,560 @@ struct CivitaiDownloadQuery {
     name: String,
     blake3: String,
     dest: String,
+    /// Download even if a file with the same hash exists
+    force: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct UpdateVersionQuery {
+    id: i64,
+    /// Keep the installed version. `true` by default, otherwise it's moved to trash.
+    keep_old: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct CivitaiDownloadResponse {
+    msg: String,
+    /// Directory of the existing file if download is skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    saved_location: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ItemDeleteQuery {
+    #[serde(rename = "id")]
+    ids: Vec<i64>,
+    /// Only list the files which would be moved to trash
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    mode: DeleteMode,
+}
+
+/// What deleting an item does to its files
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum DeleteMode {
+    /// Move files to trash
+    #[default]
+    Trash,
+    /// Only remove the item from database, files are left as they are
+    DbOnly,
+    /// Move files to trash and remove them right away
+    Permanent,
+}
+
+#[derive(Serialize)]
+struct DeletePreview {
+    id: i64,
+    files: Vec<String>,
+    err: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeleteFailure {
+    id: i64,
+    reason: String,
+}
+
+/// Files of an item which are moved to trash
+#[derive(Serialize)]
+struct MovedFiles {
+    id: i64,
+    count: usize,
+    /// File names
+    files: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+struct DeleteResponse {
+    /// Items which were moved to trash
+    deleted: Vec<i64>,
+    failed: Vec<DeleteFailure>,
+    /// Moved files of each item, including items which failed halfway
+    moved: Vec<MovedFiles>,
+}
+
+impl DeleteResponse {
+    fn add_failure(&mut self, id: i64, reason: String) {
+        self.failed.push(DeleteFailure { id, reason });
+    }
+}
+
+#[derive(Serialize)]
+struct DryRunDeleteResponse {
+    dry_run: bool,
+    items: Vec<DeletePreview>,
+}
+
+#[derive(Serialize)]
+struct DescriptionHtmlResponse {
+    description: String,
+    note: String,
+}
+
+/// Everything needed to reference a model elsewhere
+#[derive(Serialize)]
+struct ItemDetails {
+    id: i64,
+    filename: String,
+    /// Path relative to its base path
+    path: String,
+    base_label: String,
+    blake3: String,
+    model_type: String,
+    base_model: String,
+    trigger_words: Vec<String>,
+    download_url: Option<String>,
+    /// Model page on Civitai, or the URL which the model was downloaded from
+    source_url: Option<String>,
+    air: Option<String>,
+    /// Lowercase SHA256 hash, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RehashQuery {
+    id: i64,
+}
+
+#[derive(Serialize)]
+struct RehashResponse {
+    id: i64,
+    old_blake3: String,
+    new_blake3: String,
+    old_sha256: Option<String>,
+    /// Only computed if `compute_sha256` is enabled
+    new_sha256: Option<String>,
+    /// New hashes are the same as stored ones
+    matched: bool,
+}
+
+#[derive(Deserialize)]
+struct ExportSidecarQuery {
+    id: i64,
+    /// Only `civitai_helper` is supported, which is also the default
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportSidecarResponse {
+    /// Written files
+    files: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Sha256Query {
+    hash: String,
+}
+
+#[derive(MultipartForm)]
+struct PreviewUpload {
+    id: Text<i64>,
+    #[multipart(limit = "20MiB")]
+    file: TempFile,
+}
+
+#[derive(Deserialize)]
+struct ImageProxyQuery {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct PreviewUploadResponse {
+    preview: String,
+}
+
+#[derive(Deserialize)]
+struct SetPreviewRequest {
+    id: i64,
+    /// Index of gallery image `<name>.<index>.<ext>`. 0 is the original preview.
+    index: usize,
 }
 
 #[get("")]
 async fn get_items(
+    req: HttpRequest,
     config: Data<ConfigData>,
     db_pool: Data<DBPool>,
     query_params: Query<SearchQuery>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let config = config.config.read().await;
     let page = max(1, query_params.page.unwrap_or(1)) - 1;
-    let limit = max(1, query_params.count.unwrap_or(config.api.per_page as i64));
+    let mut limit = max(1, query_params.count.unwrap_or(config.api.per_page as i64));
+    let mut warning = None;
+    let max_per_page = config.api.max_per_page as i64;
+    if limit > max_per_page {
+        warning = Some(format!("count is limited to {max_per_page}"));
+        limit = max_per_page;
+    }
     let offset = page * limit;
-    let mut ret = Vec::new();
-    let mut err = None;
+    let mut next_cursor = None;
+    let max_nsfw_level = Some(query_params.max_nsfw_level.unwrap_or(config.nsfw.max_level)).filter(|l| *l > 0);
 
-    let (items, total) = if let Some(item_id) = query_params.id {
-        match db::item::get_by_id(&db_pool.sqlite_pool, item_id).await {
-            Ok(item) => (vec![item], 1),
-            Err(e) => {
-                err = Some(format!("{}", e));
-                (Vec::new(), 0)
-            }
+    let summary = match query_params.fields.as_deref() {
+        None | Some("") | Some("full") => false,
+        Some("summary") => true,
+        Some(fields) => return Err(ApiError::BadRequest(format!("Invalid fields: {fields}"))),
+    };
+    let facets_only = query_params.facets_only.unwrap_or(false);
+    let display_name = query_params.display_name.unwrap_or(config.api.display_name);
+    let tag_limit = Some(query_params.tag_limit.unwrap_or(config.api.tag_limit as i64)).filter(|l| *l > 0);
+    let tag_offset = query_params.tag_offset.unwrap_or(0).max(0);
+    let mut debug = None;
+    if query_params.explain.unwrap_or(false) {
+        if config.api.allow_explain {
+            debug = Some(Vec::new());
+        } else {
+            warning = Some("explain is disabled in config".to_string());
         }
+    }
+
+    let ids = parse_ids(&query_params.id)?;
+    let (items, total, (tags, total_tags)) = if let [item_id] = ids[..] {
+        let item = db::item::get_by_id(&db_pool.sqlite_pool, item_id).await?;
+        let tags = db::tag::list_tags(&db_pool.sqlite_pool, HashSet::from([item.id])).await?;
+        (vec![item], 1, page_tags(tags, tag_limit, tag_offset))
+    } else if !ids.is_empty() {
+        let items = db::item::get_by_ids(&db_pool.sqlite_pool, &ids).await?;
+        let item_ids = items.iter().map(|item| item.id).collect::<HashSet<_>>();
+        let tags = if item_ids.is_empty() {
+            Vec::new()
+        } else {
+            db::tag::list_tags(&db_pool.sqlite_pool, item_ids).await?
+        };
+        let total = items.len() as i64;
+        (items, total, page_tags(tags, tag_limit, tag_offset))
     } else {
-        let tag_only = query_params.tag_only.unwrap_or(false);
-        let duplicate_only = query_params.duplicate_only.unwrap_or(false);
-        match db::item::search(&db_pool.sqlite_pool, &query_params.search, limit, offset, tag_only, duplicate_only).await {
-            Ok((i, t)) => (i, t),
-            Err(e) => {
-                err = Some(format!("{}", e));
-                (Vec::new(), 0)
-            }
+        let after = query_params
+            .after
+            .as_deref()
+            .map(|cursor| decode_cursor(cursor).ok_or_else(|| ApiError::BadRequest("Invalid cursor".to_string())))
+            .transpose()?;
+        let added_after = parse_optional_date(query_params.added_after.as_deref())?;
+        let added_before = parse_optional_date(query_params.added_before.as_deref())?;
+        let filter = SearchFilter {
+            tag_only: query_params.tag_only.unwrap_or(false),
+            duplicate_only: query_params.duplicate_only.unwrap_or(false),
+            group_duplicates: query_params.group_duplicates.unwrap_or(false),
+            untagged_only: query_params.untagged_only.unwrap_or(false),
+            added_after,
+            added_before,
+            model_type: query_params.model_type.clone().filter(|t| !t.is_empty()),
+            max_nsfw_level,
+            show_unknown_nsfw: config.nsfw.show_unknown,
+            base_labels: query_params
+                .base_label
+                .iter()
+                .filter(|label| !label.is_empty())
+                .cloned()
+                .collect(),
+            ext: query_params
+                .ext
+                .as_deref()
+                .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                .filter(|ext| !ext.is_empty()),
+        };
+        let (items, total) = db::item::search(
+            &db_pool,
+            &query_params.search,
+            limit,
+            offset,
+            after,
+            &filter,
+            Duration::from_secs(config.api.count_cache_ttl),
+            debug.as_mut(),
+        )
+        .await?;
+        // Also set on the first page, so clients can switch to cursor pagination from there
+        if items.len() as i64 >= limit {
+            next_cursor = items.last().map(encode_cursor);
         }
+
+        // Facets are counted over all matched items so they stay the same across pages
+        let tags = if total == 0 || (items.is_empty() && !facets_only) {
+            (Vec::new(), 0)
+        } else {
+            db::item::search_facets(
+                &db_pool.sqlite_pool,
+                &query_params.search,
+                &filter,
+                tag_limit,
+                tag_offset,
+            )
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to list tags: {e}");
+                (Vec::new(), 0)
+            })
+        };
+        (items, total, tags)
     };
+    // Don't read sidecars of items if only facets are wanted
+    let items = if facets_only { Vec::new() } else { items };
 
-    let mut item_ids = HashSet::new();
-    for item in items {
-        let (model_url, json_url, model_json_url, mut preview_url) =
-            get_abs_path(&config, &item.base_label, &item.path);
-
-        let mut video_preview = None;
-
-        let item_info = fs::read_to_string(&json_url).await.unwrap_or_default();
-        let v: Value = serde_json::from_str(item_info.as_str()).unwrap_or_default();
-        if let Some(url) = v["images"][0]["url"].as_str() {
-            if let Some(ext) = get_extension_from_url(url) {
-                let mut abs_preview = PathBuf::from(&model_url);
-                abs_preview.set_extension(&ext);
-                if file_type(&abs_preview).await == FileType::Video {
-                    let mut video_preview_path = PathBuf::from(&preview_url);
-                    video_preview_path.set_extension(&ext);
-                    if let Some(str_path) = video_preview_path.to_str() {
-                        video_preview = Some(str_path.to_string());
-                    }
-                }
-            }
+    // Single item is cached by client with ETag
+    let etag = match (ids.len(), items.first()) {
+        (1, Some(item)) if !summary => Some(item_etag(&config, &db_pool, item, req.query_string()).await),
+        _ => None,
+    };
+    if let Some(etag) = etag.as_ref() {
+        let is_not_modified = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag));
+        if is_not_modified {
+            return Ok(HttpResponse::NotModified()
+                .insert_header((header::ETAG, etag.as_str()))
+                .finish());
         }
-        let mut abs_preview = PathBuf::from(&model_url);
-        abs_preview.set_extension(PREVIEW_EXT);
-        if !abs_preview.exists() {
-            preview_url.clear();
+    }
+
+    let hashes = items
+        .iter()
+        .map(|item| item.blake3.to_lowercase())
+        .filter(|hash| !hash.is_empty())
+        .collect::<HashSet<_>>();
+    let duplicate_counts = db::item::count_by_hashes(&db_pool.sqlite_pool, &hashes)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to count duplicates: {e}");
+            Default::default()
+        });
+
+    // Read sidecar files of items concurrently. `buffered` keeps the order of items from DB.
+    let listing = DirListing::of_items(&config, &items).await;
+    let mut ret = stream::iter(items)
+        .map(|item| build_model_info(&config, &listing, item, max_nsfw_level, summary, display_name))
+        .buffered(config.parallel.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let check_exists = query_params.check_exists.unwrap_or(false);
+    for info in ret.iter_mut() {
+        info.duplicate_count = duplicate_counts.get(&info.blake3).copied().unwrap_or(1);
+        if check_exists {
+            info.exists = Some(!info.unavailable && listing.has_file(Path::new(&info.path)));
         }
+    }
 
-        let model_info = fs::read_to_string(&model_json_url).await.unwrap_or_default();
-        let model_parsed: Value = serde_json::from_str(model_info.as_str()).unwrap_or_default();
-        let description = model_parsed["description"].as_str().unwrap_or_default().to_string();
+    let mut res = HttpResponse::Ok();
+    if let Some(etag) = etag {
+        res.insert_header((header::ETAG, etag));
+    }
+    Ok(res.json(SearchResponse {
+        items: ret,
+        total,
+        total_page: max(1, (total + limit - 1) / limit),
+        tags,
+        total_tags,
+        next_cursor,
+        warning,
+        debug,
+    }))
+}
 
-        item_ids.insert(item.id);
+/// Take a page of tags, which are sorted by count already. Return the page and total number of tags.
+fn page_tags(tags: Vec<TagCount>, limit: Option<i64>, offset: i64) -> (Vec<TagCount>, i64) {
+    let total = tags.len() as i64;
+    let tags = tags
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit.map_or(usize::MAX, |limit| limit as usize))
+        .collect();
+    (tags, total)
+}
 
-        ret.push(ModelInfo {
+/// Build info of an item from its sidecar files and its files in `listing`. JSON sidecars are not read if `summary`
+/// is set.
+async fn build_model_info(
+    config: &Config,
+    listing: &DirListing,
+    item: Item,
+    max_nsfw_level: Option<i64>,
+    summary: bool,
+    display_name: DisplayName,
+) -> ModelInfo {
+    let file_name = item.name.clone().unwrap_or_default();
+    let name = match display_name {
+        DisplayName::ModelName if !item.model_name.is_empty() => item.model_name.clone(),
+        _ => file_name.clone(),
+    };
+    let extension = Path::new(&item.path)
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    if !config.model_paths.contains_key(&item.base_label) {
+        return ModelInfo {
             id: item.id,
-            name: item.name.unwrap_or_default(),
-            path: model_url,
-            preview: preview_url,
-            video_preview,
-            info: item_info,
-            description,
-            note: item.note.clone(),
-        })
+            name,
+            file_name,
+            path: item.path,
+            note: item.note,
+            model_type: item.model_type,
+            nsfw_level: item.nsfw_level,
+            blake3: item.blake3.to_lowercase(),
+            extension,
+            updated_at: item.updated_at,
+            unavailable: true,
+            ..Default::default()
+        };
     }
 
-    let tags = if item_ids.is_empty() {
-        Vec::new()
+    let (model_url, _, _, preview_url) = get_abs_path(config, &item.base_label, &item.path);
+    let model_path = Path::new(&model_url);
+    let files = listing.same_filename(config, model_path);
+    let ((info, description), (mut preview, mut video_preview)) = if summary {
+        let previews = find_previews(config, &files, model_path, &preview_url).await;
+        ((String::new(), String::new()), previews)
     } else {
-        db::tag::list_tags(&db_pool.sqlite_pool, item_ids)
-            .await
-            .unwrap_or_else(|e| {
-                error!("Failed to list tags: {e}");
-                Vec::new()
-            })
+        tokio::join!(
+            read_sidecars(model_path),
+            find_previews(config, &files, model_path, &preview_url)
+        )
     };
+    let mut gallery = list_gallery(&files, model_path, &preview_url, item.preview_index);
+    if !preview.is_empty() {
+        // Change the url so that browser does not show the previously pinned image
+        if item.preview_index != 0 {
+            preview = format!("{preview}?v={}", item.preview_index);
+        }
+        gallery.insert(0, preview.clone());
+    }
 
-    web::Json(SearchResponse {
-        items: ret,
-        total_page: total/limit + 1,
-        tags,
-        err,
-    })
+    // Single item is not filtered by NSFW level, so its previews are blurred by client or withheld
+    let nsfw_allowed = match (max_nsfw_level, item.nsfw_level) {
+        (None, _) => true,
+        (Some(max_level), Some(level)) => level <= max_level,
+        (Some(_), None) => config.nsfw.show_unknown,
+    };
+    if !nsfw_allowed && !config.nsfw.blur_preview {
+        preview.clear();
+        video_preview = None;
+        gallery.clear();
+    }
+
+    ModelInfo {
+        id: item.id,
+        name,
+        file_name,
+        path: model_url,
+        preview,
+        video_preview,
+        gallery,
+        info,
+        description,
+        note: item.note,
+        model_type: item.model_type,
+        nsfw_level: item.nsfw_level,
+        blur_preview: !nsfw_allowed,
+        blake3: item.blake3.to_lowercase(),
+        extension,
+        updated_at: item.updated_at,
+        ..Default::default()
+    }
+}
+
+/// Read `.json` and `.model.json` of a model once. Return (info, description).
+async fn read_sidecars(model_path: &Path) -> (String, String) {
+    let (info, model_info) = tokio::join!(
+        fs::read_to_string(model_path.with_extension("json")),
+        fs::read_to_string(model_path.with_extension("model.json"))
+    );
+    let model_parsed: Value = serde_json::from_str(&model_info.unwrap_or_default()).unwrap_or_default();
+    let description = model_parsed["description"].as_str().unwrap_or_default().to_string();
+
+    (info.unwrap_or_default(), description)
+}
+
+/// Find preview files `<name>.<ext>` of a model among its `files` with the same name. Return URLs of (image, video).
+///
+/// Images are ordered as in `preview_extensions`, so the first configured format is preferred.
+async fn find_previews(
+    config: &Config,
+    files: &[PathBuf],
+    model_path: &Path,
+    preview_url: &str,
+) -> (String, Option<String>) {
+    let stem = model_path.file_stem().unwrap_or_default();
+    let mut files = files
+        .iter()
+        .filter(|p| {
+            p.file_stem() == Some(stem) && p.extension().is_some_and(|ext| ext != "json") && !config.is_model_file(p)
+        })
+        .collect::<Vec<_>>();
+    files.sort_by_key(|p| {
+        let ext = p.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        config
+            .preview_extensions
+            .iter()
+            .position(|e| e.eq_ignore_ascii_case(ext))
+            .unwrap_or(usize::MAX)
+    });
+
+    let mut image = None;
+    let mut video = None;
+    for file in files {
+        match file_type(file).await {
+            FileType::Image if image.is_none() => image = Some(file),
+            FileType::Video if video.is_none() => video = Some(file),
+            _ => {}
+        }
+        if image.is_some() && video.is_some() {
+            break;
+        }
+    }
+
+    let to_url = |file: &PathBuf| {
+        let url = PathBuf::from(preview_url).with_file_name(file.file_name()?);
+        Some(url.to_str()?.to_string())
+    };
+    (image.and_then(to_url).unwrap_or_default(), video.and_then(to_url))
+}
+
+/// ETag of a single item. It's calculated from the item in DB and modified time of its metadata files
+/// instead of the whole response. Query parameters and config which change the response are included too.
+async fn item_etag(config: &Config, db_pool: &DBPool, item: &Item, query: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&item.updated_at.unwrap_or_default().to_le_bytes());
+    hasher.update(item.blake3.as_bytes());
+    hasher.update(item.note.as_bytes());
+    hasher.update(item.model_name.as_bytes());
+    hasher.update(&item.nsfw_level.unwrap_or(-1).to_le_bytes());
+    hasher.update(&item.preview_index.to_le_bytes());
+
+    // Same parameters in another order give the same response
+    let mut params = query.split('&').filter(|param| !param.is_empty()).collect::<Vec<_>>();
+    params.sort_unstable();
+    hasher.update(params.join("&").as_bytes());
+    let response_config = format!(
+        "{:?}|{:?}|{:?}",
+        config.api.display_name, config.nsfw, config.preview_extensions
+    );
+    hasher.update(response_config.as_bytes());
+
+    let (model, json, model_json, _) = get_abs_path(config, &item.base_label, &item.path);
+    let preview = config.find_preview(Path::new(&model)).unwrap_or_default();
+    for file in [PathBuf::from(json), PathBuf::from(model_json), preview] {
+        if let Ok(modified) = fs::metadata(&file).await.and_then(|m| m.modified()) {
+            let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+            hasher.update(&modified.to_le_bytes());
+        }
+    }
+
+    let tags = db::tag::list_tags(&db_pool.sqlite_pool, HashSet::from([item.id]))
+        .await
+        .unwrap_or_default();
+    for tag in tags {
+        hasher.update(tag.tag.as_bytes());
+        hasher.update(&tag.count.to_le_bytes());
+        hasher.update(tag.category.as_bytes());
+        hasher.update(tag.color.unwrap_or_default().as_bytes());
+    }
+
+    format!("\"{}\"", &hasher.finalize().to_hex()[..32])
 }
 
 #[get("saved_location")]
@@ -185,26 +740,48 @@ async fn saved_location(
 ) -> impl Responder {
     let config = config.config.read().await;
 
-    if let Some(blake3) = query_params.blake3.as_ref() {
-        if let Ok(item) = db::item::get_by_hash(&db_pool.sqlite_pool, blake3.to_lowercase().as_str()).await {
-            let (path, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
-            let path = PathBuf::from(path);
-            let path = path
-                .parent()
-                .unwrap_or(Path::new("."))
-                .to_str()
-                .unwrap_or_default()
-                .to_string();
-            return web::Json(SavedLocationResponse {
-                saved_location: path,
-                is_downloaded: true,
-            });
-        }
+    if let Some(blake3) = query_params.blake3.as_ref()
+        && let Ok(item) = db::item::get_by_hash(&db_pool.sqlite_pool, blake3.to_lowercase().as_str()).await
+    {
+        let (path, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+        let path = PathBuf::from(path);
+        let path = path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_str()
+            .unwrap_or_default()
+            .to_string();
+        return web::Json(SavedLocationResponse {
+            saved_location: path,
+            is_downloaded: true,
+        });
     }
 
     let model_type = query_params.model_type.to_lowercase();
+    let label_path = query_params
+        .label
+        .as_ref()
+        .and_then(|label| Some((label, config.model_paths.get(label)?)));
+
+    if let Some((label, base_path)) = label_path
+        && let Some(sub_dir) = config
+            .civitai
+            .label_download_dirs
+            .get(label)
+            .and_then(|dirs| dirs.iter().find(|(t, _)| t.eq_ignore_ascii_case(&model_type)))
+            .map(|(_, sub_dir)| sub_dir)
+    {
+        let path = PathBuf::from(base_path).join(sub_dir);
+        return web::Json(SavedLocationResponse {
+            saved_location: path.to_str().unwrap_or_default().to_string(),
+            ..Default::default()
+        });
+    }
 
-    if let Some(path) = config.civitai.download_dir.get(&model_type) {
+    // The last used directory is only suggested if it's in the chosen model path
+    if let Some(path) = config.civitai.download_dir.get(&model_type)
+        && label_path.is_none_or(|(_, base_path)| Path::new(path).starts_with(base_path))
+    {
         return web::Json(SavedLocationResponse {
             saved_location: path.clone(),
             ..Default::default()
@@ -212,62 +789,98 @@ async fn saved_location(
     }
 
     let mut base_path = String::from("/");
-    for (_, path) in config.model_paths.iter() {
+    if let Some((_, path)) = label_path {
         base_path = path.clone();
+    } else {
+        for path in config.model_paths.values() {
+            base_path = path.clone();
+        }
     }
     web::Json(SavedLocationResponse {
-        saved_location: guess_saved_location(base_path.as_str(), &model_type),
+        saved_location: guess_saved_location(&config, base_path.as_str(), &model_type),
         ..Default::default()
     })
 }
 
 #[get("civitai_download")]
 async fn civitai_download(
+    req: HttpRequest,
     db_pool: Data<DBPool>,
     config_data: Data<ConfigData>,
     params: Query<CivitaiDownloadQuery>,
     broadcaster: Data<Broadcaster>,
-) -> impl Responder {
+) -> Result<impl Responder, ApiError> {
     let mut config = config_data.config.write().await.clone();
-    let dest_dir = PathBuf::from(&params.dest);
-
-    let path = dest_dir.join(&params.name);
-    let mut is_inside_base_path = false;
-    for (_, base_path) in config.model_paths.iter() {
-        let parent = PathBuf::from(base_path);
-        if path.starts_with(parent) {
-            is_inside_base_path = true;
-            break;
-        }
+    if Path::new(&params.name).file_name() != Some(OsStr::new(&params.name)) {
+        return Err(ApiError::BadRequest(format!("Invalid file name: {}", params.name)));
     }
 
-    if !is_inside_base_path {
-        error!("Destination path {} must be inside base path", path.display());
-        return web::Json(CommonResponse {
-            err: Some("Destination path must be inside base path".to_string()),
-            ..Default::default()
-        });
-    }
+    let Some(dest_dir) = resolve_in_model_paths(Path::new(&params.dest), &config) else {
+        error!("Destination path {} must be inside base path", params.dest);
+        return Err(ApiError::BadRequest(
+            "Destination path must be inside base path".to_string(),
+        ));
+    };
 
-    if let Err(e) = fs::create_dir_all(&dest_dir).await {
-        return web::Json(CommonResponse {
-            err: Some(format!("Failed to create {dest_dir:?}: {e}")),
-            ..Default::default()
-        });
+    if !params.force.unwrap_or(false)
+        && !params.blake3.is_empty()
+        && let Ok(item) = db::item::get_by_hash(&db_pool.sqlite_pool, &params.blake3.to_lowercase()).await
+    {
+        let (existing, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+        let existing = Path::new(&existing);
+        if existing.is_file() {
+            let msg = format!("{} is already downloaded at {}", params.name, existing.display());
+            broadcaster.info(&msg).await;
+            return Ok(web::Json(CivitaiDownloadResponse {
+                msg,
+                saved_location: existing.parent().map(|dir| dir.to_string_lossy().to_string()),
+            }));
+        }
     }
 
+    let client = Client::new();
+    let headers = auth_headers(&config);
+
+    let name = download_name(&config, &client, &headers, &params).await;
+    let path = dest_dir.join(&name);
+
+    fs::create_dir_all(long_path(&dest_dir))
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to create {dest_dir:?}: {e}")))?;
+
     if let Some(model_type) = params.model_type.as_ref() {
         let model_type = model_type.to_lowercase();
         config.civitai.download_dir.insert(model_type, params.dest.clone());
         let _ = config.save(&config_data.config_path, true);
     }
 
-    let client = Client::new();
-    let mut headers = HeaderMap::new();
-    if let Ok(bearer) = HeaderValue::from_str(&format!("Bearer {}", config.civitai.api_key)) {
-        headers.insert(AUTHORIZATION, bearer);
+    // Refuse early if the file doesn't fit. Size is unknown if the server doesn't tell it.
+    if config.civitai.check_disk_space
+        && let Some(size) = remote_file_size(&client, &headers, &params.url).await
+        && let Err(e) = check_disk_space(&dest_dir, size)
+    {
+        let msg = format!("Failed to download {}: {}", params.url.as_str(), e);
+        broadcaster.error(&msg).await;
+        return Err(ApiError::BadRequest(msg));
+    }
+
+    // Both names are the same file on case-insensitive filesystems, so one would overwrite the other
+    if config.civitai.case_collision != CaseCollision::Allow
+        && let Some(existing) = find_case_collision(&dest_dir, &name)
+    {
+        let msg = format!(
+            "{} differs only by case from existing file {}",
+            path.display(),
+            existing.display()
+        );
+        if config.civitai.case_collision == CaseCollision::Block {
+            broadcaster.error(&msg).await;
+            return Err(ApiError::Conflict(msg));
+        }
+        broadcaster.warn(&msg).await;
     }
 
+    let actor = request_actor(&req);
     rt::spawn(async move {
         let id = add_job(
             &db_pool.sqlite_pool,
@@ -277,7 +890,7 @@ async fn civitai_download(
         .await;
         let blake3_lowercase = params.blake3.to_lowercase();
         broadcaster
-            .info(&format!("Downloading file {}: {}", params.name, params.url))
+            .info(&format!("Downloading file {}: {}", name, params.url))
             .await;
 
         if let Err(e) = download_file(
@@ -285,7 +898,7 @@ async fn civitai_download(
             &path,
             &client,
             &headers,
-            &config.model_paths,
+            &config,
             blake3_lowercase.as_ref(),
             config.civitai.max_retries,
         )
@@ -298,119 +911,1757 @@ async fn civitai_download(
             broadcaster.error(&msg).await;
             return;
         }
-        if let Ok(id) = id {
-            let _ = update_job(&db_pool.sqlite_pool, id, "", JobState::Succeed).await;
-        }
-        broadcaster.info(&format!("Finished downloading {}", params.name)).await;
+        broadcaster.info(&format!("Finished downloading {}", name)).await;
 
-        if let Err(e) = get_item_info(&path, &client, &headers, Some(blake3_lowercase), &config).await {
-            error!("Failed to get model info {}: {}", &path.display(), e);
-            return;
+        // Index the model first, so it's not lost if metadata can't be fetched
+        index_downloaded(&db_pool, &config, &path).await;
+        if let Ok(id) = id {
+            let _ = update_job_desc(&db_pool.sqlite_pool, id, "Downloaded, metadata pending").await;
         }
 
-        for (label, base_path) in config.model_paths.iter() {
-            if path.starts_with(PathBuf::from(base_path)) {
-                let relative_path = api::get_relative_path(base_path, &path).unwrap_or_default();
-                api::save_model_info(&db_pool, &path, label, relative_path.as_str()).await;
-                break;
+        let mut retry_delays = METADATA_RETRY_DELAYS.iter();
+        let metadata = loop {
+            match get_item_info(
+                &path,
+                &client,
+                &headers,
+                Some(blake3_lowercase.clone()),
+                &config,
+                config.civitai.download_previews.count(),
+            )
+            .await
+            {
+                Ok(_) => break Ok(()),
+                // Retrying doesn't help if the model is not on Civitai
+                Err(e) if CivitaiError::is_not_found(&e) => break Err(e),
+                Err(e) => match retry_delays.next() {
+                    Some(delay) => {
+                        error!(
+                            "Failed to get model info {}: {}. Retry in {:?}",
+                            path.display(),
+                            e,
+                            delay
+                        );
+                        tokio::time::sleep(*delay).await;
+                    }
+                    None => break Err(e),
+                },
             }
-        }
-    });
-
-    web::Json(CommonResponse {
-        msg: "Downloading in background".to_string(),
-        ..Default::default()
-    })
-}
-
-#[get("delete")]
-async fn delete(config: Data<ConfigData>, db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> impl Responder {
-    let config = config.config.read().await;
-    for id in params.ids.iter() {
-        let Ok((rel_path, label)) = db::item::mark_obsolete(&db_pool.sqlite_pool, *id).await else {
-            continue;
-        };
-        let Some(base_path) = config.model_paths.get(&label) else {
-            continue;
         };
-        let base_path = PathBuf::from(base_path);
-        let model_file = base_path.join(rel_path);
-        let trash_dir = base_path.join(TRASH_DIR);
 
-        if let Err(e) = fs::create_dir_all(&trash_dir).await {
-            error!("Failed to create {:?}: {}", trash_dir, e);
-            return web::Json("");
-        }
-
-        if let Ok(files) = list_same_filename(&model_file) {
-            if let Err(e) = move_to_dir(&files, &trash_dir).await {
-                error!("Failed to move file to trash directory: {}", e);
+        match metadata {
+            Ok(_) => {
+                index_downloaded(&db_pool, &config, &path).await;
+                if let Ok(id) = id {
+                    let _ = update_job(&db_pool.sqlite_pool, id, "", JobState::Succeed).await;
+                }
+            }
+            Err(e) => {
+                let msg = format!("Downloaded, but failed to get model info: {e}");
+                if let Ok(id) = id {
+                    let _ = update_job(&db_pool.sqlite_pool, id, &msg, JobState::Failed).await;
+                }
+                broadcaster.error(&format!("{}: {}", path.display(), msg)).await;
             }
         }
 
-        // Remove *.model.json file
-        let model_json = model_file.with_extension("model.json");
-        if let Err(e) = move_to_dir(&[model_json], &trash_dir).await {
-            error!("Failed to move to trash directory: {}", e);
+        let item_id = db::item::get_by_hash(&db_pool.sqlite_pool, &blake3_lowercase)
+            .await
+            .ok()
+            .map(|item| item.id);
+        if let Some(item_id) = item_id
+            && let Err(e) = db::item::set_source_url_if_missing(&db_pool.sqlite_pool, item_id, &params.url).await
+        {
+            error!("Failed to save source URL of {}: {}", path.display(), e);
         }
-    }
+        audit(
+            &db_pool,
+            AuditAction::Download,
+            item_id,
+            actor.as_deref(),
+            params.url.as_str(),
+        )
+        .await;
+    });
 
-    web::Json("")
+    Ok(web::Json(CivitaiDownloadResponse {
+        msg: "Downloading in background".to_string(),
+        saved_location: None,
+    }))
 }
 
-#[post("update")]
-async fn update(db_pool: Data<DBPool>, data: web::Json<ItemUpdate>) -> impl Responder {
-    if let Err(e) = update_tag_item(&db_pool.sqlite_pool, data.item_id, data.tags.as_str()).await {
-        error!("Failed to update tag: {}", e);
+/// Download the newest Civitai version of a model next to it. Tags and note are carried over to the new item.
+#[get("update_version")]
+async fn update_version(
+    req: HttpRequest,
+    db_pool: Data<DBPool>,
+    config_data: Data<ConfigData>,
+    params: Query<UpdateVersionQuery>,
+    broadcaster: Data<Broadcaster>,
+) -> Result<impl Responder, ApiError> {
+    let config = config_data.config.read().await.clone();
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, params.id).await?;
+    let (model_path, json, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+    if model_path.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "Label {} is not in model paths",
+            item.base_label
+        )));
     }
+    let info = fs::read_to_string(&json).await.unwrap_or_default();
+    let info: Value = serde_json::from_str(&info).unwrap_or_default();
+    let (Some(model_id), Some(version_id)) = (info["modelId"].as_i64(), info["id"].as_i64()) else {
+        return Err(ApiError::BadRequest(format!("{} has no Civitai metadata", item.path)));
+    };
 
-    if let Err(e) = update_item_note(&db_pool.sqlite_pool, data.item_id, data.note.as_str()).await {
-        error!("Failed to update note: {}", e);
+    let client = Client::new();
+    let headers = auth_headers(&config);
+    let model = get_model(&client, &headers, model_id, config.civitai.max_retries)
+        .await
+        .map_err(|e| ApiError::NotFound(format!("Failed to get model {model_id} from Civitai: {e}")))?;
+    let latest = &model["modelVersions"][0];
+    let Some(latest_id) = latest["id"].as_i64() else {
+        return Err(ApiError::NotFound(format!(
+            "Model {model_id} has no version on Civitai"
+        )));
+    };
+    if latest_id == version_id {
+        return Ok(web::Json(CommonResponse::from_msg("Already the newest version")));
     }
 
-    web::Json("")
-}
-
-async fn move_to_dir(files: &[PathBuf], dir: &PathBuf) -> anyhow::Result<()> {
-    for file in files {
-        let file_name = file.file_name().unwrap_or_default();
-        if !file_name.is_empty() {
-            let dest = dir.join(file_name);
-            fs::rename(file, dest).await?;
-        }
+    let files = latest["files"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let Some(file) = files
+        .iter()
+        .find(|f| f["primary"].as_bool() == Some(true))
+        .or(files.first())
+    else {
+        return Err(ApiError::NotFound(format!("Version {latest_id} has no file")));
+    };
+    let name = file["name"].as_str().unwrap_or_default().to_string();
+    let url = file["downloadUrl"].as_str().unwrap_or_default().to_string();
+    let blake3 = file["hashes"]["BLAKE3"].as_str().unwrap_or_default().to_lowercase();
+    if Path::new(&name).file_name() != Some(OsStr::new(&name)) || url.is_empty() || blake3.is_empty() {
+        return Err(ApiError::Internal(format!("Invalid file of version {latest_id}")));
     }
 
-    Ok(())
-}
-
-fn list_same_filename(path: &Path) -> std::io::Result<Vec<PathBuf>> {
-    if !path.is_file() {
-        return Ok(vec![]);
+    let old_file = PathBuf::from(&model_path);
+    let path = old_file.parent().unwrap_or(Path::new(".")).join(&name);
+    let keep_old = params.keep_old.unwrap_or(true);
+    // Both versions can't be kept under the same name
+    let same_name = path == old_file;
+    if same_name && keep_old {
+        return Err(ApiError::Conflict(format!(
+            "New version has the same file name {name}. Trash the old version to update."
+        )));
     }
 
-    let dir = path.parent().unwrap_or(Path::new("."));
-    let stem = path.file_stem().unwrap_or_default(); // "filename"
+    let version_name = latest["name"].as_str().unwrap_or_default().to_string();
+    let msg = format!("Updating {} to {}", item.path, version_name);
+    let actor = request_actor(&req);
+    rt::spawn(async move {
+        let job_id = add_job(&db_pool.sqlite_pool, &format!("Update {}", item.path), &version_name).await;
+        let fail = async |msg: String| {
+            if let Ok(id) = job_id {
+                let _ = update_job(&db_pool.sqlite_pool, id, &msg, JobState::Failed).await;
+            }
+            broadcaster.error(&msg).await;
+        };
+        let old_tags = db::tag::list_tags(&db_pool.sqlite_pool, HashSet::from([item.id]))
+            .await
+            .unwrap_or_default();
 
-    let matches = std::fs::read_dir(dir)?
-        .filter_map(Result::ok)
-        .map(|entry| entry.path())
-        .filter(|p| p.is_file() && p.file_stem() == Some(stem))
-        .collect();
+        // A new version with the same name is downloaded aside, so the old version stays in place if it fails
+        let download_path = if same_name {
+            let mut download_path = path.clone().into_os_string();
+            download_path.push(".new");
+            PathBuf::from(download_path)
+        } else {
+            path.clone()
+        };
+
+        if let Ok(id) = job_id {
+            let _ = update_job_desc(&db_pool.sqlite_pool, id, &format!("Downloading {name}")).await;
+        }
+        if let Err(e) = download_file(
+            &url,
+            &download_path,
+            &client,
+            &headers,
+            &config,
+            &blake3,
+            config.civitai.max_retries,
+        )
+        .await
+        {
+            fail(format!("Failed to download {url}: {e}")).await;
+            return;
+        }
+
+        if same_name {
+            if let Err(e) = trash_item(&config, &db_pool, &item, actor.as_deref()).await {
+                let _ = fs::remove_file(long_path(&download_path)).await;
+                fail(format!("Failed to trash {}: {}", item.path, e)).await;
+                return;
+            }
+            if let Err(e) = fs::rename(long_path(&download_path), long_path(&path)).await {
+                fail(format!(
+                    "Failed to move {} to {}: {}",
+                    download_path.display(),
+                    path.display(),
+                    e
+                ))
+                .await;
+                return;
+            }
+        }
+        if let Err(e) = get_item_info(
+            &path,
+            &client,
+            &headers,
+            Some(blake3.clone()),
+            &config,
+            config.civitai.download_previews.count(),
+        )
+        .await
+        {
+            broadcaster
+                .warn(&format!("Failed to get model info {}: {}", path.display(), e))
+                .await;
+        }
+        let real_path = std::fs::canonicalize(&path).unwrap_or(path.clone());
+        index_downloaded(&db_pool, &config, &real_path).await;
+
+        // Tags of the new version are kept, e.g. its base model
+        if let Ok(id) = job_id {
+            let _ = update_job_desc(&db_pool.sqlite_pool, id, "Carrying over tags and note").await;
+        }
+        let new_item = match db::item::get_by_hash(&db_pool.sqlite_pool, &blake3).await {
+            Ok(new_item) => new_item,
+            Err(e) => {
+                fail(format!("Failed to find the new version of {}: {}", item.path, e)).await;
+                return;
+            }
+        };
+        let new_tags = db::tag::list_tags(&db_pool.sqlite_pool, HashSet::from([new_item.id]))
+            .await
+            .unwrap_or_default();
+        let tags = old_tags
+            .iter()
+            .chain(new_tags.iter())
+            .map(|tag| tag.tag.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let note = if new_item.note.is_empty() { &item.note } else { &new_item.note };
+        if let Err(e) = update_tags_and_note(&db_pool, new_item.id, &tags, note).await {
+            broadcaster
+                .warn(&format!("Failed to carry over tags to {}: {}", new_item.path, e))
+                .await;
+        }
+        audit(
+            &db_pool,
+            AuditAction::Download,
+            Some(new_item.id),
+            actor.as_deref(),
+            &url,
+        )
+        .await;
+
+        if !keep_old
+            && !same_name
+            && let Err(e) = trash_item(&config, &db_pool, &item, actor.as_deref()).await
+        {
+            fail(format!("Updated, but failed to trash {}: {}", item.path, e)).await;
+            return;
+        }
+
+        let summary = format!("Updated {} to {}", item.path, version_name);
+        if let Ok(id) = job_id {
+            let _ = update_job(&db_pool.sqlite_pool, id, &summary, JobState::Succeed).await;
+        }
+        broadcaster.info(&summary).await;
+    });
+
+    Ok(web::Json(CommonResponse::from_msg(&msg)))
+}
+
+/// Move an item to trash. The item is restored if its files can't be moved.
+async fn trash_item(config: &Config, db_pool: &DBPool, item: &Item, actor: Option<&str>) -> Result<(), String> {
+    let (Some(base_path), Some(trash_dir)) = (
+        config.model_paths.get(&item.base_label),
+        config.trash_path(&item.base_label),
+    ) else {
+        return Err(format!("Label {} is not in model paths", item.base_label));
+    };
+    let model_file = PathBuf::from(base_path).join(&item.path);
+    fs::create_dir_all(long_path(&trash_dir))
+        .await
+        .map_err(|e| format!("Failed to create {trash_dir:?}: {e}"))?;
+
+    db::item::mark_obsolete(db_pool, &[item.id])
+        .await
+        .map_err(|e| format!("{e}"))?;
+    if let Err(reason) = move_files_to_trash(config, item.id, &model_file, &trash_dir, &mut Vec::new()).await {
+        if model_file.exists() {
+            let _ = db::item::unmark_obsolete(db_pool, &[item.id]).await;
+        }
+        return Err(reason);
+    }
+    audit(
+        db_pool,
+        AuditAction::Delete,
+        Some(item.id),
+        actor,
+        &model_file.display().to_string(),
+    )
+    .await;
+    Ok(())
+}
+
+/// Name of the downloaded file from `download_filename_template`. Fall back to the requested name if the template
+/// can't be expanded.
+async fn download_name(config: &Config, client: &Client, headers: &HeaderMap, params: &CivitaiDownloadQuery) -> String {
+    let template = config.civitai.download_filename_template.trim();
+    if template.is_empty() {
+        return params.name.clone();
+    }
+
+    let info = match get_version_info(client, headers, &params.blake3.to_lowercase()).await {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Failed to get model info of {}: {}", params.name, e);
+            return params.name.clone();
+        }
+    };
+    match expand_filename_template(template, &info, &params.name) {
+        Some(name) if Path::new(&name).file_name() == Some(OsStr::new(&name)) => name,
+        _ => params.name.clone(),
+    }
+}
+
+/// Size of a remote file from `Content-Length` of a HEAD request
+async fn remote_file_size(client: &Client, headers: &HeaderMap, url: &str) -> Option<u64> {
+    let res = client.head(url).headers(headers.clone()).send().await.ok()?;
+    if !res.status().is_success() {
+        return None;
+    }
+    res.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Save a downloaded model to DB
+async fn index_downloaded(db_pool: &DBPool, config: &Config, path: &Path) {
+    // `path` is resolved, so compare it with resolved base paths
+    for (label, base_path) in config.model_paths.iter() {
+        let Ok(real_base_path) = std::fs::canonicalize(base_path) else {
+            continue;
+        };
+        if let Ok(relative_path) = path.strip_prefix(&real_base_path) {
+            let relative_path = relative_path.to_str().unwrap_or_default();
+            api::save_model_info(config, db_pool, path, label, relative_path, true).await;
+            break;
+        }
+    }
+}
+
+#[get("delete")]
+async fn delete(
+    req: HttpRequest,
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    params: Query<ItemDeleteQuery>,
+) -> impl Responder {
+    let config = config.config.read().await;
+    let actor = request_actor(&req);
+    if params.dry_run {
+        return Either::Left(web::Json(
+            preview_delete(&config, &db_pool, &params.ids, params.mode).await,
+        ));
+    }
+    if params.mode == DeleteMode::DbOnly {
+        return Either::Right(web::Json(delete_db_only(&db_pool, &params.ids, actor.as_deref()).await));
+    }
+
+    let mut res = DeleteResponse::default();
+    let marked = match db::item::mark_obsolete(&db_pool, &params.ids).await {
+        Ok(marked) => marked,
+        Err(e) => {
+            for id in params.ids.iter() {
+                res.add_failure(*id, format!("{e}"));
+            }
+            return Either::Right(web::Json(res));
+        }
+    };
+
+    // Items whose model file is still in place are restored if moving fails
+    let mut unmoved = Vec::new();
+    for (i, id) in params.ids.iter().enumerate() {
+        let Some((rel_path, label)) = marked.get(id) else {
+            res.add_failure(*id, "Item not found".to_string());
+            continue;
+        };
+        let (Some(base_path), Some(trash_dir)) = (config.model_paths.get(label), config.trash_path(label)) else {
+            res.add_failure(*id, format!("Label {label} is not in model paths"));
+            unmoved.push(*id);
+            continue;
+        };
+        let model_file = PathBuf::from(base_path).join(rel_path);
+
+        if let Err(e) = fs::create_dir_all(long_path(&trash_dir)).await {
+            error!("Failed to create {:?}: {}", trash_dir, e);
+            res.add_failure(*id, format!("Failed to create {trash_dir:?}: {e}"));
+            unmoved.push(*id);
+            for skipped_id in params.ids.iter().skip(i + 1) {
+                res.add_failure(*skipped_id, "Skipped due to previous error".to_string());
+                if marked.contains_key(skipped_id) {
+                    unmoved.push(*skipped_id);
+                }
+            }
+            break;
+        }
 
-    Ok(matches)
+        let mut moved = Vec::new();
+        let result = move_files_to_trash(&config, *id, &model_file, &trash_dir, &mut moved).await;
+        res.moved.push(MovedFiles {
+            id: *id,
+            count: moved.len(),
+            files: moved
+                .iter()
+                .map(|file| file.file_name().unwrap_or_default().to_string_lossy().to_string())
+                .collect(),
+        });
+        match result.err() {
+            Some(reason) => {
+                res.add_failure(*id, reason);
+                if model_file.exists() {
+                    unmoved.push(*id);
+                }
+            }
+            None => {
+                audit(
+                    &db_pool,
+                    AuditAction::Delete,
+                    Some(*id),
+                    actor.as_deref(),
+                    &model_file.display().to_string(),
+                )
+                .await;
+                if params.mode == DeleteMode::Permanent
+                    && let Err(reason) = purge_from_trash(&db_pool, *id, &model_file, &moved, &trash_dir).await
+                {
+                    res.add_failure(*id, reason);
+                    continue;
+                }
+                res.deleted.push(*id);
+            }
+        }
+    }
+
+    if let Err(e) = db::item::unmark_obsolete(&db_pool, &unmoved).await {
+        error!("Failed to restore items which are not deleted: {}", e);
+    }
+
+    Either::Right(web::Json(res))
 }
 
-fn guess_saved_location(base_path: &str, model_type: &str) -> String {
-    let mut path = PathBuf::from(base_path);
-    if model_type.eq_ignore_ascii_case("LORA") {
-        path = path.join("loras");
-    } else if model_type.eq_ignore_ascii_case("Hypernetwork") {
-        path = path.join("hypernetworks");
-    } else if model_type.eq_ignore_ascii_case("Checkpoint") {
-        path = path.join("checkpoints");
+/// Remove items from database without touching their files, which may be gone already
+async fn delete_db_only(db_pool: &DBPool, ids: &[i64], actor: Option<&str>) -> DeleteResponse {
+    let mut res = DeleteResponse::default();
+    for id in ids.iter() {
+        let item = match db::item::get_by_id(&db_pool.sqlite_pool, *id).await {
+            Ok(item) => item,
+            Err(e) => {
+                res.add_failure(*id, format!("{e}"));
+                continue;
+            }
+        };
+        if let Err(e) = db::item::delete(db_pool, *id).await {
+            res.add_failure(*id, format!("{e}"));
+            continue;
+        }
+        audit(
+            db_pool,
+            AuditAction::Delete,
+            Some(*id),
+            actor,
+            &format!("{} (database only)", item.path),
+        )
+        .await;
+        res.deleted.push(*id);
+    }
+    res
+}
+
+/// Remove files of `model_file` which were just moved to `trash_dir`, then remove the item from database
+async fn purge_from_trash(
+    db_pool: &DBPool,
+    id: i64,
+    model_file: &Path,
+    moved: &[PathBuf],
+    trash_dir: &Path,
+) -> Result<(), String> {
+    let stem = model_file.file_stem().unwrap_or_default();
+    for file in moved.iter() {
+        let trashed = trash_dir.join(trash_file_name(file, stem, id));
+        if let Err(e) = fs::remove_file(long_path(&trashed)).await {
+            error!("Failed to remove {}: {}", trashed.display(), e);
+            return Err(format!(
+                "Moved to trash but failed to remove {}: {e}",
+                trashed.display()
+            ));
+        }
+    }
+    db::item::delete(db_pool, id)
+        .await
+        .map_err(|e| format!("Removed files but failed to remove item: {e}"))
+}
+
+/// List files which would be moved to trash when deleting items
+async fn preview_delete(config: &Config, db_pool: &DBPool, ids: &[i64], mode: DeleteMode) -> DryRunDeleteResponse {
+    let mut items = Vec::new();
+    for id in ids.iter() {
+        let mut preview = DeletePreview {
+            id: *id,
+            files: Vec::new(),
+            err: None,
+        };
+        match db::item::get_by_id(&db_pool.sqlite_pool, *id).await {
+            // No file is touched
+            Ok(_) if mode == DeleteMode::DbOnly => {}
+            Ok(item) => {
+                if let Some(base_path) = config.model_paths.get(&item.base_label) {
+                    let model_file = PathBuf::from(base_path).join(&item.path);
+                    let mut files = list_same_filename(&model_file, config).unwrap_or_default();
+                    let model_json = model_file.with_extension("model.json");
+                    if model_json.is_file() {
+                        files.push(model_json);
+                    }
+                    preview.files = files.iter().map(|f| f.display().to_string()).collect();
+                } else {
+                    preview.err = Some(format!("Label {} is not in model paths", item.base_label));
+                }
+            }
+            Err(e) => preview.err = Some(format!("{e}")),
+        }
+        items.push(preview);
+    }
+
+    DryRunDeleteResponse { dry_run: true, items }
+}
+
+#[post("update")]
+async fn update(
+    req: HttpRequest,
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<ItemUpdate>,
+) -> Result<impl Responder, ApiError> {
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, data.item_id).await?;
+    let actor = request_actor(&req);
+    let old_tags = db::tag::list_tags(&db_pool.sqlite_pool, HashSet::from([item.id]))
+        .await?
+        .into_iter()
+        .map(|tag| tag.tag)
+        .collect::<HashSet<_>>();
+    let new_tags = data.tags.split_whitespace().map(normalize_tag).collect::<HashSet<_>>();
+
+    update_tags_and_note(&db_pool, data.item_id, data.tags.as_str(), data.note.as_str())
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to update item: {e}")))?;
+    if old_tags != new_tags {
+        audit(
+            &db_pool,
+            AuditAction::UpdateTags,
+            Some(item.id),
+            actor.as_deref(),
+            &data.tags,
+        )
+        .await;
+    }
+    if item.note != data.note {
+        audit(
+            &db_pool,
+            AuditAction::UpdateNote,
+            Some(item.id),
+            actor.as_deref(),
+            &data.note,
+        )
+        .await;
+    }
+
+    let mut res = ItemUpdateResponse::default();
+    if let Some(description) = data.description.as_ref() {
+        let config = config.config.read().await;
+        let (_, _, model_json, _) = get_abs_path(&config, &item.base_label, &item.path);
+        save_description(Path::new(&model_json), description).await?;
+        res.description = Some(description.clone());
+    }
+
+    Ok(web::Json(res))
+}
+
+/// Write description into *.model.json. Create the file if it does not exist.
+/// A file which can't be read or parsed is left untouched, so that its Civitai metadata is not lost.
+async fn save_description(model_json: &Path, description: &str) -> Result<(), ApiError> {
+    let mut model_parsed = match fs::read_to_string(model_json).await {
+        Ok(model_info) => serde_json::from_str::<Value>(&model_info)
+            .map_err(|e| ApiError::Internal(format!("Failed to parse {}: {}", model_json.display(), e)))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Value::Object(Default::default()),
+        Err(e) => {
+            return Err(ApiError::Internal(format!(
+                "Failed to read {}: {}",
+                model_json.display(),
+                e
+            )))
+        }
+    };
+    if !model_parsed.is_object() {
+        return Err(ApiError::Internal(format!(
+            "{} is not a JSON object",
+            model_json.display()
+        )));
+    }
+    model_parsed["description"] = Value::String(description.to_string());
+    save_info(model_json, &model_parsed)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to save description: {e}")))
+}
+
+/// Return description and note of an item as sanitized HTML
+#[get("{id}/description_html")]
+async fn description_html(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    id: web::Path<i64>,
+) -> Result<impl Responder, ApiError> {
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, id.into_inner()).await?;
+
+    let config = config.config.read().await;
+    let (_, _, model_json, _) = get_abs_path(&config, &item.base_label, &item.path);
+    let model_info = fs::read_to_string(&model_json).await.unwrap_or_default();
+    let model_parsed: Value = serde_json::from_str(model_info.as_str()).unwrap_or_default();
+    let description = model_parsed["description"].as_str().unwrap_or_default();
+
+    Ok(web::Json(DescriptionHtmlResponse {
+        description: markdown_to_html(description),
+        note: markdown_to_html(&item.note),
+    }))
+}
+
+#[get("{id}/details")]
+async fn details(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    id: web::Path<i64>,
+) -> Result<impl Responder, ApiError> {
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, id.into_inner()).await?;
+    let sha256 = db::item::get_sha256(&db_pool.sqlite_pool, item.id).await?;
+
+    let config = config.config.read().await;
+    let (_, json, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+    let info = fs::read_to_string(&json).await.unwrap_or_default();
+    let info_parsed: Value = serde_json::from_str(&info).unwrap_or_default();
+
+    let base_model = info_parsed["baseModel"].as_str().unwrap_or_default().to_string();
+    let model_id = info_parsed["modelId"].as_i64();
+    let version_id = info_parsed["id"].as_i64();
+    let mut trigger_words: Vec<String> = info_parsed["trainedWords"]
+        .as_array()
+        .map(|words| words.iter().filter_map(|w| w.as_str().map(|w| w.to_string())).collect())
+        .unwrap_or_default();
+    // Read from `.txt` when the model was indexed
+    if trigger_words.is_empty()
+        && let Some(words) = db::item::get_trigger_words(&db_pool.sqlite_pool, item.id).await?
+    {
+        trigger_words = words.split(", ").map(|word| word.to_string()).collect();
+    }
+    let (source_url, air) = match (model_id, version_id) {
+        (Some(model_id), Some(version_id)) => (
+            Some(model_page_url(model_id, version_id)),
+            air_identifier(&base_model, &item.model_type, model_id, version_id),
+        ),
+        // Saved source of a download whose metadata is not fetched yet
+        _ => (db::item::get_source_url(&db_pool.sqlite_pool, item.id).await?, None),
+    };
+
+    Ok(web::Json(ItemDetails {
+        id: item.id,
+        filename: Path::new(&item.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_string(),
+        download_url: info_parsed["downloadUrl"].as_str().map(|url| url.to_string()),
+        path: item.path,
+        base_label: item.base_label,
+        blake3: item.blake3,
+        model_type: item.model_type,
+        base_model,
+        trigger_words,
+        source_url,
+        air,
+        sha256,
+    }))
+}
+
+/// Hash the file of an item again and save the hashes if they changed
+#[get("rehash")]
+async fn rehash(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    query: Query<RehashQuery>,
+) -> Result<impl Responder, ApiError> {
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, query.id).await?;
+    let old_sha256 = db::item::get_sha256(&db_pool.sqlite_pool, item.id).await?;
+
+    // Config is not locked while hashing, which can take a while for large files
+    let (path, compute_sha256) = {
+        let config = config.config.read().await;
+        let (path, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+        (PathBuf::from(path), config.compute_sha256)
+    };
+    if path.as_os_str().is_empty() || !path.is_file() {
+        return Err(ApiError::NotFound(format!("File not found: {}", item.path)));
+    }
+
+    let hash_path = path.clone();
+    let (new_blake3, new_sha256) = web::block(move || {
+        if compute_sha256 {
+            calculate_hashes(&hash_path).map(|(blake3, sha256)| (blake3, Some(sha256)))
+        } else {
+            calculate_blake3(&hash_path).map(|blake3| (blake3, None))
+        }
+    })
+    .await
+    .map_err(|e| ApiError::Internal(e.to_string()))?
+    .map_err(|e| ApiError::Internal(format!("Failed to hash {}: {e}", path.display())))?;
+    let old_blake3 = item.blake3.to_lowercase();
+    let matched = old_blake3 == new_blake3
+        && match (&old_sha256, &new_sha256) {
+            (Some(old), Some(new)) => old == new,
+            _ => true,
+        };
+
+    if !matched || (old_sha256.is_none() && new_sha256.is_some()) {
+        db::item::set_hashes(&db_pool, item.id, &new_blake3, new_sha256.as_deref()).await?;
+    }
+    if matched {
+        broadcaster
+            .info(&format!("Hash of {} is unchanged", path.display()))
+            .await;
     } else {
-        path = path.join(model_type.to_lowercase());
+        broadcaster
+            .warn(&format!(
+                "Hash of {} changed from {} to {}",
+                path.display(),
+                old_blake3,
+                new_blake3
+            ))
+            .await;
+    }
+
+    Ok(web::Json(RehashResponse {
+        id: item.id,
+        old_blake3,
+        new_blake3,
+        old_sha256,
+        new_sha256,
+        matched,
+    }))
+}
+
+/// Write metadata and preview of an item next to its model file in the layout of another tool. Own sidecar files
+/// are kept.
+#[get("export_sidecar")]
+async fn export_sidecar(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    query: Query<ExportSidecarQuery>,
+) -> Result<impl Responder, ApiError> {
+    let format = query.format.as_deref().unwrap_or("civitai_helper");
+    if format != "civitai_helper" {
+        return Err(ApiError::BadRequest(format!("Unsupported format: {format}")));
+    }
+
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, query.id).await?;
+    let config = config.config.read().await;
+    let (model_path, json, model_json, _) = get_abs_path(&config, &item.base_label, &item.path);
+    let model_path = Path::new(&model_path);
+    if model_path.as_os_str().is_empty() || !model_path.is_file() {
+        return Err(ApiError::NotFound(format!("File not found: {}", item.path)));
+    }
+    let info = fs::read_to_string(long_path(Path::new(&json)))
+        .await
+        .map_err(|_| ApiError::NotFound(format!("{} has no Civitai metadata", item.path)))?;
+    let mut info: Value = serde_json::from_str(&info).map_err(|e| ApiError::Internal(format!("{json}: {e}")))?;
+
+    // Civitai Helper reads version info as is. Fill its model part from `.model.json` if it's missing.
+    if !info["model"].is_object() {
+        let model_info = fs::read_to_string(long_path(Path::new(&model_json)))
+            .await
+            .unwrap_or_default();
+        let model_info: Value = serde_json::from_str(&model_info).unwrap_or_default();
+        if model_info.is_object() {
+            info["model"] = serde_json::json!({
+                "name": model_info["name"],
+                "type": model_info["type"],
+                "nsfw": model_info["nsfw"],
+                "poi": model_info["poi"],
+            });
+        }
+    }
+
+    let mut files = Vec::new();
+    let info_file = model_path.with_extension("civitai.info");
+    let info_str = serde_json::to_string_pretty(&info).map_err(|e| ApiError::Internal(format!("{e}")))?;
+    fs::write(long_path(&info_file), info_str)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to write {}: {e}", info_file.display())))?;
+    files.push(info_file.display().to_string());
+
+    if let Some(preview) = config.find_preview(model_path) {
+        let ext = preview.extension().unwrap_or_default().to_string_lossy();
+        let preview_file = model_path.with_extension(format!("preview.{ext}"));
+        fs::copy(long_path(&preview), long_path(&preview_file))
+            .await
+            .map_err(|e| ApiError::Internal(format!("Failed to write {}: {e}", preview_file.display())))?;
+        files.push(preview_file.display().to_string());
+    }
+
+    Ok(web::Json(ExportSidecarResponse { files }))
+}
+
+/// Items whose file has the given SHA256
+#[get("by_sha256")]
+async fn by_sha256(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    query: Query<Sha256Query>,
+) -> Result<impl Responder, ApiError> {
+    let hash = query.hash.trim().to_lowercase();
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ApiError::BadRequest(format!("Invalid SHA256 hash: {}", query.hash)));
     }
 
+    let items = db::item::get_by_sha256(&db_pool.sqlite_pool, &hash).await?;
+    let config = config.config.read().await;
+    let listing = DirListing::of_items(&config, &items).await;
+    let mut infos = Vec::with_capacity(items.len());
+    for item in items {
+        infos.push(build_model_info(&config, &listing, item, None, true, config.api.display_name).await);
+    }
+    Ok(web::Json(infos))
+}
+
+/// Return generation parameters embedded in preview image of an item
+#[get("{id}/gen_params")]
+async fn gen_params(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    id: web::Path<i64>,
+) -> Result<impl Responder, ApiError> {
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, id.into_inner()).await?;
+
+    let config = config.config.read().await;
+    let (model_path, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+    let data = match config.find_preview(Path::new(&model_path)) {
+        Some(preview_path) => fs::read(&preview_path).await.unwrap_or_default(),
+        None => Vec::new(),
+    };
+
+    Ok(web::Json(parse_png(&data)))
+}
+
+/// Multipart errors as JSON. An upload over the limit of its field is rejected with 413 instead of 400.
+fn multipart_error(err: MultipartError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        MultipartError::Payload(PayloadError::Overflow) => {
+            ApiError::PayloadTooLarge("Uploaded file is too large".to_string()).into()
+        }
+        err => ApiError::BadRequest(err.to_string()).into(),
+    }
+}
+
+/// Replace preview image of an item with the uploaded one
+#[post("preview")]
+async fn upload_preview(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    MultipartForm(form): MultipartForm<PreviewUpload>,
+) -> Result<impl Responder, ApiError> {
+    let is_image = form
+        .file
+        .content_type
+        .as_ref()
+        .is_some_and(|mime| mime.essence_str().starts_with("image/"));
+    if !is_image || file_type(form.file.file.path()).await != FileType::Image {
+        return Err(ApiError::BadRequest("Uploaded file is not an image".to_string()));
+    }
+
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, *form.id).await?;
+
+    let config = config.config.read().await;
+    let (model_path, _, _, preview_url) = get_abs_path(&config, &item.base_label, &item.path);
+    if model_path.is_empty() {
+        let err = format!("Label {} is not in model paths", item.base_label);
+        return Err(ApiError::BadRequest(err));
+    }
+
+    // Save under the main preview extension, which is the one served by `preview_url`
+    let model_path = Path::new(&model_path);
+    let preview_path = long_path(&model_path.with_extension(config.preview_ext()));
+    fs::copy(form.file.file.path(), &preview_path)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to save preview {}: {}", preview_path.display(), e)))?;
+
+    // Previews with other extensions would be shown instead of the new one after the main one is gone
+    for ext in config
+        .preview_extensions
+        .iter()
+        .filter(|ext| *ext != config.preview_ext())
+    {
+        let old_preview = long_path(&model_path.with_extension(ext));
+        if old_preview.is_file()
+            && let Err(e) = fs::remove_file(&old_preview).await
+        {
+            warn!("Failed to remove old preview {}: {}", old_preview.display(), e);
+        }
+    }
+
+    // Change the url so that browser does not show the cached image
+    let version = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    Ok(web::Json(PreviewUploadResponse {
+        preview: format!("{preview_url}?v={version}"),
+    }))
+}
+
+/// Pin a gallery image as the main preview of an item. The original preview is kept as gallery image 0 so that it
+/// can be pinned back.
+#[post("set_preview")]
+async fn set_preview(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<SetPreviewRequest>,
+) -> Result<impl Responder, ApiError> {
+    let item = db::item::get_by_id(&db_pool.sqlite_pool, data.id).await?;
+
+    let config = config.config.read().await;
+    let (model_path, _, _, preview_url) = get_abs_path(&config, &item.base_label, &item.path);
+    if model_path.is_empty() {
+        let err = format!("Label {} is not in model paths", item.base_label);
+        return Err(ApiError::BadRequest(err));
+    }
+    let model_path = Path::new(&model_path);
+    let stem = model_path.file_stem().unwrap_or_default();
+    let find_gallery_image = |index: usize| {
+        list_same_filename(model_path, &config)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|p| gallery_index(p, stem) == Some(index))
+            .find(|p| config.is_preview_ext(&p.extension().unwrap_or_default().to_string_lossy()))
+    };
+
+    let current_preview = config.find_preview(model_path);
+    if item.preview_index == 0
+        && let Some(preview) = current_preview.as_ref()
+        && find_gallery_image(0).is_none()
+    {
+        let extension = preview.extension().unwrap_or_default().to_string_lossy();
+        let original = model_path.with_extension(format!("0.{extension}"));
+        fs::copy(long_path(preview), long_path(&original))
+            .await
+            .map_err(|e| ApiError::Internal(format!("Failed to keep preview {}: {}", preview.display(), e)))?;
+    }
+
+    let image = find_gallery_image(data.index)
+        .ok_or_else(|| ApiError::NotFound(format!("Gallery image {} of item {} not found", data.index, item.id)))?;
+    let extension = image.extension().unwrap_or_default();
+    let preview_path = model_path.with_extension(extension);
+    // Copy to a temporary file which replaces the preview at once, so the current preview is kept if copying fails.
+    // The temporary file is removed on drop if it's not persisted.
+    let save_err = |e: &dyn std::fmt::Display| {
+        ApiError::Internal(format!("Failed to save preview {}: {}", preview_path.display(), e))
+    };
+    let tmp_path = tempfile::Builder::new()
+        .suffix(".tmp")
+        .tempfile_in(long_path(model_path.parent().unwrap_or(Path::new("."))))
+        .map_err(|e| save_err(&e))?
+        .into_temp_path();
+    fs::copy(long_path(&image), &tmp_path).await.map_err(|e| save_err(&e))?;
+    tmp_path.persist(long_path(&preview_path)).map_err(|e| save_err(&e))?;
+    // Extension of the pinned image may differ from the current one, which would be found first
+    if let Some(preview) = current_preview
+        && preview != preview_path
+    {
+        fs::remove_file(long_path(&preview))
+            .await
+            .map_err(|e| ApiError::Internal(format!("Failed to remove preview {}: {}", preview.display(), e)))?;
+    }
+
+    db::item::set_preview_index(&db_pool.sqlite_pool, item.id, data.index as i64).await?;
+
+    let preview_url = PathBuf::from(&preview_url)
+        .with_extension(extension)
+        .to_str()
+        .unwrap_or_default()
+        .to_string();
+    let preview = match data.index {
+        0 => preview_url,
+        index => format!("{preview_url}?v={index}"),
+    };
+    Ok(web::Json(PreviewUploadResponse { preview }))
+}
+
+/// Fetch Civitai image with the API key. Images are cached on disk by url.
+#[get("image_proxy")]
+async fn image_proxy(
+    req: HttpRequest,
+    config: Data<ConfigData>,
+    params: Query<ImageProxyQuery>,
+) -> Result<HttpResponse, ApiError> {
+    // Only proxy Civitai so that this can't be used to reach other hosts
+    if !Url::parse(&params.url).is_ok_and(|url| is_civitai_url(&url)) {
+        return Err(ApiError::BadRequest("Only Civitai urls are allowed".to_string()));
+    }
+
+    let config = config.config.read().await;
+    let cache_dir = PathBuf::from(&config.civitai.image_cache_dir);
+    let cache_file = cache_dir.join(blake3::hash(params.url.as_bytes()).to_hex().as_str());
+    if cache_file.is_file() {
+        // Bump the modification time so that recently used images are evicted last
+        if let Ok(file) = fs::OpenOptions::new().write(true).open(&cache_file).await {
+            let _ = file.into_std().await.set_modified(SystemTime::now());
+        }
+        return serve_cached_image(&req, &cache_file, None).await;
+    }
+
+    // Follow redirects only within Civitai
+    let client = Client::builder()
+        .redirect(Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_PROXY_REDIRECTS {
+                attempt.error("Too many redirects")
+            } else if !is_civitai_url(attempt.url()) {
+                attempt.error("Redirect outside of Civitai")
+            } else {
+                attempt.follow()
+            }
+        }))
+        .build()
+        .map_err(|e| ApiError::Internal(format!("Failed to create client: {e}")))?;
+    let res = client
+        .get(&params.url)
+        .headers(auth_headers(&config))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| ApiError::Internal(format!("Failed to fetch image: {e}")))?;
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    fs::create_dir_all(&cache_dir)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to create {}: {}", cache_dir.display(), e)))?;
+
+    // Stream to a temporary file first so that a partial file is never served. Its name is unique, so concurrent
+    // requests of the same url don't write to the same file.
+    let tmp_file = tempfile::Builder::new()
+        .suffix(".tmp")
+        .tempfile_in(&cache_dir)
+        .map_err(|e| ApiError::Internal(format!("Failed to create temporary file: {e}")))?;
+    let (file, tmp_path) = tmp_file.into_parts();
+    let mut file = fs::File::from_std(file);
+    let mut stream = res.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| ApiError::Internal(format!("Failed to fetch image: {e}")))?;
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| ApiError::Internal(format!("Failed to cache image {}: {}", params.url, e)))?;
+    }
+    file.flush()
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to cache image {}: {}", params.url, e)))?;
+    drop(file);
+    tmp_path
+        .persist(&cache_file)
+        .map_err(|e| ApiError::Internal(format!("Failed to cache image {}: {}", params.url, e)))?;
+
+    let max_bytes = config.civitai.image_cache_max_mb * 1024 * 1024;
+    if let Err(e) = prune_image_cache(&cache_dir, max_bytes).await {
+        error!("Failed to prune image cache {}: {}", cache_dir.display(), e);
+    }
+
+    serve_cached_image(&req, &cache_file, Some(&content_type)).await
+}
+
+/// Serve an image from the cache without reading all of it into memory.
+/// The type is guessed from the file content if `content_type` is unknown.
+async fn serve_cached_image(
+    req: &HttpRequest,
+    cache_file: &Path,
+    content_type: Option<&str>,
+) -> Result<HttpResponse, ApiError> {
+    let content_type = match content_type {
+        Some(content_type) => content_type.to_string(),
+        None => {
+            let mut head = [0; 64];
+            let len = match fs::File::open(cache_file).await {
+                Ok(mut file) => file.read(&mut head).await.unwrap_or_default(),
+                Err(_) => 0,
+            };
+            infer::get(&head[..len])
+                .map_or("application/octet-stream", |t| t.mime_type())
+                .to_string()
+        }
+    };
+    let mime = content_type.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let file = NamedFile::open_async(cache_file)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to read cached image {}: {}", cache_file.display(), e)))?;
+    Ok(file.set_content_type(mime).into_response(req))
+}
+
+/// Check if the url is https on Civitai or one of its subdomains
+fn is_civitai_url(url: &Url) -> bool {
+    url.scheme() == "https"
+        && url
+            .host_str()
+            .is_some_and(|host| host == CIVITAI_HOST || host.ends_with(&format!(".{CIVITAI_HOST}")))
+}
+
+/// Remove the least recently used images until the cache fits in `max_bytes`. 0 means no limit.
+async fn prune_image_cache(cache_dir: &Path, max_bytes: u64) -> std::io::Result<()> {
+    if max_bytes == 0 {
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    let mut total = 0;
+    let mut entries = fs::read_dir(cache_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        // Temporary files are being written by other requests
+        if !metadata.is_file() || path.extension() == Some(OsStr::new("tmp")) {
+            continue;
+        }
+        total += metadata.len();
+        files.push((metadata.modified()?, metadata.len(), path));
+    }
+
+    files.sort();
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        match fs::remove_file(&path).await {
+            Ok(_) => total -= len,
+            Err(e) if e.kind() == ErrorKind::NotFound => total -= len,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Search models on Civitai with the API key, so that the key is not sent to the browser.
+/// Query parameters are passed to the Civitai models API as is.
+#[get("civitai_search")]
+async fn civitai_search(config: Data<ConfigData>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let mut url = Url::parse(&format!("https://{CIVITAI_HOST}/api/v1/models"))
+        .map_err(|e| ApiError::Internal(format!("Invalid Civitai url: {e}")))?;
+    url.set_query(Some(req.query_string()).filter(|q| !q.is_empty()));
+
+    let config = config.config.read().await;
+    let client = Client::new();
+    let res = client
+        .get(url)
+        .headers(auth_headers(&config))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| ApiError::Internal(format!("Failed to search Civitai: {e}")))?;
+    let data = res
+        .bytes()
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to search Civitai: {e}")))?;
+
+    Ok(HttpResponse::Ok().content_type("application/json").body(data))
+}
+
+/// Return http paths of gallery images `<name>.<index>.<ext>` among `files` of a model, ordered by index.
+/// The pinned image is excluded because it's already the main preview.
+fn list_gallery(files: &[PathBuf], model_path: &Path, preview_url: &str, pinned: i64) -> Vec<String> {
+    let stem = model_path.file_stem().unwrap_or_default();
+    let mut images = files
+        .iter()
+        .filter_map(|p| Some((gallery_index(p, stem)?, p)))
+        .filter(|(index, _)| *index as i64 != pinned)
+        .collect::<Vec<_>>();
+    images.sort();
+
+    images
+        .iter()
+        .filter_map(|(_, p)| {
+            let url = PathBuf::from(preview_url).with_file_name(p.file_name()?);
+            Some(url.to_str()?.to_string())
+        })
+        .collect()
+}
+
+/// Files in the directories of a page of items. Each directory is listed once in a blocking thread, and its files are
+/// shared by previews and gallery of all items in it.
+#[derive(Default)]
+struct DirListing {
+    /// Regular files by directory, then by the name of the model which they belong to. Gallery image
+    /// `<name>.<index>.<ext>` belongs to `<name>` too.
+    files: HashMap<PathBuf, HashMap<OsString, Vec<PathBuf>>>,
+}
+
+impl DirListing {
+    /// List directories of `items` which are in model paths
+    async fn of_items(config: &Config, items: &[Item]) -> Self {
+        let dirs = items
+            .iter()
+            .filter(|item| config.model_paths.contains_key(&item.base_label))
+            .filter_map(|item| {
+                let (model_path, _, _, _) = get_abs_path(config, &item.base_label, &item.path);
+                Some(Path::new(&model_path).parent()?.to_path_buf())
+            })
+            .collect::<HashSet<_>>();
+        web::block(move || Self::read(dirs)).await.unwrap_or_default()
+    }
+
+    fn read(dirs: HashSet<PathBuf>) -> Self {
+        let mut files = HashMap::new();
+        for dir in dirs {
+            let mut by_name: HashMap<OsString, Vec<PathBuf>> = HashMap::new();
+            for entry in std::fs::read_dir(long_path(&dir)).into_iter().flatten().flatten() {
+                // Entries are joined to `dir` so that they are comparable with model paths even if `dir` is prefixed
+                let path = dir.join(entry.file_name());
+                let is_file = match entry.file_type() {
+                    Ok(file_type) if file_type.is_symlink() => {
+                        std::fs::metadata(long_path(&path)).is_ok_and(|m| m.is_file())
+                    }
+                    Ok(file_type) => file_type.is_file(),
+                    Err(_) => false,
+                };
+                let Some(stem) = path.file_stem().filter(|_| is_file) else {
+                    continue;
+                };
+                if let Some((name, index)) = stem.to_str().and_then(|stem| stem.rsplit_once('.'))
+                    && index.parse::<usize>().is_ok()
+                {
+                    by_name.entry(OsString::from(name)).or_default().push(path.clone());
+                }
+                by_name.entry(stem.to_os_string()).or_default().push(path);
+            }
+            files.insert(dir, by_name);
+        }
+        Self { files }
+    }
+
+    /// Whether `path` is a regular file, as of listing its directory
+    fn has_file(&self, path: &Path) -> bool {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let stem = path.file_stem().unwrap_or_default();
+        self.files
+            .get(dir)
+            .and_then(|by_name| by_name.get(stem))
+            .is_some_and(|files| files.iter().any(|p| p == path))
+    }
+
+    /// Files which have the same name as the model file, including gallery images. Other model files are excluded.
+    fn same_filename(&self, config: &Config, model_path: &Path) -> Vec<PathBuf> {
+        let dir = model_path.parent().unwrap_or(Path::new("."));
+        let stem = model_path.file_stem().unwrap_or_default();
+        self.files
+            .get(dir)
+            .and_then(|by_name| by_name.get(stem))
+            .into_iter()
+            .flatten()
+            .filter(|p| *p == model_path || !config.is_model_file(p))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Encode sort key `(updated_at, id)` of an item as cursor
+fn encode_cursor(item: &Item) -> String {
+    format!("{}_{}", item.updated_at.unwrap_or_default(), item.id)
+}
+
+fn decode_cursor(cursor: &str) -> Option<(i64, i64)> {
+    let (updated_at, id) = cursor.split_once('_')?;
+    Some((updated_at.parse().ok()?, id.parse().ok()?))
+}
+
+/// Parse ids which are repeated or comma separated like `id=1&id=2,3`
+fn parse_ids(ids: &[String]) -> Result<Vec<i64>, ApiError> {
+    ids.iter()
+        .flat_map(|ids| ids.split(','))
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map_err(|_| ApiError::BadRequest(format!("Invalid id: {id}")))
+        })
+        .collect()
+}
+
+fn parse_optional_date(date: Option<&str>) -> Result<Option<i64>, ApiError> {
+    date.map(|date| parse_date(date).ok_or_else(|| ApiError::BadRequest(format!("Invalid date: {date}"))))
+        .transpose()
+}
+
+/// Parse epoch millis, `YYYY-MM-DD` or RFC 3339 datetime to epoch millis
+fn parse_date(date: &str) -> Option<i64> {
+    let date = date.trim();
+    if let Ok(millis) = date.parse::<i64>() {
+        return Some(millis);
+    }
+
+    let datetime = match Date::parse(date, format_description!("[year]-[month]-[day]")) {
+        Ok(date) => date.midnight().assume_utc(),
+        Err(_) => OffsetDateTime::parse(date, &Rfc3339).ok()?,
+    };
+    Some((datetime.unix_timestamp_nanos() / 1_000_000) as i64)
+}
+
+fn guess_saved_location(config: &Config, base_path: &str, model_type: &str) -> String {
+    let path = PathBuf::from(base_path).join(config.civitai.model_type_dir(model_type));
     path.to_str().unwrap_or_default().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::insert_test_item;
+    use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use tokio::sync::RwLock;
+
+    /// Signature of a PNG file, which is enough to be detected as an image
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    /// Start of an MP4 file
+    const MP4: &[u8] = b"\0\0\0\x18ftypisom\0\0\0\0";
+
+    /// App of the item scope with `config` and `db_pool`. The config data is returned for tests which change it.
+    fn test_app(
+        config: Config,
+        db_pool: DBPool,
+    ) -> (
+        App<
+            impl ServiceFactory<
+                ServiceRequest,
+                Config = (),
+                Response = ServiceResponse,
+                Error = actix_web::Error,
+                InitError = (),
+            >,
+        >,
+        Data<ConfigData>,
+    ) {
+        let config = Data::new(ConfigData {
+            config: RwLock::new(config),
+            config_path: PathBuf::new(),
+            bind_addr: String::new(),
+        });
+        let app = App::new()
+            .app_data(config.clone())
+            .app_data(Data::new(db_pool))
+            .configure(scope);
+        (app, config)
+    }
+
+    #[actix_web::test]
+    async fn single_item_is_cached_with_etag() {
+        let db_pool = DBPool::memory().await;
+        let id = insert_test_item(&db_pool, "model.safetensors", "model.safetensors", 0).await;
+        let (app, config) = test_app(Config::default(), db_pool);
+        let app = test::init_service(app).await;
+
+        let get = |uri: String, etag: Option<String>| {
+            let mut req = test::TestRequest::get().uri(&uri);
+            if let Some(etag) = etag {
+                req = req.insert_header((header::IF_NONE_MATCH, etag));
+            }
+            req.to_request()
+        };
+        let etag_of = |res: &actix_web::dev::ServiceResponse| {
+            res.headers().get(header::ETAG).map(|v| v.to_str().unwrap().to_string())
+        };
+
+        let res = test::call_service(&app, get(format!("/item?id={id}&fields=full"), None)).await;
+        assert!(res.status().is_success());
+        let etag = etag_of(&res).expect("single item has an ETag");
+
+        // Same query in another order
+        let res = test::call_service(&app, get(format!("/item?fields=full&id={id}"), Some(etag.clone()))).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        // Query which changes the response
+        let res = test::call_service(
+            &app,
+            get(
+                format!("/item?id={id}&fields=full&display_name=ModelName"),
+                Some(etag.clone()),
+            ),
+        )
+        .await;
+        assert!(res.status().is_success());
+        assert_ne!(etag_of(&res), Some(etag.clone()));
+
+        // Config which changes the response
+        config.config.write().await.preview_extensions.push("avif".to_string());
+        let res = test::call_service(&app, get(format!("/item?id={id}&fields=full"), Some(etag.clone()))).await;
+        assert!(res.status().is_success());
+        assert_ne!(etag_of(&res), Some(etag));
+    }
+
+    #[actix_web::test]
+    async fn dates_are_parsed_to_millis() {
+        assert_eq!(parse_date("1500"), Some(1500));
+        assert_eq!(parse_date("1970-01-02"), Some(86_400_000));
+        assert_eq!(parse_date("1970-01-01T00:00:01Z"), Some(1000));
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[actix_web::test]
+    async fn sidecars_are_read_from_fixture() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("model.safetensors");
+        std::fs::write(&model_path, b"model").unwrap();
+        let info = r#"{"id": 1, "trainedWords": ["trigger"]}"#;
+        std::fs::write(dir.path().join("model.json"), info).unwrap();
+        std::fs::write(
+            dir.path().join("model.model.json"),
+            r#"{"name": "Model", "description": "<p>Hello</p>"}"#,
+        )
+        .unwrap();
+
+        let (read_info, description) = read_sidecars(&model_path).await;
+        assert_eq!(read_info, info);
+        assert_eq!(description, "<p>Hello</p>");
+
+        // Missing or broken sidecars are empty
+        std::fs::write(dir.path().join("model.model.json"), "{").unwrap();
+        std::fs::remove_file(dir.path().join("model.json")).unwrap();
+        let (read_info, description) = read_sidecars(&model_path).await;
+        assert_eq!(read_info, "");
+        assert_eq!(description, "");
+    }
+
+    #[actix_web::test]
+    async fn dir_listing_groups_files_by_model() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "model.safetensors",
+            "model.png",
+            "model.1.jpeg",
+            "model.2.png",
+            "model.v2.safetensors",
+            "model.v2.png",
+            "other.png",
+        ] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("model.3.png")).unwrap();
+        let config = Config::default();
+
+        let listing = DirListing::read(HashSet::from([dir.path().to_path_buf()]));
+        let names = |model: &str| {
+            let mut names = listing
+                .same_filename(&config, &dir.path().join(model))
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            names("model.safetensors"),
+            ["model.1.jpeg", "model.2.png", "model.png", "model.safetensors"]
+        );
+        assert_eq!(names("model.v2.safetensors"), ["model.v2.png", "model.v2.safetensors"]);
+        assert!(names("missing.safetensors").is_empty());
+        assert!(listing.has_file(&dir.path().join("model.v2.safetensors")));
+        assert!(!listing.has_file(&dir.path().join("missing.safetensors")));
+        assert!(!listing.has_file(&dir.path().join("model.3.png")));
+    }
+
+    /// Previews found next to `model.safetensors` with `files`
+    async fn previews_with(files: &[(&str, &[u8])]) -> (String, Option<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("model.safetensors");
+        std::fs::write(&model_path, b"model").unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+        let config = Config::default();
+        let listing = DirListing::read(HashSet::from([dir.path().to_path_buf()]));
+        let files = listing.same_filename(&config, &model_path);
+        find_previews(&config, &files, &model_path, "/models/test/model.jpeg").await
+    }
+
+    #[actix_web::test]
+    async fn previews_with_only_images() {
+        let files: [(&str, &[u8]); 3] = [("model.png", PNG), ("model.jpg", PNG), ("model.1.jpeg", PNG)];
+        assert_eq!(
+            previews_with(&files).await,
+            ("/models/test/model.jpg".to_string(), None)
+        );
+    }
+
+    #[actix_web::test]
+    async fn previews_with_only_video() {
+        let files: [(&str, &[u8]); 2] = [("model.mp4", MP4), ("model.json", b"{}")];
+        assert_eq!(
+            previews_with(&files).await,
+            (String::new(), Some("/models/test/model.mp4".to_string()))
+        );
+    }
+
+    #[actix_web::test]
+    async fn previews_with_images_and_video() {
+        // Files are detected by content, not by extension
+        let files: [(&str, &[u8]); 4] = [
+            ("model.webm", b"not a video"),
+            ("model.jpeg", MP4),
+            ("model.webp", PNG),
+            ("model.mp4", MP4),
+        ];
+        let (image, video) = previews_with(&files).await;
+        assert_eq!(image, "/models/test/model.webp");
+        assert_eq!(video.as_deref(), Some("/models/test/model.jpeg"));
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn facets_only_reads_no_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("model.safetensors"), b"model").unwrap();
+        // Opening a FIFO for reading blocks until a writer opens it, so any read of the sidecar hangs
+        let fifo = dir.path().join("model.model.json");
+        let status = std::process::Command::new("mkfifo").arg(&fifo).status().unwrap();
+        assert!(status.success());
+
+        let db_pool = DBPool::memory().await;
+        let id = insert_test_item(&db_pool, "model.safetensors", "model.safetensors", 0).await;
+        db::tag::update_tags_and_note(&db_pool, id, "style", "").await.unwrap();
+        let config = Config::with_model_path(dir.path());
+        let (app, _) = test_app(config, db_pool);
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/item?facets_only=true").to_request();
+        let res = tokio::time::timeout(Duration::from_secs(5), test::call_service(&app, req)).await;
+        let Ok(res) = res else {
+            // Unblock the reader so that the runtime can shut down
+            drop(std::fs::File::options().write(true).open(&fifo));
+            panic!("sidecar was read");
+        };
+        assert!(res.status().is_success());
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["items"], serde_json::json!([]));
+        assert_eq!(body["tags"][0]["tag"], "style");
+    }
+
+    #[actix_web::test]
+    async fn updated_at_round_trips_as_millis() {
+        let db_pool = DBPool::memory().await;
+        let updated_at_ms = 1_700_000_000_123;
+        let id = insert_test_item(&db_pool, "model.safetensors", "hash", updated_at_ms).await;
+        let (app, _) = test_app(Config::default(), db_pool);
+        let app = test::init_service(app).await;
+
+        // Both a single item and search results
+        for uri in [format!("/item?id={id}"), "/item?search=model".to_string()] {
+            let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+            assert!(res.status().is_success());
+            let body: Value = test::read_body_json(res).await;
+            assert_eq!(body["items"][0]["updated_at"], updated_at_ms, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn saved_location_uses_configured_type_dirs() {
+        let mut config = Config::default();
+        let base_path = Path::new("/models");
+        let guess = |config: &Config, model_type| PathBuf::from(guess_saved_location(config, "/models", model_type));
+
+        // Defaults and fallback to the lowercased type
+        assert_eq!(guess(&config, "LORA"), base_path.join("loras"));
+        assert_eq!(guess(&config, "checkpoint"), base_path.join("checkpoints"));
+        assert_eq!(guess(&config, "LoCon"), base_path.join("locon"));
+
+        config
+            .civitai
+            .model_type_dirs
+            .insert("LoCon".to_string(), "loras".to_string());
+        config
+            .civitai
+            .model_type_dirs
+            .insert("TextualInversion".to_string(), "embeddings".to_string());
+        assert_eq!(guess(&config, "locon"), base_path.join("loras"));
+        assert_eq!(guess(&config, "TextualInversion"), base_path.join("embeddings"));
+        assert_eq!(guess(&config, "VAE"), base_path.join("vae"));
+    }
+
+    #[actix_web::test]
+    async fn display_name_falls_back_to_file_name() {
+        let config = &Config::default();
+        let item = |model_name: &str| Item {
+            id: 1,
+            name: Some("cute_style_v1".to_string()),
+            path: "loras/cute_style_v1.safetensors".to_string(),
+            base_label: "test".to_string(),
+            note: String::new(),
+            updated_at: None,
+            blake3: String::new(),
+            model_type: "lora".to_string(),
+            nsfw_level: None,
+            model_name: model_name.to_string(),
+            preview_index: 0,
+        };
+        let names = |item, display_name| async move {
+            let info = build_model_info(config, &DirListing::default(), item, None, true, display_name).await;
+            (info.name, info.file_name)
+        };
+
+        let file_name = "cute_style_v1".to_string();
+        assert_eq!(
+            names(item("Cute Style"), DisplayName::FileName).await,
+            (file_name.clone(), file_name.clone())
+        );
+        assert_eq!(
+            names(item("Cute Style"), DisplayName::ModelName).await,
+            ("Cute Style".to_string(), file_name.clone())
+        );
+        assert_eq!(
+            names(item(""), DisplayName::ModelName).await,
+            (file_name.clone(), file_name)
+        );
+    }
+
+    #[actix_web::test]
+    async fn pinned_preview_is_returned_by_get_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = |n: u8| [PNG, &[n]].concat();
+        std::fs::write(dir.path().join("model.safetensors"), b"model").unwrap();
+        std::fs::write(dir.path().join("model.png"), image(0)).unwrap();
+        std::fs::write(dir.path().join("model.1.jpeg"), image(1)).unwrap();
+        std::fs::write(dir.path().join("model.2.png"), image(2)).unwrap();
+
+        let db_pool = DBPool::memory().await;
+        let id = insert_test_item(&db_pool, "model.safetensors", "model.safetensors", 0).await;
+        let config = Config::with_model_path(dir.path());
+        let (app, _) = test_app(config, db_pool);
+        let app = test::init_service(app).await;
+
+        let pin = |index: usize| {
+            let req = test::TestRequest::post()
+                .uri("/item/set_preview")
+                .set_json(serde_json::json!({"id": id, "index": index}))
+                .to_request();
+            test::call_service(&app, req)
+        };
+        let file_name = |url: &Value| url.as_str().unwrap().rsplit('/').next().unwrap().to_string();
+        let get_item = || async {
+            let req = test::TestRequest::get().uri(&format!("/item?id={id}")).to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            let item = &body["items"][0];
+            let gallery = item["gallery"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(file_name)
+                .collect::<Vec<_>>();
+            (file_name(&item["preview"]), gallery)
+        };
+
+        let (preview, gallery) = get_item().await;
+        assert_eq!(preview, "model.png");
+        assert_eq!(gallery, ["model.png", "model.1.jpeg", "model.2.png"]);
+
+        assert!(pin(2).await.status().is_success());
+        let (preview, gallery) = get_item().await;
+        assert_eq!(preview, "model.png?v=2");
+        // The original preview is kept as the first gallery image
+        assert_eq!(gallery, ["model.png?v=2", "model.0.png", "model.1.jpeg"]);
+        assert_eq!(std::fs::read(dir.path().join("model.png")).unwrap(), image(2));
+
+        assert!(pin(1).await.status().is_success());
+        let (preview, gallery) = get_item().await;
+        assert_eq!(preview, "model.jpeg?v=1");
+        assert_eq!(gallery, ["model.jpeg?v=1", "model.0.png", "model.2.png"]);
+        assert!(!dir.path().join("model.png").exists());
+        // No temporary file is left behind
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "model.0.png",
+                "model.1.jpeg",
+                "model.2.png",
+                "model.jpeg",
+                "model.safetensors"
+            ]
+        );
+    }
+
+    /// Multipart body of a preview upload of `file` for item `id`
+    fn preview_upload(id: i64, file: &[u8], content_type: &str) -> (String, Vec<u8>) {
+        let boundary = "preview-boundary";
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"id\"\r\n\r\n{id}\r\n\
+            --{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"preview\"\r\n\
+            Content-Type: {content_type}\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(file);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        (format!("multipart/form-data; boundary={boundary}"), body)
+    }
+
+    #[actix_web::test]
+    async fn preview_upload_rejects_large_and_non_image_files() {
+        let db_pool = DBPool::memory().await;
+        let id = insert_test_item(&db_pool, "model.safetensors", "model.safetensors", 0).await;
+        let (app, _) = test_app(Config::default(), db_pool);
+        let app = test::init_service(app).await;
+        let upload = |file: &[u8], content_type: &str| {
+            let (content_type, body) = preview_upload(id, file, content_type);
+            test::TestRequest::post()
+                .uri("/item/preview")
+                .insert_header((header::CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let mut large = PNG.to_vec();
+        large.resize(20 * 1024 * 1024 + 1, 0);
+        let res = test::call_service(&app, upload(&large, "image/png")).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["error"]["code"], "payload_too_large");
+
+        for (file, content_type) in [(PNG, "text/plain"), (b"not an image".as_slice(), "image/png")] {
+            let res = test::call_service(&app, upload(file, content_type)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body: Value = test::read_body_json(res).await;
+            assert_eq!(body["error"]["code"], "bad_request");
+        }
+    }
+}
@@ -1,56 +1,128 @@
+use crate::api::error::ApiError;
 use crate::api::CommonResponse;
-use crate::db::job::Job;
+use crate::db::job::{Job, JobActivity};
 use crate::db::DBPool;
+use crate::ui::{Broadcaster, EventMsg};
 use crate::{db, ConfigData};
 use actix_web::web::{Data, Query};
 use actix_web::{get, web, Responder};
 use serde::{Deserialize, Serialize};
 
 pub fn scope(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::scope("/job").service(get_jobs).service(clear_jobs));
+    cfg.service(
+        web::scope("/job")
+            .service(get_jobs)
+            .service(activity)
+            .service(clear_jobs),
+    );
 }
 
 #[derive(Deserialize)]
 struct JobQuery {
     limit: Option<i64>,
     offset: Option<i64>,
+    /// Text in title or description
+    q: Option<String>,
+    /// 0: running, 1: succeeded, 2: failed
+    state: Option<i64>,
 }
 
 #[derive(Serialize, Default)]
 struct JobResponse {
     jobs: Vec<Job>,
     total: i64,
-    err: Option<String>,
 }
 
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Activity {
+    Job(JobActivity),
+    Event(EventMsg),
+}
+
+impl Activity {
+    fn time(&self) -> i64 {
+        match self {
+            Activity::Job(job) => job.started_at,
+            Activity::Event(event) => event.time,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ActivityResponse {
+    /// Newest first
+    entries: Vec<Activity>,
+    /// Total number of jobs
+    total: i64,
+}
+
+/// Seconds between a job stops and the event announcing it
+const JOB_EVENT_WINDOW: i64 = 5;
+
 #[get("")]
 async fn get_jobs(
     config_data: Data<ConfigData>,
     db_pool: Data<DBPool>,
     query_params: Query<JobQuery>,
-) -> impl Responder {
+) -> Result<impl Responder, ApiError> {
     let config = config_data.config.read().await;
     let limit = query_params.limit.unwrap_or(config.api.per_page as i64);
     let offset = query_params.offset.unwrap_or(0);
-    let mut res = JobResponse::default();
-    match db::job::get(&db_pool.sqlite_pool, limit, offset).await {
-        Ok((jobs, total)) => {
-            res.jobs = jobs;
-            res.total = total;
-        }
-        Err(e) => {
-            res.err = Some(format!("Failed to get jobs list: {}", e));
-        }
-    }
-    web::Json(res)
+    let q = query_params.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let (jobs, total) = db::job::get(&db_pool.sqlite_pool, q, query_params.state, limit, offset)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to get jobs list: {}", e)))?;
+    Ok(web::Json(JobResponse { jobs, total }))
+}
+
+/// Jobs and recent events in one feed. Jobs are paginated, and each page contains the events which happened
+/// between its jobs.
+#[get("activity")]
+async fn activity(
+    config_data: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    query_params: Query<JobQuery>,
+) -> Result<impl Responder, ApiError> {
+    let config = config_data.config.read().await;
+    let limit = query_params.limit.unwrap_or(config.api.per_page as i64).max(1);
+    let offset = query_params.offset.unwrap_or(0).max(0);
+
+    // Fetch the last job of the previous page too, which bounds events of this page
+    let (mut jobs, total) = db::job::get_timeline(&db_pool.sqlite_pool, limit + 1, (offset - 1).max(0)).await?;
+    let newer_bound = if offset > 0 && !jobs.is_empty() { Some(jobs.remove(0).started_at) } else { None };
+    jobs.truncate(limit as usize);
+    // Last page contains all older events
+    let older_bound = if offset + (jobs.len() as i64) < total { jobs.last().map(|job| job.started_at) } else { None };
+
+    let events = broadcaster
+        .history()
+        .into_iter()
+        .filter(|event| {
+            newer_bound.is_none_or(|bound| event.time < bound)
+                && older_bound.is_none_or(|bound| event.time >= bound)
+                // Skip events which only announce how a job ended
+                && !jobs.iter().any(|job| {
+                    !job.desc.is_empty()
+                        && event.msg.contains(&job.desc)
+                        && job.stopped_at.is_some_and(|stopped_at| (event.time - stopped_at).abs() <= JOB_EVENT_WINDOW)
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let mut entries: Vec<Activity> = jobs
+        .into_iter()
+        .map(Activity::Job)
+        .chain(events.into_iter().map(Activity::Event))
+        .collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.time()));
+
+    Ok(web::Json(ActivityResponse { entries, total }))
 }
 
 #[get("clear")]
-async fn clear_jobs(db_pool: Data<DBPool>) -> impl Responder {
-    let msg = if let Err(e) = db::job::clean(&db_pool.sqlite_pool).await {
-        CommonResponse::from_err(format!("{e}").as_str());
-    } else {
-        CommonResponse::from_msg("Clear jobs successfully");
-    };
-    web::Json(msg)
+async fn clear_jobs(db_pool: Data<DBPool>) -> Result<impl Responder, ApiError> {
+    db::job::clean(&db_pool.sqlite_pool).await?;
+    Ok(web::Json(CommonResponse::from_msg("Clear jobs successfully")))
 }
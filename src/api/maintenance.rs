@@ -1,22 +1,32 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
-use crate::api::{get_abs_path, CommonResponse, TRASH_DIR};
-use crate::civitai::{get_item_info, update_model_info};
+use crate::api::error::ApiError;
+use crate::api::{get_abs_path, long_path, move_to_dir, CommonResponse};
+use crate::civitai::{auth_headers, get_item_info, get_model, model_page_url, update_model_info, CivitaiError};
+use crate::config::Config;
+use crate::db::audit::AuditEntry;
 use crate::db::job::{add_job, update_job, JobState};
 use crate::db::DBPool;
 use crate::ui::Broadcaster;
 use crate::{api, db, ConfigData, StopHandle};
 use actix_web::web::{Data, Query};
-use actix_web::{get, rt, web, HttpResponse, Responder};
+use actix_web::{get, post, rt, web, HttpResponse, Responder};
+use futures_util::stream::{self, StreamExt};
 use jwalk::{Parallelism, WalkDir};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use parking_lot::Mutex;
 use reqwest::Client;
-use serde::Deserialize;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
 use tokio::sync::{RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
 use tracing::{error, info};
 
 pub fn scope(cfg: &mut web::ServiceConfig) {
@@ -24,10 +34,19 @@ pub fn scope(cfg: &mut web::ServiceConfig) {
         web::scope("/maintenance")
             .service(scan_folder)
             .service(remove_orphan)
+            .service(clean_orphans)
             .service(sync_civitai)
+            .service(refresh_all)
+            .service(audit_log)
+            .service(normalize_tags)
             .service(restart)
             .service(force_restart)
-            .service(empty_trash),
+            .service(empty_trash)
+            .service(purge_trash)
+            .service(unconfigured_items)
+            .service(check_updates)
+            .service(index_file)
+            .service(missing_items),
     );
 }
 
@@ -37,6 +56,101 @@ struct SyncCivitaiQuery {
     id: Option<i64>,
 }
 
+#[derive(Deserialize)]
+struct RefreshAllQuery {
+    /// Base label. Refresh all items if not set.
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CleanOrphansQuery {
+    /// Only report orphaned files, do not move them to trash
+    dry_run: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    /// History of all items if not set
+    item_id: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AuditResponse {
+    entries: Vec<AuditEntry>,
+    total: i64,
+}
+
+#[derive(Serialize)]
+struct RemoveOrphanResponse {
+    deleted_items: u64,
+}
+
+#[derive(Serialize, Default)]
+struct CleanOrphansResponse {
+    dry_run: bool,
+    /// Metadata and preview files whose model file no longer exists
+    orphaned_files: Vec<String>,
+    /// Orphaned files which could not be moved to trash, with the reason
+    failed: Vec<String>,
+    /// Model files which are not indexed in database
+    unindexed_models: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct UnconfiguredItem {
+    id: i64,
+    base_label: String,
+    /// Path relative to the removed base path
+    path: String,
+}
+
+#[derive(Serialize)]
+struct MissingItem {
+    id: i64,
+    base_label: String,
+    /// Path relative to the model path
+    path: String,
+}
+
+/// Civitai version of an installed item
+struct InstalledVersion {
+    id: i64,
+    path: String,
+    version_id: i64,
+    version: String,
+}
+
+#[derive(Serialize)]
+struct CheckUpdatesResponse {
+    /// Job which lists the updates in its description when it's finished
+    job_id: i64,
+}
+
+#[derive(Deserialize)]
+struct IndexFileRequest {
+    /// Absolute path, or path relative to a model path
+    path: String,
+    /// Model path which a relative `path` is in. All model paths are tried if not set.
+    label: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IndexFileResponse {
+    /// Item id
+    id: i64,
+    label: String,
+    /// Path relative to the model path
+    path: String,
+}
+
+/// Broadcast progress of checking updates after every this many models
+const UPDATE_CHECK_PROGRESS_STEP: usize = 20;
+
+/// Set while a scan is running so that scans do not overlap
+static SCAN_RUNNING: AtomicBool = AtomicBool::new(false);
+
 #[get("scan")]
 async fn scan_folder(
     config: Data<ConfigData>,
@@ -51,17 +165,201 @@ async fn scan_folder(
 }
 
 #[get("remove_orphan")]
-async fn remove_orphan(db_pool: Data<DBPool>, broadcaster: Data<Broadcaster>) -> impl Responder {
+async fn remove_orphan(db_pool: Data<DBPool>, broadcaster: Data<Broadcaster>) -> Result<impl Responder, ApiError> {
     broadcaster.warn("Removing orphaned item...").await;
-    let deleted_items = db::item::clean(&db_pool.sqlite_pool).await.unwrap_or_default();
-    broadcaster.info(&format!("Removed {} orphaned items", deleted_items)).await;
+    let id = add_job(&db_pool.sqlite_pool, "Remove orphaned items", "").await;
+    let deleted_items = match db::item::clean(&db_pool).await {
+        Ok(deleted_items) => deleted_items,
+        Err(e) => {
+            if let Ok(id) = id {
+                let _ = update_job(&db_pool.sqlite_pool, id, &format!("{e}"), JobState::Failed).await;
+            }
+            return Err(e.into());
+        }
+    };
+    let summary = format!("Removed {} orphaned items", deleted_items);
+    if let Ok(id) = id {
+        let _ = update_job(&db_pool.sqlite_pool, id, &summary, JobState::Succeed).await;
+    }
+    broadcaster.info(&summary).await;
+
+    Ok(web::Json(RemoveOrphanResponse { deleted_items }))
+}
+
+#[post("clean_orphans")]
+async fn clean_orphans(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    params: Query<CleanOrphansQuery>,
+) -> Result<impl Responder, ApiError> {
+    let config = config.config.read().await;
+    let dry_run = params.dry_run.unwrap_or(true);
+    if dry_run {
+        return Ok(web::Json(find_orphans(&config, &db_pool, true).await?));
+    }
+
+    let id = add_job(&db_pool.sqlite_pool, "Clean orphaned files", "").await;
+    let res = find_orphans(&config, &db_pool, false).await;
+    let (summary, state) = match &res {
+        Ok(res) if res.failed.is_empty() => (
+            format!("Moved {} orphaned files to trash", res.orphaned_files.len()),
+            JobState::Succeed,
+        ),
+        Ok(res) => (
+            format!(
+                "Moved {} orphaned files to trash, failed to move {}",
+                res.orphaned_files.len() - res.failed.len(),
+                res.failed.len()
+            ),
+            JobState::Failed,
+        ),
+        Err(e) => (format!("{e}"), JobState::Failed),
+    };
+    if let Ok(id) = id {
+        let _ = update_job(&db_pool.sqlite_pool, id, &summary, state).await;
+    }
+    match res {
+        Ok(res) => {
+            broadcaster.info(&summary).await;
+            Ok(web::Json(res))
+        }
+        Err(e) => {
+            broadcaster.error(&summary).await;
+            Err(e)
+        }
+    }
+}
+
+/// List orphaned files and unindexed models. Move orphaned files to trash if not `dry_run`.
+async fn find_orphans(config: &Config, db_pool: &DBPool, dry_run: bool) -> Result<CleanOrphansResponse, ApiError> {
+    let mut res = CleanOrphansResponse {
+        dry_run,
+        ..Default::default()
+    };
+
+    for (label, base_path) in config.model_paths.iter() {
+        let trash_dir = config.trash_path(label).unwrap_or_default();
+        let mut models = Vec::new();
+        let mut others = Vec::new();
+        for entry in WalkDir::new(base_path)
+            .skip_hidden(true)
+            .follow_links(false)
+            .into_iter()
+            .flatten()
+        {
+            let path = entry.path();
+            if !entry.file_type().is_file() || path.starts_with(&trash_dir) {
+                continue;
+            }
+            if config.is_model_file(&path) {
+                models.push(path);
+            } else {
+                others.push(path);
+            }
+        }
+
+        // Models without extension, to look up sidecars by their model name
+        let model_stems = models
+            .iter()
+            .map(|model| model.with_extension(""))
+            .collect::<HashSet<_>>();
+        // `<model>.<ext>` is the preview of a model whose name has dots, like `foo.v2.safetensors`
+        let has_model = |file: &Path| {
+            model_stems.contains(&file.with_extension(""))
+                || sidecar_model_stem(file).is_some_and(|stem| model_stems.contains(&file.with_file_name(stem)))
+        };
+
+        // Files which are left behind by the same model are grouped by its name without extension
+        let mut groups: BTreeMap<PathBuf, BTreeSet<PathBuf>> = BTreeMap::new();
+        for file in others.into_iter().filter(|file| !has_model(file)) {
+            let stem = match sidecar_model_stem(&file) {
+                Some(stem) => file.with_file_name(stem),
+                None => file.with_extension(""),
+            };
+            groups.entry(stem).or_default().insert(file);
+        }
+
+        let mut orphans = Vec::new();
+        for (stem, mut files) in groups {
+            // Same files which deleting the model would have moved to trash. The model is gone, so any extension
+            // gives its name.
+            let mut model_file = stem.into_os_string();
+            model_file.push(".safetensors");
+            let model_file = PathBuf::from(model_file);
+            files.extend(
+                api::list_same_filename(&model_file, config)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|file| !has_model(file)),
+            );
+            // Files without Civitai metadata may be the user's own, like a cover image
+            if files.iter().any(|file| is_civitai_metadata(file)) {
+                orphans.extend(files);
+            }
+        }
+
+        for model in models.iter() {
+            let Ok(relative_path) = api::get_relative_path(base_path, model) else {
+                continue;
+            };
+            match db::item::is_indexed(&db_pool.sqlite_pool, relative_path.as_str(), label).await {
+                Ok(true) => {}
+                Ok(false) => res.unindexed_models.push(model.display().to_string()),
+                Err(e) => error!("Failed to check {}: {}", model.display(), e),
+            }
+        }
+
+        if !res.dry_run
+            && !orphans.is_empty()
+            && let Err(e) = fs::create_dir_all(long_path(&trash_dir)).await
+        {
+            return Err(ApiError::Internal(format!("Failed to create {trash_dir:?}: {e}")));
+        }
+        for orphan in orphans.iter() {
+            res.orphaned_files.push(orphan.display().to_string());
+            if res.dry_run {
+                continue;
+            }
+            if let Err(e) = move_to_dir(std::slice::from_ref(orphan), &trash_dir).await {
+                error!("Failed to move {} to trash: {}", orphan.display(), e);
+                res.failed.push(format!("{}: {}", orphan.display(), e));
+            }
+        }
+    }
 
-    web::Json(format!(
-        "{{
-        \"deleted_items\": {},
-    }}",
-        deleted_items,
-    ))
+    Ok(res)
+}
+
+/// Name of the model which `path` belongs to, if it's named like `<model>.preview.<ext>`, `<model>.model.json`,
+/// `<model>.civitai.info` or gallery image `<model>.<index>.<ext>`
+fn sidecar_model_stem(path: &Path) -> Option<&OsStr> {
+    let ext = path.extension()?;
+    let stem = Path::new(path.file_stem()?);
+    let kind = stem.extension()?.to_str()?;
+    let is_sidecar = match kind {
+        "preview" => true,
+        "model" => ext == "json",
+        "civitai" => ext == "info",
+        index => index.parse::<usize>().is_ok(),
+    };
+    if is_sidecar {
+        stem.file_stem()
+    } else {
+        None
+    }
+}
+
+/// Whether `path` is version or model info which was saved from Civitai
+fn is_civitai_metadata(path: &Path) -> bool {
+    let ext = path.extension().unwrap_or_default();
+    if ext != "json" && ext != "info" {
+        return false;
+    }
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|info| serde_json::from_str::<Value>(&info).ok())
+        .is_some_and(|info| info["modelId"].is_i64() || info["modelVersions"].is_array())
 }
 
 #[get("sync_civitai")]
@@ -70,31 +368,39 @@ async fn sync_civitai(
     db_pool: Data<DBPool>,
     broadcaster: Data<Broadcaster>,
     params: Query<SyncCivitaiQuery>,
-) -> impl Responder {
+) -> Result<impl Responder, ApiError> {
     if let Some(id) = params.id {
-        match db::item::get_by_id(&db_pool.sqlite_pool, id).await {
-            Ok(item) => {
-                let config = config_data.config.read().await;
-                let (path, _, _, _) = get_abs_path(&config, item.base_label.as_str(), item.path.as_str());
-                let client = Client::new();
-                let mut headers = HeaderMap::new();
-                headers.insert(
-                    AUTHORIZATION,
-                    HeaderValue::from_str(&format!("Bearer {}", config.civitai.api_key))
-                        .unwrap_or(HeaderValue::from(0)),
-                );
-                let path = Path::new(&path);
-                broadcaster.info("Start to sync Civitai...").await;
-                if let Err(e) = get_item_info(path, &client, &headers, None, &config).await {
-                    broadcaster
-                        .error(&format!("Failed to get model info {}: {}", &path.display(), e))
-                        .await;
-                } else {
-                    api::save_model_info(&db_pool, &path, item.base_label.as_str(), item.path.as_str()).await;
-                    broadcaster.info(&format!("Synced model {}", &path.display())).await;
-                }
-            }
-            Err(e) => return web::Json(CommonResponse::from_err(e.to_string().as_str())),
+        let item = db::item::get_by_id(&db_pool.sqlite_pool, id).await?;
+        let config = config_data.config.read().await;
+        let (path, _, _, _) = get_abs_path(&config, item.base_label.as_str(), item.path.as_str());
+        let client = Client::new();
+        let headers = auth_headers(&config);
+        let path = Path::new(&path);
+        broadcaster.info("Start to sync Civitai...").await;
+        if let Err(e) = get_item_info(
+            path,
+            &client,
+            &headers,
+            None,
+            &config,
+            config.civitai.download_previews.count(),
+        )
+        .await
+        {
+            broadcaster
+                .error(&format!("Failed to get model info {}: {}", path.display(), e))
+                .await;
+        } else {
+            api::save_model_info(
+                &config,
+                &db_pool,
+                path,
+                item.base_label.as_str(),
+                item.path.as_str(),
+                true,
+            )
+            .await;
+            broadcaster.info(&format!("Synced model {}", path.display())).await;
         }
     } else {
         rt::spawn(async move {
@@ -109,23 +415,418 @@ async fn sync_civitai(
             scan(config_data, db_pool, &broadcaster).await;
         });
     }
-    web::Json(CommonResponse::from_msg(""))
+    Ok(web::Json(CommonResponse::from_msg("")))
+}
+
+#[post("refresh_all")]
+async fn refresh_all(
+    config_data: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    params: Query<RefreshAllQuery>,
+) -> Result<impl Responder, ApiError> {
+    let label = params.into_inner().label.filter(|l| !l.is_empty());
+    let mut config = config_data.config.read().await.clone();
+    if let Some(label) = &label
+        && !config.model_paths.contains_key(label)
+    {
+        return Err(ApiError::NotFound(format!("Unknown label: {label}")));
+    }
+    let items = db::item::list_by_label(&db_pool.sqlite_pool, label.as_deref()).await?;
+    // Refreshing means fetching metadata again even if it's already saved
+    config.civitai.overwrite_json = true;
+
+    let msg = format!("Refreshing metadata of {} items...", items.len());
+    let started_msg = msg.clone();
+    rt::spawn(async move {
+        broadcaster.info(&started_msg).await;
+        let job_id = add_job(
+            &db_pool.sqlite_pool,
+            "Refresh metadata",
+            label.as_deref().unwrap_or_default(),
+        )
+        .await;
+
+        let client = Client::new();
+        let config = Arc::new(config);
+        let semaphore = Arc::new(Semaphore::new(config.parallel));
+        let refreshed = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let not_found = Arc::new(AtomicUsize::new(0));
+
+        let mut handles = Vec::new();
+        for item in items {
+            let config = config.clone();
+            let db_pool = db_pool.clone();
+            let broadcaster = broadcaster.clone();
+            let client = client.clone();
+            let headers = auth_headers(&config);
+            let semaphore = semaphore.clone();
+            let refreshed = refreshed.clone();
+            let failed = failed.clone();
+            let not_found = not_found.clone();
+
+            handles.push(tokio::spawn(async move {
+                let Ok(_permit) = semaphore.acquire().await else {
+                    return;
+                };
+                let (path, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+                let path = Path::new(&path);
+                if !path.exists() {
+                    not_found.fetch_add(1, Ordering::Relaxed);
+                    broadcaster.warn(&format!("File not found: {}", path.display())).await;
+                    return;
+                }
+
+                let blake3 = Some(item.blake3.clone()).filter(|b| !b.is_empty());
+                match get_item_info(
+                    path,
+                    &client,
+                    &headers,
+                    blake3,
+                    &config,
+                    config.civitai.download_previews.count(),
+                )
+                .await
+                {
+                    Ok(_) => {
+                        api::save_model_info(&config, &db_pool, path, &item.base_label, &item.path, true).await;
+                        refreshed.fetch_add(1, Ordering::Relaxed);
+                        broadcaster.info(&format!("Refreshed {}", path.display())).await;
+                    }
+                    Err(e) if CivitaiError::is_not_found(&e) => {
+                        not_found.fetch_add(1, Ordering::Relaxed);
+                        broadcaster
+                            .warn(&format!("Not found on Civitai: {}", path.display()))
+                            .await;
+                    }
+                    Err(e) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        broadcaster
+                            .error(&format!("Failed to refresh {}: {}", path.display(), e))
+                            .await;
+                    }
+                }
+            }));
+        }
+
+        for handle in handles {
+            if let Err(e) = handle.await {
+                error!("Failed to refresh model info: {e}");
+            }
+        }
+
+        let summary = format!(
+            "Finished refreshing metadata. Refreshed: {}, failed: {}, not found: {}",
+            refreshed.load(Ordering::Relaxed),
+            failed.load(Ordering::Relaxed),
+            not_found.load(Ordering::Relaxed)
+        );
+        if let Ok(id) = job_id {
+            let _ = update_job(&db_pool.sqlite_pool, id, &summary, JobState::Succeed).await;
+        }
+        broadcaster.info(&summary).await;
+    });
+
+    Ok(web::Json(CommonResponse::from_msg(&msg)))
+}
+
+/// Index a single model file with its sidecar files, without scanning the whole directory
+#[post("index_file")]
+async fn index_file(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    data: web::Json<IndexFileRequest>,
+) -> Result<impl Responder, ApiError> {
+    let config = config.config.read().await;
+    let requested = Path::new(data.path.trim());
+    let candidates: Vec<PathBuf> = if requested.is_absolute() {
+        vec![requested.to_path_buf()]
+    } else if let Some(label) = data.label.as_ref() {
+        let base_path = config
+            .model_paths
+            .get(label)
+            .ok_or_else(|| ApiError::BadRequest(format!("Label {label} is not in model paths")))?;
+        vec![PathBuf::from(base_path).join(requested)]
+    } else {
+        config
+            .model_paths
+            .values()
+            .map(|base_path| PathBuf::from(base_path).join(requested))
+            .collect()
+    };
+    let path = candidates
+        .into_iter()
+        .find(|path| path.is_file())
+        .ok_or_else(|| ApiError::BadRequest(format!("File not found: {}", data.path)))?;
+    let real_path = api::resolve_in_model_paths(&path, &config)
+        .ok_or_else(|| ApiError::BadRequest(format!("{} is not in model paths", path.display())))?;
+    if !config.is_model_file(&real_path) {
+        return Err(ApiError::BadRequest(format!("{} is not a model file", path.display())));
+    }
+
+    // `real_path` is resolved, so compare it with resolved base paths
+    for (label, base_path) in config.model_paths.iter() {
+        let Ok(real_base_path) = std::fs::canonicalize(base_path) else {
+            continue;
+        };
+        let Ok(relative_path) = real_path.strip_prefix(&real_base_path) else {
+            continue;
+        };
+        let relative_path = relative_path.to_str().unwrap_or_default();
+        let id = api::save_model_info(&config, &db_pool, &real_path, label, relative_path, true)
+            .await
+            .ok_or_else(|| ApiError::Internal(format!("Failed to index {}", real_path.display())))?;
+        broadcaster.info(&format!("Indexed {}", real_path.display())).await;
+        return Ok(web::Json(IndexFileResponse {
+            id,
+            label: label.clone(),
+            path: relative_path.to_string(),
+        }));
+    }
+
+    Err(ApiError::BadRequest(format!(
+        "{} is not in model paths",
+        path.display()
+    )))
+}
+
+/// Report installed models which have a newer version on Civitai in a background job. Nothing is downloaded.
+#[post("check_updates")]
+async fn check_updates(
+    config_data: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+) -> Result<impl Responder, ApiError> {
+    let config = config_data.config.read().await.clone();
+    let items = db::item::list_by_label(&db_pool.sqlite_pool, None).await?;
+    let job_id = add_job(&db_pool.sqlite_pool, "Check model updates", "").await?;
+
+    rt::spawn(async move {
+        // Items of the same model are checked with one request
+        let mut installed: HashMap<i64, Vec<InstalledVersion>> = HashMap::new();
+        for item in items {
+            let (_, json, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+            let info: Value = fs::read_to_string(&json)
+                .await
+                .ok()
+                .and_then(|info| serde_json::from_str(&info).ok())
+                .unwrap_or_default();
+            if let (Some(model_id), Some(version_id)) = (info["modelId"].as_i64(), info["id"].as_i64()) {
+                let version = info["name"].as_str().unwrap_or_default().to_string();
+                installed.entry(model_id).or_default().push(InstalledVersion {
+                    id: item.id,
+                    path: item.path,
+                    version_id,
+                    version,
+                });
+            }
+        }
+
+        let total = installed.len();
+        broadcaster
+            .info(&format!("Checking updates of {total} models..."))
+            .await;
+
+        let client = Client::new();
+        let max_retries = config.civitai.max_retries;
+        let checked = AtomicUsize::new(0);
+        let results = stream::iter(installed)
+            .map(|(model_id, items)| {
+                let (client, checked, broadcaster) = (&client, &checked, &broadcaster);
+                let headers = auth_headers(&config);
+                async move {
+                    let model = get_model(client, &headers, model_id, max_retries).await;
+                    let done = checked.fetch_add(1, Ordering::Relaxed) + 1;
+                    if done % UPDATE_CHECK_PROGRESS_STEP == 0 {
+                        broadcaster
+                            .info(&format!("Checked updates of {done}/{total} models"))
+                            .await;
+                    }
+                    (model_id, items, model)
+                }
+            })
+            .buffer_unordered(config.parallel.max(1))
+            .collect::<Vec<_>>()
+            .await;
+
+        let (mut checked, mut failed) = (0, 0);
+        let mut updates = Vec::new();
+        for (model_id, items, model) in results {
+            let model = match model {
+                Ok(model) => model,
+                Err(e) => {
+                    failed += 1;
+                    broadcaster
+                        .warn(&format!("Failed to check updates of model {model_id}: {e}"))
+                        .await;
+                    continue;
+                }
+            };
+            checked += 1;
+            let latest = &model["modelVersions"][0];
+            let Some(latest_version_id) = latest["id"].as_i64() else {
+                continue;
+            };
+            let latest_version = latest["name"].as_str().unwrap_or_default();
+            for item in items {
+                if item.version_id != latest_version_id {
+                    updates.push(format!(
+                        "{} (item {}): {} ({}) -> {} ({}), {}",
+                        item.path,
+                        item.id,
+                        item.version,
+                        item.version_id,
+                        latest_version,
+                        latest_version_id,
+                        model_page_url(model_id, latest_version_id)
+                    ));
+                }
+            }
+        }
+
+        let summary = format!(
+            "Checked {checked} models. Updates available: {}, failed: {failed}",
+            updates.len()
+        );
+        broadcaster.info(&summary).await;
+        let desc = std::iter::once(summary).chain(updates).collect::<Vec<_>>().join("\n");
+        let _ = update_job(&db_pool.sqlite_pool, job_id, &desc, JobState::Succeed).await;
+    });
+
+    Ok(web::Json(CheckUpdatesResponse { job_id }))
+}
+
+/// Normalize and merge existing tags which were stored with mixed case or whitespace
+#[post("normalize_tags")]
+async fn normalize_tags(db_pool: Data<DBPool>) -> Result<impl Responder, ApiError> {
+    let id = add_job(&db_pool.sqlite_pool, "Normalize tags", "").await;
+    let changed = match db::tag::normalize_all(&db_pool).await {
+        Ok(changed) => changed,
+        Err(e) => {
+            if let Ok(id) = id {
+                let _ = update_job(&db_pool.sqlite_pool, id, &format!("{e}"), JobState::Failed).await;
+            }
+            return Err(e.into());
+        }
+    };
+    let msg = format!("Normalized {changed} tags");
+    if let Ok(id) = id {
+        let _ = update_job(&db_pool.sqlite_pool, id, &msg, JobState::Succeed).await;
+    }
+    Ok(web::Json(CommonResponse::from_msg(&msg)))
+}
+
+#[get("audit")]
+async fn audit_log(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    params: Query<AuditQuery>,
+) -> Result<impl Responder, ApiError> {
+    let config = config.config.read().await;
+    let limit = params.limit.unwrap_or(config.api.per_page as i64);
+    let offset = params.offset.unwrap_or(0);
+    let (entries, total) = db::audit::get(&db_pool.sqlite_pool, params.item_id, limit, offset).await?;
+    Ok(web::Json(AuditResponse { entries, total }))
 }
 
 #[get("empty_trash")]
-async fn empty_trash(config: Data<ConfigData>, broadcaster: Data<Broadcaster>) -> impl Responder {
+async fn empty_trash(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+) -> impl Responder {
     broadcaster.warn("Emptying trash...").await;
+    let id = add_job(&db_pool.sqlite_pool, "Empty trash", "").await;
     let config = config.config.read().await;
-    for (_, base_path) in config.model_paths.iter() {
-        let trash_dir = PathBuf::from(base_path).join(TRASH_DIR);
-        if let Err(e) = fs::remove_dir_all(&trash_dir).await {
+    let mut failed = Vec::new();
+    for label in config.model_paths.keys() {
+        let Some(trash_dir) = config.trash_path(label) else {
+            continue;
+        };
+        if let Err(e) = fs::remove_dir_all(&trash_dir).await
+            && trash_dir.exists()
+        {
             error!("Failed to remove trash directory: {}", e);
+            failed.push(format!("{}: {}", trash_dir.display(), e));
         }
     }
+    if let Ok(id) = id {
+        let (desc, state) = if failed.is_empty() {
+            ("Emptied trash".to_string(), JobState::Succeed)
+        } else {
+            (failed.join("\n"), JobState::Failed)
+        };
+        let _ = update_job(&db_pool.sqlite_pool, id, &desc, state).await;
+    }
     broadcaster.info("Finish emptying trash...").await;
     web::Json(CommonResponse::default())
 }
 
+/// Purge trash of items deleted more than `trash_retention_days` ago
+#[get("purge_trash")]
+async fn purge_trash(config: Data<ConfigData>, db_pool: Data<DBPool>) -> Result<impl Responder, ApiError> {
+    let config = config.config.read().await;
+    if config.trash_retention_days == 0 {
+        return Err(ApiError::BadRequest("Trash retention is disabled".to_string()));
+    }
+    let id = add_job(&db_pool.sqlite_pool, "Purge trash", "").await;
+    let purged = match api::purge_expired_trash(&config, &db_pool).await {
+        Ok(purged) => purged,
+        Err(e) => {
+            if let Ok(id) = id {
+                let _ = update_job(&db_pool.sqlite_pool, id, &format!("{e}"), JobState::Failed).await;
+            }
+            return Err(e.into());
+        }
+    };
+    let msg = format!("Purged {purged} items from trash");
+    if let Ok(id) = id {
+        let _ = update_job(&db_pool.sqlite_pool, id, &msg, JobState::Succeed).await;
+    }
+    Ok(web::Json(CommonResponse::from_msg(&msg)))
+}
+
+/// List items whose base label was removed from `model_paths`
+#[get("unconfigured_items")]
+async fn unconfigured_items(config: Data<ConfigData>, db_pool: Data<DBPool>) -> Result<impl Responder, ApiError> {
+    let config = config.config.read().await;
+    let labels: Vec<&String> = config.model_paths.keys().collect();
+    let items = db::item::list_by_other_labels(&db_pool.sqlite_pool, &labels).await?;
+    let items: Vec<UnconfiguredItem> = items
+        .into_iter()
+        .map(|item| UnconfiguredItem {
+            id: item.id,
+            base_label: item.base_label,
+            path: item.path,
+        })
+        .collect();
+    Ok(web::Json(items))
+}
+
+/// List items whose model file is gone from disk, e.g. moved or deleted outside the app since the last scan.
+/// Items of labels which are not in `model_paths` are listed by `unconfigured_items` instead.
+#[get("missing_items")]
+async fn missing_items(config: Data<ConfigData>, db_pool: Data<DBPool>) -> Result<impl Responder, ApiError> {
+    let config = config.config.read().await;
+    let items = db::item::list_by_label(&db_pool.sqlite_pool, None).await?;
+    let items: Vec<MissingItem> = items
+        .into_iter()
+        .filter(|item| config.model_paths.contains_key(&item.base_label))
+        .filter(|item| {
+            let (path, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+            !Path::new(&path).is_file()
+        })
+        .map(|item| MissingItem {
+            id: item.id,
+            base_label: item.base_label,
+            path: item.path,
+        })
+        .collect();
+    Ok(web::Json(items))
+}
+
 #[get("restart")]
 async fn restart(stop_handle: Data<RwLock<StopHandle>>, broadcaster: Data<Broadcaster>) -> impl Responder {
     broadcaster.warn("Restarting server. Please wait a minute...").await;
@@ -144,47 +845,144 @@ async fn force_restart(stop_handle: Data<RwLock<StopHandle>>, broadcaster: Data<
     HttpResponse::NoContent().finish()
 }
 
+/// Walk `base_path` for scanning. When following symlinks, a directory is only read once by its real path so that
+/// symlink cycles end.
+fn walk_dir(base_path: &str, config: &Config) -> WalkDir {
+    let config = config.clone();
+    let visited_dirs = Mutex::new(HashSet::new());
+    WalkDir::new(base_path)
+        .skip_hidden(!config.scan_hidden_dirs)
+        .parallelism(Parallelism::RayonNewPool(config.parallel))
+        .follow_links(config.follow_symlinks)
+        .process_read_dir(move |depth, _, _, children| {
+            for child in children.iter_mut().flatten() {
+                if !child.file_type().is_dir() {
+                    continue;
+                }
+                // `base_path` itself is passed without depth, it's always read
+                let is_ignored = depth.is_some() && config.is_ignored_name(&child.file_name.to_string_lossy());
+                let is_new = !config.follow_symlinks
+                    || std::fs::canonicalize(child.path()).is_ok_and(|p| visited_dirs.lock().insert(p));
+                if is_ignored || !is_new {
+                    child.read_children_path = None;
+                }
+            }
+        })
+}
+
+/// Scan model paths every `scan_interval_minutes`. Disabled if it's 0.
+pub(crate) fn spawn_scheduled_scan(
+    config: &Config,
+    config_data: Arc<ConfigData>,
+    db_pool: Arc<DBPool>,
+    broadcaster: Arc<Broadcaster>,
+) -> Option<JoinHandle<()>> {
+    if config.scan_interval_minutes == 0 {
+        return None;
+    }
+
+    let period = Duration::from_secs(config.scan_interval_minutes * 60);
+    Some(tokio::spawn(async move {
+        let mut interval = interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            info!("Start scheduled scan");
+            let config_data = Data::from(config_data.clone());
+            let db_pool = Data::from(db_pool.clone());
+            scan(config_data, db_pool, &broadcaster).await;
+        }
+    }))
+}
+
+/// Held while a scan is running. The flag is cleared on drop, so it's cleared even if the scan task is aborted.
+struct ScanGuard;
+
+impl ScanGuard {
+    /// `None` if another scan is in progress
+    fn acquire() -> Option<Self> {
+        (!SCAN_RUNNING.swap(true, Ordering::AcqRel)).then_some(Self)
+    }
+}
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        SCAN_RUNNING.store(false, Ordering::Release);
+    }
+}
+
+/// Scan model paths unless another scan is in progress
 async fn scan(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: &Broadcaster) {
+    let Some(_guard) = ScanGuard::acquire() else {
+        broadcaster.warn("Another scan is in progress. Skipped.").await;
+        return;
+    };
+    scan_model_paths(config, db_pool, broadcaster).await;
+}
+
+async fn scan_model_paths(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: &Broadcaster) {
     let id = add_job(&db_pool.sqlite_pool, "Scan folder", "").await;
 
     let config = config.config.read().await;
-    let valid_ext = config.extensions.iter().collect::<HashSet<_>>();
 
-    if let Err(e) = db::item::mark_obsolete_all(&db_pool.sqlite_pool).await {
+    if let Err(e) = db::item::mark_obsolete_all(&db_pool).await {
         let msg = format!("Failed to mark all item for reload: {e}");
         if let Ok(id) = id {
             let _ = update_job(&db_pool.sqlite_pool, id, msg.as_str(), JobState::Failed).await;
         }
-        broadcaster.error(format!("Scan failed. {}", &msg).as_str()).await;
+        broadcaster.error(format!("Scan failed. {}", msg).as_str()).await;
         return;
     }
     let mut handles = Vec::new();
     let semaphore = Arc::new(Semaphore::new(config.parallel));
+    // Scan tasks outlive the config lock guard
+    let task_config = Arc::new(config.clone());
+    let real_model_paths: Vec<PathBuf> = config
+        .model_paths
+        .values()
+        .filter_map(|p| std::fs::canonicalize(p).ok())
+        .collect();
+    let mut visited_files = HashSet::new();
     for (label, base_path) in config.model_paths.iter() {
-        let parallelism = Parallelism::RayonNewPool(config.parallel);
-        for entry in WalkDir::new(base_path)
-            .skip_hidden(true)
-            .parallelism(parallelism.clone())
-            .follow_links(false)
-            .into_iter()
-            .flatten()
-        {
+        for entry in walk_dir(base_path, &config).into_iter().flatten() {
             if entry.file_type().is_file() {
                 let path = entry.path();
                 let Ok(relative_path) = api::get_relative_path(base_path, &path) else {
                     continue;
                 };
 
-                let file_ext = path.extension().unwrap_or_default().to_str().unwrap_or_default();
-                if valid_ext.contains(&file_ext.to_string()) {
+                if config.follow_symlinks {
+                    let Ok(real_path) = std::fs::canonicalize(&path) else {
+                        continue;
+                    };
+                    if !real_model_paths.iter().any(|p| real_path.starts_with(p)) {
+                        info!("Skip {path:?} which links outside model paths");
+                        continue;
+                    }
+                    // Same file is reachable through several links
+                    if !visited_files.insert(real_path) {
+                        continue;
+                    }
+                }
+
+                if config.is_model_file(&path) {
                     let semaphore = semaphore.clone();
                     let db_pool = db_pool.clone();
                     let label = label.clone();
+                    let task_config = task_config.clone();
 
                     let handle = tokio::spawn(async move {
                         if let Ok(_permit) = semaphore.acquire().await {
                             info!("Found {path:?}");
-                            api::save_model_info(&db_pool, &path, label.as_str(), relative_path.as_str()).await;
+                            api::save_model_info(
+                                &task_config,
+                                &db_pool,
+                                &path,
+                                label.as_str(),
+                                relative_path.as_str(),
+                                false,
+                            )
+                            .await;
                         }
                     });
                     handles.push(handle);
@@ -194,14 +992,145 @@ async fn scan(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: &Bro
         info!("Finished scanning {}", label);
     }
 
+    let found = handles.len();
     for handle in handles {
         if let Err(e) = handle.await {
             error!("Failed to scan model: {e}");
         }
     }
+    // Unchanged items are checked again without invalidating counts
+    db_pool.count_cache.invalidate();
 
+    let summary = format!("Finished scanning. Found {found} models");
     if let Ok(id) = id {
-        let _ = update_job(&db_pool.sqlite_pool, id, "", JobState::Succeed).await;
+        let _ = update_job(&db_pool.sqlite_pool, id, &summary, JobState::Succeed).await;
+    }
+    broadcaster.info(&summary).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn orphans_are_grouped_by_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("models");
+        std::fs::create_dir_all(model_path.join("loras")).unwrap();
+        let civitai_info = r#"{"modelId": 1, "id": 2}"#;
+        for (file, content) in [
+            ("kept.safetensors", ""),
+            ("kept.json", civitai_info),
+            ("kept.png", ""),
+            ("foo.v2.safetensors", ""),
+            ("foo.v2.png", ""),
+            ("loras/gone.json", civitai_info),
+            ("loras/gone.model.json", r#"{"modelVersions": []}"#),
+            ("loras/gone.png", ""),
+            ("loras/gone.1.png", ""),
+            ("loras/gone.txt", "trigger"),
+            // The user's own files which are not named after any model
+            ("cover.png", ""),
+            ("notes.json", "{}"),
+        ] {
+            std::fs::write(model_path.join(file), content).unwrap();
+        }
+        let config = Config::with_model_path(&model_path);
+        let db_pool = DBPool::memory().await;
+
+        let res = find_orphans(&config, &db_pool, true).await.unwrap();
+        let gone = ["gone.1.png", "gone.json", "gone.model.json", "gone.png", "gone.txt"];
+        let expected = gone
+            .iter()
+            .map(|file| model_path.join("loras").join(file).display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(res.orphaned_files, expected);
+        assert_eq!(res.unindexed_models.len(), 2);
+        assert!(model_path.join("loras/gone.json").exists());
+
+        let res = find_orphans(&config, &db_pool, false).await.unwrap();
+        assert!(res.failed.is_empty());
+        let trash_dir = config.trash_path("test").unwrap();
+        for file in gone {
+            assert!(trash_dir.join(file).is_file(), "{file} is moved to trash");
+            assert!(!model_path.join("loras").join(file).exists());
+        }
+        for file in ["kept.json", "kept.png", "foo.v2.png", "cover.png", "notes.json"] {
+            assert!(model_path.join(file).is_file(), "{file} is kept");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn aborted_scan_releases_the_flag() {
+        let task = tokio::spawn(async {
+            let _guard = ScanGuard::acquire().unwrap();
+            std::future::pending::<()>().await;
+        });
+        while !SCAN_RUNNING.load(Ordering::Acquire) {
+            tokio::task::yield_now().await;
+        }
+        assert!(ScanGuard::acquire().is_none());
+
+        task.abort();
+        let _ = task.await;
+        assert!(ScanGuard::acquire().is_some());
+    }
+
+    #[test]
+    fn walk_dir_ends_on_symlink_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("models");
+        std::fs::create_dir_all(model_path.join("loras")).unwrap();
+        std::fs::write(model_path.join("loras/model.safetensors"), "").unwrap();
+        // Links back to the model path and to itself
+        std::os::unix::fs::symlink(&model_path, model_path.join("loras/back")).unwrap();
+        std::os::unix::fs::symlink(model_path.join("loras"), model_path.join("loras/self")).unwrap();
+        let config = Config {
+            follow_symlinks: true,
+            ..Config::with_model_path(&model_path)
+        };
+
+        let models = walk_dir(&model_path.to_string_lossy(), &config)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        assert_eq!(models, vec![model_path.join("loras/model.safetensors")]);
+    }
+
+    #[test]
+    fn walk_dir_skips_hidden_and_system_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("models");
+        let dirs = ["loras", ".hidden", "$RECYCLE.BIN", "@eadir", "lost+found"];
+        for name in dirs {
+            std::fs::create_dir_all(model_path.join(name)).unwrap();
+            std::fs::write(model_path.join(name).join("model.safetensors"), "").unwrap();
+        }
+        let walk = |config: &Config| {
+            let mut models = walk_dir(&model_path.to_string_lossy(), config)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_file())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>();
+            models.sort();
+            models
+        };
+
+        let config = Config::with_model_path(&model_path);
+        assert_eq!(walk(&config), vec![model_path.join("loras/model.safetensors")]);
+
+        let config = Config {
+            scan_hidden_dirs: true,
+            ..config
+        };
+        let mut all = dirs
+            .map(|name| model_path.join(name).join("model.safetensors"))
+            .to_vec();
+        all.sort();
+        assert_eq!(walk(&config), all);
     }
-    broadcaster.info("Finished scanning").await;
 }
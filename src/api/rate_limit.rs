@@ -0,0 +1,144 @@
+//! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
+//!
+//! Token bucket rate limiter per client IP.
+
+use crate::api::error::ErrorBody;
+use crate::ConfigData;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpResponse};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+/// Endpoints which change items, write or download files or run heavy jobs even with GET method. `image_proxy` is a
+/// read because a gallery loads many thumbnails through it, most of them from its cache.
+const WRITE_ENDPOINTS: [&str; 14] = [
+    "civitai_download",
+    "delete",
+    "scan",
+    "sync_civitai",
+    "remove_orphan",
+    "empty_trash",
+    "purge_trash",
+    "restart",
+    "force_restart",
+    "clear",
+    "rehash",
+    "civitai_search",
+    "update_version",
+    "export_sidecar",
+];
+
+/// Buckets are dropped after being idle this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+const MAX_BUCKETS: usize = 10_000;
+
+/// Bucket by (client IP, is write endpoint)
+static BUCKETS: LazyLock<Mutex<HashMap<(IpAddr, bool), Bucket>>> = LazyLock::new(Default::default);
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Middleware for `/api` scope. Event stream is not under this scope so it's never throttled.
+pub async fn limit<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let limit = match req.app_data::<Data<ConfigData>>() {
+        Some(config) => {
+            let config = config.config.read().await;
+            let rate_limit = &config.api.rate_limit;
+            let is_write = is_write_request(&req);
+            let per_minute = if is_write { rate_limit.write_per_minute } else { rate_limit.read_per_minute };
+            rate_limit.enabled.then_some((is_write, per_minute))
+        }
+        None => None,
+    };
+
+    if let (Some((is_write, per_minute)), Some(peer)) = (limit, req.peer_addr())
+        && let Err(retry_after) = acquire(peer.ip(), is_write, per_minute)
+    {
+        let res = HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.as_secs().to_string()))
+            .json(ErrorBody::new("too_many_requests", "Too many requests"));
+        return Ok(req.into_response(res).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+fn is_write_request(req: &ServiceRequest) -> bool {
+    if req.method() != Method::GET {
+        return true;
+    }
+    let endpoint = req.path().trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    WRITE_ENDPOINTS.contains(&endpoint)
+}
+
+/// Take a token from the bucket. Return the time to wait if the bucket is empty.
+fn acquire(ip: IpAddr, is_write: bool, per_minute: u32) -> Result<(), Duration> {
+    let capacity = per_minute.max(1) as f64;
+    let refill_per_sec = capacity / 60.0;
+    let now = Instant::now();
+
+    let mut buckets = BUCKETS.lock();
+    if buckets.len() > MAX_BUCKETS {
+        buckets.retain(|_, bucket| now.duration_since(bucket.updated_at) < IDLE_TIMEOUT);
+    }
+
+    let bucket = buckets.entry((ip, is_write)).or_insert(Bucket {
+        tokens: capacity,
+        updated_at: now,
+    });
+    let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+    bucket.updated_at = now;
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        let wait = (1.0 - bucket.tokens) / refill_per_sec;
+        Err(Duration::from_secs(wait.ceil() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn get_endpoints_which_call_civitai_or_write_files_are_writes() {
+        for endpoint in [
+            "rehash",
+            "civitai_search",
+            "civitai_download",
+            "update_version",
+            "export_sidecar",
+        ] {
+            let req = TestRequest::get()
+                .uri(&format!("/api/{endpoint}?id=1"))
+                .to_srv_request();
+            assert!(is_write_request(&req), "{endpoint}");
+        }
+        assert!(!is_write_request(&TestRequest::get().uri("/api/item").to_srv_request()));
+        assert!(is_write_request(&TestRequest::post().uri("/api/item").to_srv_request()));
+    }
+
+    #[test]
+    fn proxied_gallery_images_are_reads() {
+        let req = TestRequest::get()
+            .uri("/api/item/image_proxy?url=https://image.civitai.com/a.jpeg")
+            .to_srv_request();
+        assert!(!is_write_request(&req));
+    }
+}
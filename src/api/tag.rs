@@ -1,13 +1,14 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
+use crate::api::error::ApiError;
 use crate::api::{CommonResponse, DeleteRequest};
 use crate::db;
-use crate::db::tag::Tag;
+use crate::db::tag::{normalize_tag, resolve_aliases, Tag};
 use crate::db::DBPool;
 use actix_web::web::Data;
 use actix_web::{get, post, web, Responder};
 use actix_web_lab::extract::Query;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use tracing::error;
 
@@ -15,16 +16,58 @@ pub fn scope(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/tag")
             .service(get_all)
+            .service(cloud)
+            .service(cooccur)
             .service(get)
             .service(update)
+            .service(set_category)
+            .service(set_color)
+            .service(list_aliases)
+            .service(add_alias)
             .service(delete),
     );
 }
 
-#[derive(Serialize, Default)]
+const DEFAULT_CLOUD_LIMIT: i64 = 100;
+const DEFAULT_COOCCUR_LIMIT: i64 = 20;
+
+#[derive(Serialize)]
 struct TagResponse {
-    tag: Option<Tag>,
-    err: Option<String>,
+    tag: Tag,
+}
+
+#[derive(Deserialize)]
+struct TagCategoryUpdate {
+    tag: String,
+    /// Empty to remove tag from its category
+    category: String,
+}
+
+#[derive(Deserialize)]
+struct TagColorUpdate {
+    tag: String,
+    /// Hex color like `#ff8800`. Empty to remove the color.
+    color: String,
+}
+
+#[derive(Deserialize)]
+struct TagCloudQuery {
+    /// Only count tags of items whose name contains this
+    #[serde(default)]
+    search: String,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TagCooccurQuery {
+    tag: String,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TagAliasUpdate {
+    alias: String,
+    tag: String,
 }
 
 #[get("")]
@@ -38,44 +81,101 @@ async fn get_all(db_pool: Data<DBPool>) -> impl Responder {
     web::Json(get_all)
 }
 
+/// Tag counts of all items, independent of the current page
+#[get("cloud")]
+async fn cloud(db_pool: Data<DBPool>, query: Query<TagCloudQuery>) -> Result<impl Responder, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_CLOUD_LIMIT).max(1);
+    let tags = db::tag::tag_cloud(&db_pool.sqlite_pool, query.search.trim(), limit).await?;
+    Ok(web::Json(tags))
+}
+
+/// Tags which are most often applied together with the given tag, for tag suggestions
+#[get("cooccur")]
+async fn cooccur(db_pool: Data<DBPool>, query: Query<TagCooccurQuery>) -> Result<impl Responder, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_COOCCUR_LIMIT).max(1);
+    let tag = resolve_aliases(&db_pool.sqlite_pool, vec![query.tag.clone()])
+        .await?
+        .pop()
+        .unwrap_or_default();
+    let tags = db::tag::cooccur(&db_pool.sqlite_pool, &tag, limit).await?;
+    Ok(web::Json(tags))
+}
+
 #[get("detail/{tag}")]
-async fn get(db_pool: Data<DBPool>, tag: web::Path<String>) -> impl Responder {
-    let res = match db::tag::get_tag_by_name(&db_pool.sqlite_pool, tag.into_inner().as_str()).await {
-        Ok(tag) => TagResponse {
-            tag: Some(tag),
-            ..Default::default()
-        },
-        Err(e) => TagResponse {
-            err: Some(format!("{e}")),
-            ..Default::default()
-        },
-    };
-    web::Json(res)
+async fn get(db_pool: Data<DBPool>, tag: web::Path<String>) -> Result<impl Responder, ApiError> {
+    let tag = db::tag::get_tag_by_name(&db_pool.sqlite_pool, &normalize_tag(&tag)).await?;
+    Ok(web::Json(TagResponse { tag }))
 }
 
 #[post("update")]
-async fn update(db_pool: Data<DBPool>, data: web::Json<Tag>) -> impl Responder {
-    if let Err(e) = db::tag::update_tag(&db_pool.sqlite_pool, &data.into_inner()).await {
-        return web::Json(CommonResponse {
-            err: Some(format!("Failed to update tag: {e}")),
-            ..Default::default()
-        });
-    }
-    web::Json(CommonResponse::default())
+async fn update(db_pool: Data<DBPool>, data: web::Json<Tag>) -> Result<impl Responder, ApiError> {
+    db::tag::update_tag(&db_pool, &data.into_inner())
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to update tag: {e}")))?;
+    Ok(web::Json(CommonResponse::default()))
+}
+
+#[post("category")]
+async fn set_category(db_pool: Data<DBPool>, data: web::Json<TagCategoryUpdate>) -> Result<impl Responder, ApiError> {
+    let tag = normalize_tag(&data.tag);
+    db::tag::set_category(&db_pool.sqlite_pool, &tag, data.category.trim()).await?;
+    Ok(web::Json(CommonResponse::default()))
+}
+
+#[post("color")]
+async fn set_color(db_pool: Data<DBPool>, data: web::Json<TagColorUpdate>) -> Result<impl Responder, ApiError> {
+    let tag = normalize_tag(&data.tag);
+    let color = data.color.trim().to_lowercase();
+    let color = if color.is_empty() {
+        None
+    } else if is_hex_color(&color) {
+        Some(color.as_str())
+    } else {
+        return Err(ApiError::BadRequest(format!("Invalid hex color: {}", data.color)));
+    };
+
+    db::tag::set_color(&db_pool.sqlite_pool, &tag, color).await?;
+    Ok(web::Json(CommonResponse::default()))
+}
+
+#[get("alias")]
+async fn list_aliases(db_pool: Data<DBPool>) -> impl Responder {
+    let aliases = db::tag::list_aliases(&db_pool.sqlite_pool).await.unwrap_or_else(|e| {
+        error!("Failed to list tag aliases: {e}");
+        Vec::new()
+    });
+    web::Json(aliases)
+}
+
+#[post("alias")]
+async fn add_alias(db_pool: Data<DBPool>, data: web::Json<TagAliasUpdate>) -> Result<impl Responder, ApiError> {
+    let alias = normalize_tag(&data.alias);
+    let tag = normalize_tag(&data.tag);
+    db::tag::add_alias(&db_pool, &alias, &tag)
+        .await
+        .map_err(|e| ApiError::Conflict(format!("Failed to add alias {alias}: {e}")))?;
+    Ok(web::Json(CommonResponse::default()))
 }
 
 #[get("delete")]
-async fn delete(db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> impl Responder {
+async fn delete(db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> Result<impl Responder, ApiError> {
     let mut err_str = String::new();
     for id in params.ids.iter() {
-        if let Err(e) = db::tag::delete(&db_pool.sqlite_pool, *id).await {
+        if let Err(e) = db::tag::delete(&db_pool, *id).await {
             err_str.push_str(&format!("{e}\n"));
         }
     }
 
-    let err = if err_str.is_empty() { None } else { Some(err_str) };
-    web::Json(CommonResponse {
-        err,
-        ..Default::default()
-    })
+    if !err_str.is_empty() {
+        return Err(ApiError::Internal(err_str));
+    }
+    Ok(web::Json(CommonResponse::default()))
+}
+
+/// Check if color is in `#rgb` or `#rrggbb` format
+fn is_hex_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
 }
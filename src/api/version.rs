@@ -0,0 +1,29 @@
+//! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
+
+use actix_web::{get, web, Responder};
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_HASH: &str = env!("SDMM_GIT_HASH");
+const BUILD_TIMESTAMP: &str = env!("SDMM_BUILD_TIMESTAMP");
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(version);
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    git_hash: &'static str,
+    /// Unix timestamp in seconds
+    build_timestamp: u64,
+}
+
+#[get("version")]
+async fn version() -> impl Responder {
+    web::Json(VersionResponse {
+        version: VERSION,
+        git_hash: GIT_HASH,
+        build_timestamp: BUILD_TIMESTAMP.parse().unwrap_or_default(),
+    })
+}
@@ -1,25 +1,77 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
-use crate::api::TRASH_DIR;
+use crate::api::long_path;
 use crate::config::Config;
 use actix_web_lab::__reexports::futures_util::StreamExt;
 use jwalk::{Parallelism, WalkDir};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use reqwest::Client;
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, RETRY_AFTER};
+use reqwest::{Client, StatusCode};
 use serde::Deserialize;
 use serde_json::{to_string_pretty, Value};
-use std::collections::{HashMap, HashSet};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs;
+use tokio::io::AsyncReadExt;
 use tokio::sync::Semaphore;
 use tracing::{error, info};
 
-pub const PREVIEW_EXT: &str = "jpeg";
+/// Model types on Civitai
+const MODEL_TYPES: [&str; 11] = [
+    "Checkpoint",
+    "LORA",
+    "LoCon",
+    "DoRA",
+    "TextualInversion",
+    "Hypernetwork",
+    "Controlnet",
+    "VAE",
+    "Upscaler",
+    "MotionModule",
+    "Poses",
+];
+
+/// Seconds to wait when Civitai rate limits a request without `Retry-After`
+const RATE_LIMIT_DELAY_SECS: u64 = 10;
+/// Seconds to skip an API key after Civitai rejects it
+const UNHEALTHY_KEY_SECS: u64 = 600;
+/// Bytes which are read to detect type of a file
+const FILE_TYPE_HEAD_LEN: u64 = 8192;
+
+/// Counter to use API keys in turn
+static NEXT_API_KEY: AtomicUsize = AtomicUsize::new(0);
+/// API keys which were rejected by Civitai, and when
+static UNHEALTHY_KEYS: LazyLock<Mutex<HashMap<String, Instant>>> = LazyLock::new(Default::default);
+
+/// Error which Civitai API responded with
+#[derive(Debug)]
+pub struct CivitaiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for CivitaiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for CivitaiError {}
+
+impl CivitaiError {
+    /// Model or version is not on Civitai, so retrying doesn't help
+    pub fn is_not_found(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<CivitaiError>()
+            .is_some_and(|e| e.status == StatusCode::NOT_FOUND)
+    }
+}
 
 #[derive(PartialEq)]
 pub enum FileType {
@@ -35,19 +87,56 @@ pub struct CivitaiFileMetadata {
     pub size: Option<String>,
 }
 
+/// Headers to authorize a request to Civitai. API keys are used in turn, skipping keys which were rejected recently
+/// unless all of them were.
+pub fn auth_headers(config: &Config) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let keys = config.civitai.all_api_keys();
+    if keys.is_empty() {
+        return headers;
+    }
+
+    let start = NEXT_API_KEY.fetch_add(1, Ordering::Relaxed);
+    let mut unhealthy = UNHEALTHY_KEYS.lock();
+    unhealthy.retain(|_, rejected_at| rejected_at.elapsed() < Duration::from_secs(UNHEALTHY_KEY_SECS));
+    let key = pick_api_key(&keys, start, &unhealthy);
+    if let Ok(bearer) = HeaderValue::from_str(&format!("Bearer {key}")) {
+        headers.insert(AUTHORIZATION, bearer);
+    }
+    headers
+}
+
+/// First key from the `start`-th one in turn which is not in `unhealthy`, or the `start`-th one if all of them are.
+/// `keys` must not be empty.
+fn pick_api_key<'a>(keys: &[&'a str], start: usize, unhealthy: &HashMap<String, Instant>) -> &'a str {
+    (0..keys.len())
+        .map(|i| keys[(start + i) % keys.len()])
+        .find(|key| !unhealthy.contains_key(*key))
+        .unwrap_or(keys[start % keys.len()])
+}
+
+/// Skip the API key of `headers` for a while if Civitai rejected it
+fn check_unauthorized(status: StatusCode, headers: &HeaderMap) {
+    if status != StatusCode::UNAUTHORIZED {
+        return;
+    }
+    if let Some(key) = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        error!("Civitai rejected an API key. Skip it for {}s", UNHEALTHY_KEY_SECS);
+        UNHEALTHY_KEYS.lock().insert(key.to_string(), Instant::now());
+    }
+}
+
 pub async fn update_model_info(config: &Config) -> anyhow::Result<()> {
-    let valid_ext = config.extensions.iter().collect::<HashSet<_>>();
     let client = Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", config.civitai.api_key))?,
-    );
 
     let mut handles = Vec::new();
     let semaphore = Arc::new(Semaphore::new(config.parallel));
     let parallelism = Parallelism::RayonNewPool(config.parallel);
-    for (_, base_path) in config.model_paths.iter() {
+    for base_path in config.model_paths.values() {
         for entry in WalkDir::new(base_path)
             .skip_hidden(true)
             .parallelism(parallelism.clone())
@@ -56,24 +145,29 @@ pub async fn update_model_info(config: &Config) -> anyhow::Result<()> {
             .flatten()
         {
             let path = entry.path();
-            if entry.file_type().is_file() || entry.file_type().is_symlink() {
-                let file_ext = path.extension().unwrap_or_default().to_str().unwrap_or_default();
-                if valid_ext.contains(&file_ext.to_string()) {
-                    let client = client.clone();
-                    let headers = headers.clone();
-                    let config = config.clone();
-                    let semaphore = semaphore.clone();
-
-                    let handle = tokio::spawn(async move {
-                        info!("Update model info: {}", entry.path().display());
-                        if let Ok(_permit) = semaphore.acquire().await {
-                            if let Err(e) = get_item_info(&path, &client, &headers, None, &config).await {
-                                error!("Failed to get model info {}: {}", &path.display(), e);
-                            }
-                        }
-                    });
-                    handles.push(handle);
-                }
+            if (entry.file_type().is_file() || entry.file_type().is_symlink()) && config.is_model_file(&path) {
+                let client = client.clone();
+                let headers = auth_headers(config);
+                let config = config.clone();
+                let semaphore = semaphore.clone();
+
+                let handle = tokio::spawn(async move {
+                    info!("Update model info: {}", entry.path().display());
+                    if let Ok(_permit) = semaphore.acquire().await
+                        && let Err(e) = get_item_info(
+                            &path,
+                            &client,
+                            &headers,
+                            None,
+                            &config,
+                            config.civitai.download_previews.count(),
+                        )
+                        .await
+                    {
+                        error!("Failed to get model info {}: {}", &path.display(), e);
+                    }
+                });
+                handles.push(handle);
             }
         }
     }
@@ -95,6 +189,7 @@ pub async fn get_item_info(
     headers: &HeaderMap,
     blake3: Option<String>,
     config: &Config,
+    preview_count: usize,
 ) -> anyhow::Result<()> {
     let info: Value;
     let mut json_path = PathBuf::from(path);
@@ -102,13 +197,7 @@ pub async fn get_item_info(
 
     if !json_path.exists() || config.civitai.overwrite_json {
         let hash = blake3.unwrap_or(calculate_blake3(path)?);
-        let url = format!("https://civitai.com/api/v1/model-versions/by-hash/{hash}");
-        info = client.get(url).headers(headers.clone()).send().await?.json().await?;
-        if let Some(err) = info["error"].as_str() {
-            if !err.is_empty() {
-                return Err(anyhow::anyhow!(err.to_string()));
-            }
-        }
+        info = get_version_info(client, headers, &hash).await?;
         save_info(&json_path, &info).await?;
     } else {
         info!("File already exists: {}", json_path.display());
@@ -119,11 +208,65 @@ pub async fn get_item_info(
         get_model_info(path, client, headers, model_id, config.civitai.overwrite_json).await?;
     }
 
-    download_preview(client, headers, config, &info, path).await?;
+    download_preview(client, headers, config, &info, path, preview_count).await?;
 
     Ok(())
 }
 
+/// Fail with [`CivitaiError`] if Civitai responded with an error
+fn check_error(status: StatusCode, info: &Value) -> Result<(), CivitaiError> {
+    match info["error"].as_str() {
+        Some(err) if !err.is_empty() => Err(CivitaiError {
+            status,
+            message: err.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Info of a model version on Civitai by BLAKE3 of its file
+pub async fn get_version_info(client: &Client, headers: &HeaderMap, blake3: &str) -> anyhow::Result<Value> {
+    let url = format!("https://civitai.com/api/v1/model-versions/by-hash/{blake3}");
+    let res = client.get(url).headers(headers.clone()).send().await?;
+    let status = res.status();
+    check_unauthorized(status, headers);
+    let info: Value = res.json().await?;
+    check_error(status, &info)?;
+    Ok(info)
+}
+
+/// Info of a model on Civitai with all its versions, newest first. Wait and retry if rate limited.
+pub async fn get_model(
+    client: &Client,
+    headers: &HeaderMap,
+    model_id: i64,
+    max_retries: usize,
+) -> anyhow::Result<Value> {
+    let url = format!("https://civitai.com/api/v1/models/{model_id}");
+    let mut retries = 0;
+    loop {
+        let res = client.get(&url).headers(headers.clone()).send().await?;
+        check_unauthorized(res.status(), headers);
+        if res.status() == StatusCode::TOO_MANY_REQUESTS && retries < max_retries {
+            retries += 1;
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(RATE_LIMIT_DELAY_SECS);
+            info!("Rate limited by Civitai. Retry in {retry_after}s");
+            tokio::time::sleep(Duration::from_secs(retry_after)).await;
+            continue;
+        }
+
+        let status = res.status();
+        let info: Value = res.json().await?;
+        check_error(status, &info)?;
+        return Ok(info);
+    }
+}
+
 async fn get_model_info(
     path: &Path,
     client: &Client,
@@ -137,23 +280,110 @@ async fn get_model_info(
     if !json_path.exists() || overwrite {
         let url = format!("https://civitai.com/api/v1/models/{model_id}");
         // TODO: clean code
-        info = client.get(url).headers(headers.clone()).send().await?.json().await?;
-        if let Some(err) = info["error"].as_str() {
-            if !err.is_empty() {
-                return Err(anyhow::anyhow!(err.to_string()));
-            }
-        }
+        let res = client.get(url).headers(headers.clone()).send().await?;
+        let status = res.status();
+        check_unauthorized(status, headers);
+        info = res.json().await?;
+        check_error(status, &info)?;
         save_info(&json_path, &info).await?;
     }
     Ok(())
 }
 
+/// Fail if a file of `size` bytes doesn't fit in `dir`. Pass if free space can't be queried.
+pub fn check_disk_space(dir: &Path, size: u64) -> anyhow::Result<()> {
+    match fs2::available_space(dir) {
+        Ok(available) if size > available => Err(anyhow::anyhow!(
+            "Not enough disk space in {}: need {} bytes, {} bytes available",
+            dir.display(),
+            size,
+            available
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Existing file in `dir` whose name differs from `name` only by case
+pub fn find_case_collision(dir: &Path, name: &str) -> Option<PathBuf> {
+    let lowercase_name = name.to_lowercase();
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .find(|entry| {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            file_name != name && file_name.to_lowercase() == lowercase_name
+        })
+        .map(|entry| entry.path())
+}
+
+/// Expand `template` with model version `info`. `name` is the original file name. Its extension is appended if the
+/// expanded name doesn't end with it, so that the downloaded file is still found as a model file.
+///
+/// Return `None` if a token is unknown or has no value.
+pub fn expand_filename_template(template: &str, info: &Value, name: &str) -> Option<String> {
+    let name_path = Path::new(name);
+    let stem = name_path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let ext = name_path.extension().and_then(|s| s.to_str()).unwrap_or_default();
+
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}')? + start;
+        let value = match &rest[start + 1..end] {
+            "modelName" => info["model"]["name"].as_str().map(str::to_string),
+            "versionName" => info["name"].as_str().map(str::to_string),
+            "baseModel" => info["baseModel"].as_str().map(str::to_string),
+            "type" => info["model"]["type"].as_str().map(str::to_string),
+            "modelId" => info["modelId"].as_i64().map(|id| id.to_string()),
+            "versionId" => info["id"].as_i64().map(|id| id.to_string()),
+            "name" => Some(stem.to_string()),
+            "ext" => Some(ext.to_string()),
+            _ => None,
+        }
+        .filter(|value| !value.trim().is_empty())?;
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    let mut sanitized = sanitize_filename(&expanded);
+    if sanitized.is_empty() {
+        return None;
+    }
+    let has_ext = Path::new(&sanitized)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(ext));
+    if !ext.is_empty() && !has_ext {
+        sanitized = format!("{sanitized}.{}", sanitize_filename(ext));
+    }
+    Some(sanitized)
+}
+
+/// Replace characters which are not allowed in file names on common filesystems
+pub fn sanitize_filename(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Leading dots hide the file, trailing dots and spaces are dropped by Windows
+    sanitized
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' '])
+        .to_string()
+}
+
 pub async fn download_file(
     url: &str,
     path: &Path,
     client: &Client,
     headers: &HeaderMap,
-    base_paths: &HashMap<String, String>,
+    config: &Config,
     blake3: &str,
     max_retry: usize,
 ) -> anyhow::Result<()> {
@@ -165,13 +395,13 @@ pub async fn download_file(
             return Ok(());
         } else {
             let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
-            let mut trash_path = PathBuf::from(path.parent().unwrap_or(Path::new("."))).join(TRASH_DIR);
-            for (_, base_path) in base_paths.iter() {
+            let mut trash_path = PathBuf::from(path.parent().unwrap_or(Path::new("."))).join(&config.trash_dir);
+            for (label, base_path) in config.model_paths.iter() {
                 if path.starts_with(base_path) {
-                    trash_path = PathBuf::from(base_path).join(TRASH_DIR);
+                    trash_path = config.trash_path(label).unwrap_or(trash_path);
                 }
             }
-            if fs::create_dir_all(&trash_path).await.is_ok() {
+            if fs::create_dir_all(long_path(&trash_path)).await.is_ok() {
                 let mut new_name = PathBuf::from(path);
                 new_name.set_extension(format!(
                     "{}.bakup.{}",
@@ -179,7 +409,7 @@ pub async fn download_file(
                     timestamp
                 ));
                 trash_path = trash_path.join(new_name.file_name().unwrap_or_default());
-                fs::rename(path, trash_path).await?;
+                fs::rename(long_path(path), long_path(&trash_path)).await?;
             }
         }
     }
@@ -187,23 +417,59 @@ pub async fn download_file(
     let mut hasher = blake3::Hasher::new();
     let mut downloaded_bytes = 0;
     let mut retried = 0;
-    let mut file = File::create(path)?;
+    // Write to `<name>.part` so that an unfinished file is never indexed as a model
+    let part_path = part_path(path);
+    let mut file = File::create(long_path(&part_path))?;
     let mut range_headers = headers.clone();
     let mut err_msg = String::new();
     loop {
+        // Whether the server sent the whole response without interruption
+        let mut completed = false;
         match client.get(url).headers(range_headers.clone()).send().await {
             Ok(response) => {
                 if !response.status().is_success() {
-                    err_msg = response.text().await.unwrap_or_default();
+                    let status = response.status();
+                    check_unauthorized(status, headers);
+                    err_msg = format!("{status}: {}", response.text().await.unwrap_or_default());
                     error!("Request failed: {}", &err_msg);
                 } else {
+                    let size = response
+                        .headers()
+                        .get(CONTENT_LENGTH)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse::<u64>().ok());
+                    if config.civitai.check_disk_space
+                        && downloaded_bytes == 0
+                        && let Some(size) = size
+                        && let Err(e) = check_disk_space(path.parent().unwrap_or(Path::new(".")), size)
+                    {
+                        // Don't leave an empty file behind
+                        drop(file);
+                        let _ = fs::remove_file(long_path(&part_path)).await;
+                        return Err(e);
+                    }
+
+                    // Chunks are written as they arrive, so memory use doesn't grow with file size
                     let mut stream = response.bytes_stream();
+                    completed = true;
                     while let Some(chunk_result) = stream.next().await {
-                        if let Ok(chunk) = chunk_result {
-                            if file.write_all(&chunk).is_ok() {
+                        match chunk_result {
+                            Ok(chunk) if file.write_all(&chunk).is_ok() => {
                                 hasher.update(&chunk);
                                 downloaded_bytes += chunk.len();
                             }
+                            Ok(_) => {
+                                err_msg = format!("Failed to write {}", part_path.display());
+                                error!("{}", err_msg);
+                                completed = false;
+                                break;
+                            }
+                            Err(e) => {
+                                err_msg = format!("{e}");
+                                error!("Download interrupted: {}", err_msg);
+                                completed = false;
+                                break;
+                            }
                         }
                     }
                 }
@@ -211,14 +477,22 @@ pub async fn download_file(
             Err(e) => {
                 err_msg = format!("{e}");
                 error!("{}", err_msg.as_str());
-                break;
             }
         }
-        let file_hash = hasher.finalize();
-        if blake3.is_empty() || blake3 == file_hash.to_hex().to_string().to_lowercase() {
-            break;
+        if completed {
+            let file_hash = hasher.finalize().to_hex().to_string().to_lowercase();
+            if blake3.is_empty() || blake3 == file_hash {
+                break;
+            }
+            // Downloading again gives the same wrong file, so don't retry
+            drop(file);
+            let _ = fs::remove_file(long_path(&part_path)).await;
+            return Err(anyhow::anyhow!("Hash mismatch (expected {blake3}, got {file_hash})"));
         }
-        if retried > max_retry {
+        // The request or the stream failed, so resume from what is written. The part file is never renamed.
+        if retried >= max_retry {
+            drop(file);
+            let _ = fs::remove_file(long_path(&part_path)).await;
             return Err(anyhow::anyhow!(err_msg));
         }
 
@@ -226,60 +500,186 @@ pub async fn download_file(
         range_headers.insert("Range", HeaderValue::from_str(&format!("bytes={}-", downloaded_bytes))?);
     }
     file.flush()?;
+    drop(file);
+    fs::rename(long_path(&part_path), long_path(path)).await?;
     info!("Finish downloading: {}", path.display());
     Ok(())
 }
 
+/// Temporary path of a file while it's being downloaded
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    PathBuf::from(part_path)
+}
+
 async fn download_preview(
     client: &Client,
     headers: &HeaderMap,
     config: &Config,
     info: &Value,
     model_path: &Path,
+    count: usize,
 ) -> anyhow::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+
     if let Some(images) = info["images"].as_array() {
-        if let Some(first_image) = images.first() {
-            if let Some(url) = first_image["url"].as_str() {
-                let extension = get_extension_from_url(url).unwrap_or(PREVIEW_EXT.to_string());
-                let mut preview_file = PathBuf::from(model_path);
-                preview_file.set_extension(extension);
-
-                let image_path = Path::new(&preview_file);
-                if image_path.exists() && !config.civitai.overwrite_thumbnail {
-                    info!("File already exists: {}", image_path.display());
-                } else {
-                    download_file(
-                        url,
-                        image_path,
-                        client,
-                        headers,
-                        &config.model_paths,
-                        "",
-                        config.civitai.max_retries,
-                    )
-                    .await?;
-                }
+        let mut images = images.iter().collect::<Vec<_>>();
+        // Move the first non-video image to the front so that it becomes the main preview
+        if config.civitai.prefer_image_preview
+            && let Some(pos) = images.iter().position(|image| image["type"].as_str() != Some("video"))
+        {
+            let image = images.remove(pos);
+            images.insert(0, image);
+        }
 
-                let file_type = file_type(image_path).await;
-                if file_type == FileType::Video {
-                    if let Err(e) = generate_video_thumbnail(&preview_file, config.civitai.overwrite_thumbnail) {
-                        error!("Failed to generate video thumbnail: {}", e);
-                    }
-                } else if file_type == FileType::Image {
-                    //  Change preview image extension to jpeg for easier to manage
-                    if image_path.extension().unwrap_or_default() != PREVIEW_EXT {
-                        let mut new_name = preview_file.clone();
-                        new_name.set_extension(PREVIEW_EXT);
-                        fs::rename(preview_file, new_name).await?;
-                    }
+        if let Some(first_image) = images.first()
+            && let Some(url) = first_image["url"].as_str()
+        {
+            let extension = get_extension_from_url(url).unwrap_or(config.preview_ext().to_string());
+            let mut preview_file = PathBuf::from(model_path);
+            preview_file.set_extension(&extension);
+
+            let image_path = Path::new(&preview_file);
+            if image_path.exists() && !config.civitai.overwrite_thumbnail {
+                info!("File already exists: {}", image_path.display());
+            } else {
+                download_file(url, image_path, client, headers, config, "", config.civitai.max_retries).await?;
+            }
+
+            let file_type = file_type(image_path).await;
+            if file_type == FileType::Video {
+                if let Err(e) = generate_video_thumbnail(&preview_file, config) {
+                    error!("Failed to generate video thumbnail: {}", e);
+                }
+            } else if file_type == FileType::Image {
+                // Images in other formats are renamed so that they are found as preview
+                if !config.is_preview_ext(&extension) {
+                    let mut new_name = preview_file.clone();
+                    new_name.set_extension(config.preview_ext());
+                    fs::rename(preview_file, new_name).await?;
                 }
             }
         }
+
+        // The first image is the main preview, others are saved as `<name>.<index>.<ext>`
+        for (i, image) in images.iter().enumerate().take(count).skip(1) {
+            let Some(url) = image["url"].as_str() else {
+                continue;
+            };
+            if let Err(e) = download_gallery_image(client, headers, config, url, model_path, i).await {
+                error!("Failed to download gallery image {}: {}", url, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn download_gallery_image(
+    client: &Client,
+    headers: &HeaderMap,
+    config: &Config,
+    url: &str,
+    model_path: &Path,
+    index: usize,
+) -> anyhow::Result<()> {
+    let extension = get_extension_from_url(url).unwrap_or(config.preview_ext().to_string());
+    let image_path = model_path.with_extension(format!("{index}.{extension}"));
+    let converted_path = if config.is_preview_ext(&extension) {
+        image_path.clone()
+    } else {
+        model_path.with_extension(format!("{index}.{}", config.preview_ext()))
+    };
+    if (image_path.exists() || converted_path.exists()) && !config.civitai.overwrite_thumbnail {
+        info!("File already exists: {}", image_path.display());
+        return Ok(());
     }
+
+    download_file(
+        url,
+        &image_path,
+        client,
+        headers,
+        config,
+        "",
+        config.civitai.max_retries,
+    )
+    .await?;
+
+    if file_type(&image_path).await == FileType::Image && image_path != converted_path {
+        fs::rename(&image_path, &converted_path).await?;
+    }
+
     Ok(())
 }
 
-async fn save_info(info_file: &Path, info: &Value) -> anyhow::Result<()> {
+/// Page of a model version on Civitai
+pub fn model_page_url(model_id: i64, version_id: i64) -> String {
+    format!("https://civitai.com/models/{model_id}?modelVersionId={version_id}")
+}
+
+/// AI Resource identifier like `urn:air:sdxl:lora:civitai:328553@368189`.
+/// `None` if the ecosystem of the base model is unknown.
+pub fn air_identifier(base_model: &str, model_type: &str, model_id: i64, version_id: i64) -> Option<String> {
+    let base_model = base_model.to_lowercase();
+    let ecosystem = if base_model.starts_with("sd 1") {
+        "sd1"
+    } else if base_model.starts_with("sd 2") {
+        "sd2"
+    } else if base_model.starts_with("sd 3") {
+        "sd3"
+    } else if base_model.starts_with("sdxl") || base_model.starts_with("pony") || base_model.starts_with("illustrious")
+    {
+        "sdxl"
+    } else if base_model.starts_with("flux") {
+        "flux1"
+    } else {
+        return None;
+    };
+    let resource_type = match model_type.to_lowercase().as_str() {
+        "textualinversion" => "embedding".to_string(),
+        "hypernetwork" => "hypernet".to_string(),
+        "locon" | "dora" => "lora".to_string(),
+        model_type => model_type.to_string(),
+    };
+
+    Some(format!(
+        "urn:air:{ecosystem}:{resource_type}:civitai:{model_id}@{version_id}"
+    ))
+}
+
+/// Infer model type from the nearest parent directory which is named after a model type
+pub fn infer_model_type(config: &Config, path: &Path) -> Option<&'static str> {
+    path.ancestors()
+        .skip(1)
+        .filter_map(|dir| dir.file_name()?.to_str())
+        .find_map(|dir| {
+            MODEL_TYPES
+                .into_iter()
+                .find(|model_type| config.civitai.model_type_dir(model_type).eq_ignore_ascii_case(dir))
+        })
+}
+
+/// NSFW level of a model version. Fall back to the highest level of its images, then the level of the model.
+///
+/// Levels on Civitai are bit flags, so the highest flag is taken.
+pub fn parse_nsfw_level(item_info: &Value, model_info: &Value) -> Option<i64> {
+    let level = item_info["nsfwLevel"]
+        .as_i64()
+        .or_else(|| {
+            item_info["images"]
+                .as_array()?
+                .iter()
+                .filter_map(|image| image["nsfwLevel"].as_i64())
+                .max()
+        })
+        .or_else(|| model_info["nsfwLevel"].as_i64());
+    level.filter(|l| *l > 0).map(|l| 1 << l.ilog2())
+}
+
+pub async fn save_info(info_file: &Path, info: &Value) -> anyhow::Result<()> {
     if !info_file.extension().unwrap_or_default().eq("json") {
         return Err(anyhow::anyhow!("Invalid json extension. Do you save to wrong file?"));
     }
@@ -310,10 +710,32 @@ pub fn calculate_blake3(file_path: &Path) -> std::io::Result<String> {
     Ok(result.to_hex().to_string().to_lowercase())
 }
 
-fn generate_video_thumbnail(file_path: &Path, overwrite: bool) -> anyhow::Result<()> {
+/// BLAKE3 and SHA256 of a file, computed in a single read
+pub fn calculate_hashes(file_path: &Path) -> std::io::Result<(String, String)> {
+    let file = File::open(file_path)?;
+    let mut reader = BufReader::new(file);
+    let mut blake3_hasher = blake3::Hasher::new();
+    let mut sha256_hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        blake3_hasher.update(&buffer[..bytes_read]);
+        sha256_hasher.update(&buffer[..bytes_read]);
+    }
+
+    let blake3 = blake3_hasher.finalize().to_hex().to_string().to_lowercase();
+    let sha256 = sha256_hasher.finalize().iter().map(|b| format!("{b:02x}")).collect();
+    Ok((blake3, sha256))
+}
+
+fn generate_video_thumbnail(file_path: &Path, config: &Config) -> anyhow::Result<()> {
     let mut thumbnail_path = PathBuf::from(file_path);
-    thumbnail_path.set_extension(PREVIEW_EXT);
-    if !overwrite && thumbnail_path.exists() {
+    thumbnail_path.set_extension(config.preview_ext());
+    if !config.civitai.overwrite_thumbnail && thumbnail_path.exists() {
         return Ok(());
     }
 
@@ -337,8 +759,12 @@ fn generate_video_thumbnail(file_path: &Path, overwrite: bool) -> anyhow::Result
     Ok(())
 }
 
+/// Detect type of a file by its first bytes. The rest of the file is not read, previews can be large videos.
 pub async fn file_type(path: &Path) -> FileType {
-    let data = fs::read(path).await.ok().unwrap_or_default();
+    let mut data = Vec::new();
+    if let Ok(file) = fs::File::open(path).await {
+        let _ = file.take(FILE_TYPE_HEAD_LEN).read_to_end(&mut data).await;
+    }
     if let Some(kind) = infer::get(&data) {
         if kind.mime_type().starts_with("video/") {
             return FileType::Video;
@@ -356,3 +782,263 @@ pub fn get_extension_from_url(url: &str) -> Option<String> {
         .and_then(|filename| Path::new(filename).extension())
         .and_then(|ext| ext.to_str().map(|ext| ext.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const DATA: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// Serve `DATA` over HTTP and honor `Range`. The first response is cut in the middle if `interrupt` is set.
+    fn serve(interrupt: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let start = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.trim().trim_end_matches('-').parse::<usize>().ok())
+                    .unwrap_or(0);
+                let body = &DATA[start..];
+                let status = if start > 0 { "206 Partial Content" } else { "200 OK" };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                let sent = if interrupt && i == 0 { body.len() / 2 } else { body.len() };
+                stream.write_all(&body[..sent]).unwrap();
+            }
+        });
+        format!("http://{addr}/model.safetensors")
+    }
+
+    fn test_config() -> Config {
+        let mut config = Config::default();
+        config.civitai.check_disk_space = false;
+        config
+    }
+
+    #[tokio::test]
+    async fn download_with_mismatched_hash_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.safetensors");
+        let blake3 = blake3::hash(b"another file").to_hex().to_string();
+
+        let result = download_file(
+            &serve(false),
+            &path,
+            &Client::new(),
+            &HeaderMap::new(),
+            &test_config(),
+            &blake3,
+            3,
+        )
+        .await;
+        assert!(result.unwrap_err().to_string().contains("Hash mismatch"));
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn network_failure_fails_without_leaving_file() {
+        // Connections are closed as soon as the request is read
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dropped = format!("http://{}/model.safetensors", listener.local_addr().unwrap());
+        let attempts = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        {
+            let attempts = attempts.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let _ = stream.read(&mut [0; 4096]);
+                    attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            });
+        }
+        // Nothing listens on the port of a closed listener
+        let refused = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/model.safetensors", listener.local_addr().unwrap())
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.safetensors");
+        // Previews are downloaded without a hash
+        for (url, blake3) in [(&dropped, ""), (&refused, ""), (&refused, "expected")] {
+            let result = download_file(url, &path, &Client::new(), &HeaderMap::new(), &test_config(), blake3, 2).await;
+            assert!(result.is_err(), "{url}");
+            assert!(!path.exists(), "{url}");
+            assert!(!part_path(&path).exists(), "{url}");
+        }
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn interrupted_download_is_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.safetensors");
+        let blake3 = blake3::hash(DATA).to_hex().to_string();
+
+        download_file(
+            &serve(true),
+            &path,
+            &Client::new(),
+            &HeaderMap::new(),
+            &test_config(),
+            &blake3,
+            3,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), DATA);
+        assert!(!part_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn download_is_written_while_streaming() {
+        // Memory of the test process is shared by parallel tests, so it's not measured directly. Instead the server
+        // stops in the middle of a large body until the first half is on disk, which can't happen if the body is
+        // buffered before writing.
+        const HALF: usize = 8 * 1024 * 1024;
+        let body = (0..HALF * 2).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let blake3 = blake3::hash(&body).to_hex().to_string();
+        let (resume_tx, resume_rx) = std::sync::mpsc::channel::<()>();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/model.safetensors", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).unwrap();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(&body[..HALF]).unwrap();
+            let _ = resume_rx.recv();
+            stream.write_all(&body[HALF..]).unwrap();
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.safetensors");
+        let download = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                download_file(
+                    &url,
+                    &path,
+                    &Client::new(),
+                    &HeaderMap::new(),
+                    &test_config(),
+                    &blake3,
+                    0,
+                )
+                .await
+            })
+        };
+
+        let part_path = part_path(&path);
+        let started = Instant::now();
+        while std::fs::metadata(&part_path).map_or(0, |m| m.len()) < HALF as u64 {
+            assert!(started.elapsed() < Duration::from_secs(10), "first half is not written");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        resume_tx.send(()).unwrap();
+
+        download.await.unwrap().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (HALF * 2) as u64);
+    }
+
+    #[test]
+    fn api_keys_are_used_in_turn() {
+        let keys = ["a", "b", "c"];
+        let picked = (0..4)
+            .map(|start| pick_api_key(&keys, start, &HashMap::new()))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn unhealthy_api_key_is_skipped() {
+        let keys = ["a", "b", "c"];
+        let mut unhealthy = HashMap::from([("b".to_string(), Instant::now())]);
+        let picked = (0..3)
+            .map(|start| pick_api_key(&keys, start, &unhealthy))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["a", "c", "c"]);
+
+        // Keys are still used if all of them were rejected
+        unhealthy.insert("a".to_string(), Instant::now());
+        unhealthy.insert("c".to_string(), Instant::now());
+        assert_eq!(pick_api_key(&keys, 1, &unhealthy), "b");
+    }
+
+    #[test]
+    fn case_collision_is_found_only_for_other_names() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("Model.safetensors"), b"").unwrap();
+
+        assert_eq!(
+            find_case_collision(dir.path(), "model.SAFETENSORS"),
+            Some(dir.path().join("Model.safetensors"))
+        );
+        // Same file is not a collision
+        assert_eq!(find_case_collision(dir.path(), "Model.safetensors"), None);
+        assert_eq!(find_case_collision(dir.path(), "other.safetensors"), None);
+        assert_eq!(
+            find_case_collision(&dir.path().join("missing"), "model.safetensors"),
+            None
+        );
+    }
+
+    #[test]
+    fn filename_template_is_expanded_with_version_info() {
+        let info = serde_json::json!({
+            "id": 20,
+            "modelId": 10,
+            "name": "v1.0",
+            "baseModel": "SDXL 1.0",
+            "model": {"name": "Cute: Style", "type": "LORA"},
+        });
+
+        assert_eq!(
+            expand_filename_template("{modelName}_{versionName}_{versionId}.{ext}", &info, "orig.safetensors"),
+            Some("Cute_ Style_v1.0_20.safetensors".to_string())
+        );
+        assert_eq!(
+            expand_filename_template("{type}/{baseModel}-{name}", &info, "orig.safetensors"),
+            Some("LORA_SDXL 1.0-orig.safetensors".to_string())
+        );
+        // Extension is kept if the template has no `{ext}`
+        assert_eq!(
+            expand_filename_template("{modelName}-{versionName}", &info, "orig.SafeTensors"),
+            Some("Cute_ Style-v1.0.SafeTensors".to_string())
+        );
+        assert_eq!(
+            expand_filename_template("{name}.SAFETENSORS", &info, "orig.safetensors"),
+            Some("orig.SAFETENSORS".to_string())
+        );
+        // Unknown token, missing value and unclosed brace
+        assert_eq!(expand_filename_template("{unknown}", &info, "orig.safetensors"), None);
+        assert_eq!(
+            expand_filename_template("{modelName}", &serde_json::json!({}), "orig.safetensors"),
+            None
+        );
+        assert_eq!(expand_filename_template("{modelName", &info, "orig.safetensors"), None);
+    }
+
+    #[test]
+    fn filename_is_sanitized() {
+        assert_eq!(sanitize_filename(r#"a/b\c:d*e?f"g<h>i|j"#), "a_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize_filename("tab\tname"), "tab_name");
+        assert_eq!(sanitize_filename(" ..hidden. . "), "hidden");
+        assert_eq!(sanitize_filename(".."), "");
+    }
+}
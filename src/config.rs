@@ -1,34 +1,96 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
+use indexmap::IndexSet;
 use ron::ser::{to_string_pretty, PrettyConfig};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::log::info;
 
 const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0";
 const DEFAULT_LISTEN_PORT: u32 = 9696;
 
 const DEFAULT_SQLITE_PATH: &str = "sdmm.sqlite";
+const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
 
 const DEFAULT_API_PER_PAGE: u32 = 20;
+const DEFAULT_API_MAX_PER_PAGE: u32 = 200;
+const DEFAULT_TAG_LIMIT: u32 = 50;
+const DEFAULT_COUNT_CACHE_TTL: u64 = 5;
+const DEFAULT_READ_PER_MINUTE: u32 = 600;
+const DEFAULT_WRITE_PER_MINUTE: u32 = 60;
 const DEFAULT_PARALLEL: usize = 8;
+const DEFAULT_IMAGE_CACHE_DIR: &str = "image_cache";
+const DEFAULT_IMAGE_CACHE_MAX_MB: u64 = 512;
+const DEFAULT_MODEL_EXTENSIONS: [&str; 4] = ["safetensors", "ckpt", "pt", "pth"];
+const DEFAULT_PREVIEW_EXTENSIONS: [&str; 4] = ["jpeg", "jpg", "png", "webp"];
+const DEFAULT_TRASH_DIR: &str = ".trash";
+
+/// Directories of operating systems and NAS which never contain models
+const SYSTEM_DIRS: [&str; 5] = [
+    "$RECYCLE.BIN",
+    "System Volume Information",
+    "lost+found",
+    "@eaDir",
+    "#recycle",
+];
+
+/// Placeholder of secrets in config responses. Submitting it back keeps the current secret.
+const REDACTED: &str = "********";
 
 #[derive(Deserialize, Debug, Serialize, Clone)]
 pub struct SQLiteConfig {
     pub db_path: String,
+    /// WAL lets readers run alongside a writer, which avoids "database is locked" under parallel scans and downloads
+    #[serde(default)]
+    pub journal_mode: JournalMode,
+    /// Milliseconds to wait for a lock before failing with "database is locked"
+    #[serde(default = "default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    /// `Normal` is safe with WAL and faster than `Full`
+    #[serde(default)]
+    pub synchronous: Synchronous,
 }
 
 impl Default for SQLiteConfig {
     fn default() -> Self {
         Self {
             db_path: DEFAULT_SQLITE_PATH.to_string(),
+            journal_mode: JournalMode::default(),
+            busy_timeout_ms: DEFAULT_BUSY_TIMEOUT_MS,
+            synchronous: Synchronous::default(),
         }
     }
 }
 
+/// Journal mode of SQLite. See <https://www.sqlite.org/pragma.html#pragma_journal_mode>.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    #[default]
+    Wal,
+    Off,
+}
+
+/// Synchronous level of SQLite. See <https://www.sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+fn default_busy_timeout_ms() -> u64 {
+    DEFAULT_BUSY_TIMEOUT_MS
+}
+
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct DBConfig {
     pub sqlite: SQLiteConfig,
@@ -37,22 +99,107 @@ pub struct DBConfig {
 #[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct APIConfig {
     pub per_page: u32,
+    /// Highest number of items per page which clients can request
+    #[serde(default = "default_max_per_page")]
+    pub max_per_page: u32,
     #[serde(default)]
     pub basic_auth_user: String,
     #[serde(default)]
     pub basic_auth_pass: String,
+    /// Seconds to cache the total count of search results. 0 to disable.
+    #[serde(default = "default_count_cache_ttl")]
+    pub count_cache_ttl: u64,
+    /// Compress responses with gzip/brotli/zstd if client accepts it.
+    /// Images and videos are not compressed again.
+    /// The compression level is not configurable, actix-web always uses its fast levels.
+    #[serde(default = "default_true")]
+    pub compress: bool,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Name to show as `name` of items
+    #[serde(default)]
+    pub display_name: DisplayName,
+    /// Number of tags to return with search results by default. 0 to return all.
+    #[serde(default = "default_tag_limit")]
+    pub tag_limit: u32,
+    /// Allow `explain=true` in item search to return query plans and timing. Only for debugging.
+    #[serde(default)]
+    pub allow_explain: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayName {
+    /// Name of the model file
+    #[default]
+    FileName,
+    /// Name of the model on Civitai. Fall back to the file name if it's unknown.
+    ModelName,
+}
+
+/// Limit requests to API per client IP
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Requests per minute to read endpoints
+    #[serde(default = "default_read_per_minute")]
+    pub read_per_minute: u32,
+    /// Requests per minute to endpoints which download, delete or update items
+    #[serde(default = "default_write_per_minute")]
+    pub write_per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            read_per_minute: DEFAULT_READ_PER_MINUTE,
+            write_per_minute: DEFAULT_WRITE_PER_MINUTE,
+        }
+    }
+}
+
+fn default_read_per_minute() -> u32 {
+    DEFAULT_READ_PER_MINUTE
+}
+
+fn default_write_per_minute() -> u32 {
+    DEFAULT_WRITE_PER_MINUTE
 }
 
 impl Default for APIConfig {
     fn default() -> Self {
         Self {
             per_page: DEFAULT_API_PER_PAGE,
+            max_per_page: DEFAULT_API_MAX_PER_PAGE,
             basic_auth_user: String::new(),
             basic_auth_pass: String::new(),
+            count_cache_ttl: DEFAULT_COUNT_CACHE_TTL,
+            compress: true,
+            rate_limit: RateLimitConfig::default(),
+            display_name: DisplayName::default(),
+            tag_limit: DEFAULT_TAG_LIMIT,
+            allow_explain: false,
         }
     }
 }
 
+fn default_tag_limit() -> u32 {
+    DEFAULT_TAG_LIMIT
+}
+
+fn default_max_per_page() -> u32 {
+    DEFAULT_API_MAX_PER_PAGE
+}
+
+fn default_count_cache_ttl() -> u64 {
+    DEFAULT_COUNT_CACHE_TTL
+}
+
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct CivitaiSearch {
     #[serde(default)]
@@ -82,6 +229,9 @@ impl Default for CivitaiSearch {
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct CivitaiConfig {
     pub api_key: String,
+    /// More API keys which are used in turn with `api_key` to spread load over them
+    #[serde(default)]
+    pub api_keys: Vec<String>,
     pub overwrite_thumbnail: bool,
     pub overwrite_json: bool,
     #[serde(default)]
@@ -90,17 +240,202 @@ pub struct CivitaiConfig {
     pub max_retries: usize,
     #[serde(default)]
     pub search: CivitaiSearch,
+    /// Directory to cache images which are fetched through image proxy
+    #[serde(default = "default_image_cache_dir")]
+    pub image_cache_dir: String,
+    /// Maximum size of the image cache in MB. The least recently used images are removed first. 0 means no limit.
+    #[serde(default = "default_image_cache_max_mb")]
+    pub image_cache_max_mb: u64,
+    /// Check free disk space before downloading. Disable it for filesystems which can't report free space reliably.
+    #[serde(default = "default_true")]
+    pub check_disk_space: bool,
+    /// What to do if a download differs only by case from an existing file, which is the same file on
+    /// case-insensitive filesystems
+    #[serde(default)]
+    pub case_collision: CaseCollision,
+    /// Name of downloaded files like `{modelName}-{versionName}.{ext}`. Available tokens are `{modelName}`,
+    /// `{versionName}`, `{baseModel}`, `{type}`, `{modelId}`, `{versionId}`, `{name}` (original name without extension)
+    /// and `{ext}`. The original name is kept if it's empty or a token has no value. The original extension is appended
+    /// if the name doesn't end with it.
+    #[serde(default)]
+    pub download_filename_template: String,
+    /// Preview images to download with a model from Civitai, also used when refreshing model info
+    #[serde(default)]
+    pub download_previews: PreviewFetch,
+    /// Use the first image instead of a video as the main preview if a model has both
+    #[serde(default)]
+    pub prefer_image_preview: bool,
+    /// Sub directory to download each model type to, per label, like `{"sdxl": {"lora": "loras"}}`.
+    /// It's relative to the model path of the label and takes precedence over the last used directory.
+    #[serde(default)]
+    pub label_download_dirs: HashMap<String, HashMap<String, String>>,
+    /// Directory name for each Civitai model type, like `{"LoCon": "loras"}`. Types are matched case-insensitively
+    /// and the lowercased type is used if it's not in the map.
+    #[serde(default = "default_model_type_dirs")]
+    pub model_type_dirs: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewFetch {
+    /// Don't download any preview
+    None,
+    /// Download only the main preview
+    #[default]
+    First,
+    /// Download the main preview and example images, up to this number in total
+    FirstN(usize),
+}
+
+impl PreviewFetch {
+    /// Number of images to download
+    pub fn count(&self) -> usize {
+        match self {
+            PreviewFetch::None => 0,
+            PreviewFetch::First => 1,
+            PreviewFetch::FirstN(n) => *n,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CaseCollision {
+    /// Download anyway and warn about it
+    #[default]
+    Warn,
+    /// Refuse to download
+    Block,
+    /// Download silently
+    Allow,
 }
 
 impl Default for CivitaiConfig {
     fn default() -> Self {
         Self {
             api_key: "your_civitai_api_key".to_string(),
+            api_keys: Vec::new(),
             overwrite_thumbnail: false,
             overwrite_json: false,
             download_dir: HashMap::new(),
             max_retries: 3,
             search: CivitaiSearch::default(),
+            image_cache_dir: DEFAULT_IMAGE_CACHE_DIR.to_string(),
+            image_cache_max_mb: DEFAULT_IMAGE_CACHE_MAX_MB,
+            check_disk_space: true,
+            case_collision: CaseCollision::default(),
+            download_filename_template: String::new(),
+            download_previews: PreviewFetch::default(),
+            prefer_image_preview: false,
+            label_download_dirs: HashMap::new(),
+            model_type_dirs: default_model_type_dirs(),
+        }
+    }
+}
+
+impl CivitaiConfig {
+    /// Directory name to save models of this type
+    pub fn model_type_dir(&self, model_type: &str) -> String {
+        self.model_type_dirs
+            .iter()
+            .find(|(t, _)| t.eq_ignore_ascii_case(model_type))
+            .map(|(_, dir)| dir.clone())
+            .unwrap_or_else(|| model_type.to_lowercase())
+    }
+
+    /// All API keys without duplicates, `api_key` first
+    pub fn all_api_keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        for key in std::iter::once(&self.api_key).chain(self.api_keys.iter()) {
+            let key = key.trim();
+            if !key.is_empty() && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    /// Copy with the API key masked, to be shown to users
+    pub fn masked(&self) -> Self {
+        Self {
+            api_key: mask_secret(&self.api_key),
+            api_keys: self.api_keys.iter().map(|key| mask_secret(key)).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Show only the last 4 characters of a long secret
+fn mask_secret(secret: &str) -> String {
+    let len = secret.chars().count();
+    if len == 0 {
+        String::new()
+    } else if len > 8 {
+        let last = secret.chars().skip(len - 4).collect::<String>();
+        format!("****{last}")
+    } else {
+        REDACTED.to_string()
+    }
+}
+
+/// Whether `secret` is shown masked by `mask_secret`, so it's not a real secret
+fn is_masked(secret: &str) -> bool {
+    secret == REDACTED || secret.starts_with("****")
+}
+
+fn default_model_type_dirs() -> HashMap<String, String> {
+    [
+        ("LORA", "loras"),
+        ("Hypernetwork", "hypernetworks"),
+        ("Checkpoint", "checkpoints"),
+    ]
+    .into_iter()
+    .map(|(model_type, dir)| (model_type.to_string(), dir.to_string()))
+    .collect()
+}
+
+fn default_image_cache_dir() -> String {
+    DEFAULT_IMAGE_CACHE_DIR.to_string()
+}
+
+fn default_image_cache_max_mb() -> u64 {
+    DEFAULT_IMAGE_CACHE_MAX_MB
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CorsConfig {
+    /// Origins like `https://example.com` which are allowed to call the API.
+    /// Only same origin is allowed if it's empty.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ServerConfig {
+    /// Address to bind as `host:port`, like `127.0.0.1:9696`. `listen_addr` and `listen_port` are used if it's
+    /// empty. It's read at startup, so a change takes effect after restart.
+    #[serde(default)]
+    pub bind: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NsfwConfig {
+    /// Highest NSFW level to show if search doesn't set it. 0 to show all.
+    /// Levels on Civitai: 1 (PG), 2 (PG-13), 4 (R), 8 (X), 16 (XXX).
+    #[serde(default)]
+    pub max_level: i64,
+    /// Show items without NSFW data when max level is set
+    #[serde(default = "default_true")]
+    pub show_unknown: bool,
+    /// Blur previews above max level instead of withholding them
+    #[serde(default = "default_true")]
+    pub blur_preview: bool,
+}
+
+impl Default for NsfwConfig {
+    fn default() -> Self {
+        Self {
+            max_level: 0,
+            show_unknown: true,
+            blur_preview: true,
         }
     }
 }
@@ -116,11 +451,56 @@ pub struct Config {
     #[serde(default)]
     pub listen_port: u32,
     #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
     pub api: APIConfig,
     #[serde(default)]
     pub parallel: usize,
+    /// Files with these extensions are indexed as models. Other files next to them are sidecars.
+    #[serde(default = "default_model_extensions", alias = "extensions")]
+    pub model_extensions: HashSet<String>,
+    /// Extensions of preview images in order of priority. New previews are saved with the first one.
+    #[serde(default = "default_preview_extensions")]
+    pub preview_extensions: Vec<String>,
+    /// Watch model paths for changes and index them automatically
+    #[serde(default)]
+    pub watch: bool,
+    /// Follow symlinks when scanning. Each file is indexed once by its real path, and files which resolve outside
+    /// all model paths are skipped. Items are keyed by path, so a symlinked duplicate is never treated as a move.
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Also scan hidden files and directories, and system directories like `$RECYCLE.BIN`. Trash directory is never
+    /// scanned.
+    #[serde(default)]
+    pub scan_hidden_dirs: bool,
+    /// Compute SHA256 of models in addition to BLAKE3, in the same read of the file. It costs extra CPU, so it's
+    /// off by default. SHA256 from Civitai metadata is always stored.
     #[serde(default)]
-    pub extensions: HashSet<String>,
+    pub compute_sha256: bool,
+    /// Read trigger words from `<name>.txt` next to models whose Civitai metadata has none. Words are separated by
+    /// commas or new lines.
+    #[serde(default = "default_true")]
+    pub txt_trigger_words: bool,
+    /// Also tag models with trigger words from `.txt` files
+    #[serde(default)]
+    pub txt_trigger_words_as_tags: bool,
+    /// Scan model paths periodically. 0 to disable.
+    #[serde(default)]
+    pub scan_interval_minutes: u64,
+    /// Name of the trash directory in each model path. It must be hidden so that it's not scanned.
+    #[serde(default = "default_trash_dir")]
+    pub trash_dir: String,
+    /// Absolute directory to share trash of all model paths, with a subdirectory per label.
+    /// Empty to use `trash_dir` in each model path.
+    #[serde(default)]
+    pub trash_root: String,
+    /// Files in trash are purged this many days after their items are deleted. 0 to keep them until trash is emptied.
+    #[serde(default)]
+    pub trash_retention_days: u64,
+    #[serde(default)]
+    pub nsfw: NsfwConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
 }
 
 impl Default for Config {
@@ -128,30 +508,267 @@ impl Default for Config {
         Self {
             listen_addr: DEFAULT_LISTEN_ADDR.to_string(),
             listen_port: DEFAULT_LISTEN_PORT,
+            server: ServerConfig::default(),
             parallel: DEFAULT_PARALLEL,
             model_paths: HashMap::from([("collection1".to_string(), "/workspace/models".to_string())]),
-            extensions: HashSet::from([
-                "safetensors".to_string(),
-                "ckpt".to_string(),
-                "gguf".to_string(),
-                "pt".to_string(),
-                "pth".to_string(),
-            ]),
+            model_extensions: default_model_extensions(),
+            preview_extensions: default_preview_extensions(),
             db: DBConfig::default(),
             api: APIConfig::default(),
             civitai: CivitaiConfig::default(),
+            watch: false,
+            follow_symlinks: false,
+            scan_hidden_dirs: false,
+            compute_sha256: false,
+            txt_trigger_words: true,
+            txt_trigger_words_as_tags: false,
+            scan_interval_minutes: 0,
+            trash_dir: default_trash_dir(),
+            trash_root: String::new(),
+            trash_retention_days: 0,
+            nsfw: NsfwConfig::default(),
+            cors: CorsConfig::default(),
         }
     }
 }
 
+#[cfg(test)]
+impl Config {
+    /// Default config with `path` as model path `test`
+    pub fn with_model_path(path: &Path) -> Self {
+        Self {
+            model_paths: HashMap::from([("test".to_string(), path.to_string_lossy().to_string())]),
+            ..Self::default()
+        }
+    }
+}
+
+fn default_model_extensions() -> HashSet<String> {
+    DEFAULT_MODEL_EXTENSIONS.iter().map(|ext| ext.to_string()).collect()
+}
+
+fn default_preview_extensions() -> Vec<String> {
+    DEFAULT_PREVIEW_EXTENSIONS.iter().map(|ext| ext.to_string()).collect()
+}
+
+fn default_trash_dir() -> String {
+    DEFAULT_TRASH_DIR.to_string()
+}
+
 impl Config {
     /// Load config from file
     pub fn load(config_path: &Path) -> anyhow::Result<Self> {
         let file = File::open(config_path)?;
-        let config = ron::de::from_reader(file)?;
+        let mut config: Self = ron::de::from_reader(file)?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Normalize and check config values
+    pub fn validate(&mut self) -> anyhow::Result<()> {
+        self.model_extensions = self
+            .model_extensions
+            .iter()
+            .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect();
+        if self.model_extensions.is_empty() {
+            return Err(anyhow::anyhow!("model_extensions must not be empty"));
+        }
+
+        let mut preview_extensions = IndexSet::new();
+        for ext in self.preview_extensions.iter() {
+            let ext = ext.trim().trim_start_matches('.').to_lowercase();
+            if !ext.is_empty() {
+                preview_extensions.insert(ext);
+            }
+        }
+        if preview_extensions.is_empty() {
+            return Err(anyhow::anyhow!("preview_extensions must not be empty"));
+        }
+        self.preview_extensions = preview_extensions.into_iter().collect();
+
+        if self.api.max_per_page == 0 {
+            return Err(anyhow::anyhow!("api.max_per_page must be positive"));
+        }
+        self.api.per_page = self.api.per_page.min(self.api.max_per_page);
+
+        self.trash_dir = self.trash_dir.trim().to_string();
+        if !self.trash_dir.starts_with('.') || Path::new(&self.trash_dir).components().count() != 1 {
+            return Err(anyhow::anyhow!(
+                "trash_dir must be a hidden directory name like .trash: {}",
+                self.trash_dir
+            ));
+        }
+        self.trash_root = self.trash_root.trim().to_string();
+        if !self.trash_root.is_empty() {
+            self.check_trash_root()?;
+        }
+
+        self.server.bind = self.server.bind.trim().to_string();
+        if !self.server.bind.is_empty()
+            && !self
+                .server
+                .bind
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        {
+            return Err(anyhow::anyhow!(
+                "server.bind must be host:port like 127.0.0.1:9696: {}",
+                self.server.bind
+            ));
+        }
+
+        for origin in self.cors.allowed_origins.iter_mut() {
+            *origin = origin.trim().trim_end_matches('/').to_string();
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(anyhow::anyhow!("Invalid CORS origin: {origin}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Address which the server binds to, as `host:port`. `server.bind` takes precedence over `listen_addr` and
+    /// `listen_port`.
+    pub fn bind_addr(&self) -> String {
+        if self.server.bind.is_empty() {
+            format!("{}:{}", self.listen_addr, self.listen_port)
+        } else {
+            self.server.bind.clone()
+        }
+    }
+
+    /// Copy of config with secrets masked. Only the last characters of the API key are shown.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.civitai = config.civitai.masked();
+        if !config.api.basic_auth_pass.is_empty() {
+            config.api.basic_auth_pass = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// Keep secrets of `current` which are submitted back masked. An API key is restored from the same position, or
+    /// else from the only other unused key with the same mask, so that keys can be removed and reordered. Fail if a
+    /// masked key can't be restored, rather than saving the mask as the key.
+    pub fn restore_secrets(&mut self, current: &Config) -> anyhow::Result<()> {
+        if self.civitai.api_key == mask_secret(&current.civitai.api_key) {
+            self.civitai.api_key = current.civitai.api_key.clone();
+        }
+
+        let current_keys = &current.civitai.api_keys;
+        let mut used = vec![false; current_keys.len()];
+        let mut restored = vec![false; self.civitai.api_keys.len()];
+        for (i, key) in self.civitai.api_keys.iter_mut().enumerate() {
+            if let Some(current_key) = current_keys.get(i)
+                && is_masked(key)
+                && *key == mask_secret(current_key)
+            {
+                *key = current_key.clone();
+                used[i] = true;
+                restored[i] = true;
+            }
+        }
+        for (i, key) in self.civitai.api_keys.iter_mut().enumerate() {
+            if restored[i] || !is_masked(key) {
+                continue;
+            }
+            let mut candidates = (0..current_keys.len()).filter(|&j| !used[j] && *key == mask_secret(&current_keys[j]));
+            if let (Some(j), None) = (candidates.next(), candidates.next()) {
+                *key = current_keys[j].clone();
+                used[j] = true;
+            }
+        }
+
+        if self.api.basic_auth_pass == REDACTED {
+            self.api.basic_auth_pass = current.api.basic_auth_pass.clone();
+        }
+
+        let unrestored = std::iter::once(&self.civitai.api_key)
+            .chain(self.civitai.api_keys.iter())
+            .filter(|key| is_masked(key))
+            .count();
+        if unrestored > 0 {
+            return Err(anyhow::anyhow!(
+                "{unrestored} masked API key(s) don't match any saved key. Enter the keys again."
+            ));
+        }
+        Ok(())
+    }
+
+    /// Trash root must be absolute, outside model paths so that it's not scanned, and writable
+    fn check_trash_root(&self) -> anyhow::Result<()> {
+        let trash_root = Path::new(&self.trash_root);
+        if !trash_root.is_absolute() {
+            return Err(anyhow::anyhow!(
+                "trash_root must be an absolute path: {}",
+                self.trash_root
+            ));
+        }
+        for base_path in self.model_paths.values() {
+            if trash_root.starts_with(base_path) {
+                return Err(anyhow::anyhow!(
+                    "trash_root must be outside model paths: {}",
+                    self.trash_root
+                ));
+            }
+        }
+
+        std::fs::create_dir_all(trash_root)
+            .map_err(|e| anyhow::anyhow!("Failed to create trash root {}: {}", self.trash_root, e))?;
+        let probe = trash_root.join(".sdmm_write_test");
+        std::fs::write(&probe, b"")
+            .and_then(|_| std::fs::remove_file(&probe))
+            .map_err(|e| anyhow::anyhow!("Trash root {} is not writable: {}", self.trash_root, e))?;
+        Ok(())
+    }
+
+    /// Trash directory of a model path. `None` if the label is not in model paths.
+    pub fn trash_path(&self, label: &str) -> Option<PathBuf> {
+        let base_path = self.model_paths.get(label)?;
+        if self.trash_root.is_empty() {
+            Some(Path::new(base_path).join(&self.trash_dir))
+        } else {
+            Some(Path::new(&self.trash_root).join(label))
+        }
+    }
+
+    /// Check if the file is a model file by its extension
+    pub fn is_model_file(&self, path: &Path) -> bool {
+        let ext = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        self.model_extensions.contains(&ext)
+    }
+
+    /// Extension which new previews are saved with
+    pub fn preview_ext(&self) -> &str {
+        self.preview_extensions
+            .first()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_PREVIEW_EXTENSIONS[0])
+    }
+
+    pub fn is_preview_ext(&self, ext: &str) -> bool {
+        self.preview_extensions.iter().any(|e| e.eq_ignore_ascii_case(ext))
+    }
+
+    /// Files and directories with this name are not scanned or watched
+    pub fn is_ignored_name(&self, name: &str) -> bool {
+        if name == self.trash_dir {
+            return true;
+        }
+        !self.scan_hidden_dirs
+            && (name.starts_with('.') || SYSTEM_DIRS.iter().any(|dir| dir.eq_ignore_ascii_case(name)))
+    }
+
+    /// Find preview image of a model on disk by trying preview extensions in order
+    pub fn find_preview(&self, model_path: &Path) -> Option<PathBuf> {
+        self.preview_extensions
+            .iter()
+            .map(|ext| model_path.with_extension(ext))
+            .find(|preview| preview.is_file())
+    }
+
     /// Save config to file
     pub fn save(&self, config_path: &Path, force_overwrite: bool) -> anyhow::Result<()> {
         info!("Saving config file to: {:?}", config_path.display());
@@ -169,3 +786,103 @@ impl Config {
         file.write_all(ron_str.as_bytes()).map_err(|e| anyhow::anyhow!(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_files_are_matched_by_extension() {
+        let mut config = Config {
+            model_extensions: [".GGUF", "safetensors"].iter().map(|ext| ext.to_string()).collect(),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        assert!(config.is_model_file(Path::new("models/a.safetensors")));
+        assert!(config.is_model_file(Path::new("models/a.SafeTensors")));
+        assert!(config.is_model_file(Path::new("models/a.gguf")));
+        assert!(!config.is_model_file(Path::new("models/a.ckpt")));
+        assert!(!config.is_model_file(Path::new("models/a.safetensors.json")));
+        assert!(!config.is_model_file(Path::new("models/safetensors")));
+    }
+
+    #[test]
+    fn server_bind_overrides_listen_addr() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.bind_addr(),
+            format!("{DEFAULT_LISTEN_ADDR}:{DEFAULT_LISTEN_PORT}")
+        );
+
+        config.server.bind = " 127.0.0.1:8080 ".to_string();
+        config.validate().unwrap();
+        assert_eq!(config.bind_addr(), "127.0.0.1:8080");
+
+        for bind in ["127.0.0.1", ":8080", "localhost:http"] {
+            config.server.bind = bind.to_string();
+            assert!(config.validate().is_err(), "{bind}");
+        }
+    }
+
+    #[test]
+    fn api_keys_with_same_mask_are_restored_by_position() {
+        let mut current = Config::default();
+        current.civitai.api_keys = vec!["first-key-1234".to_string(), "second-key-1234".to_string()];
+        let mut submitted = current.redacted();
+        assert_eq!(submitted.civitai.api_keys[0], submitted.civitai.api_keys[1]);
+        submitted.civitai.api_keys.push("new-key".to_string());
+
+        submitted.restore_secrets(&current).unwrap();
+        assert_eq!(
+            submitted.civitai.api_keys,
+            ["first-key-1234", "second-key-1234", "new-key"]
+        );
+    }
+
+    #[test]
+    fn api_keys_are_restored_after_removing_and_reordering() {
+        let mut current = Config::default();
+        current.civitai.api_keys = ["key-aaaa", "long-key-1111", "long-key-2222", "long-key-3333"]
+            .map(String::from)
+            .to_vec();
+        let masked = current.redacted().civitai.api_keys;
+
+        // The second key is removed
+        let mut submitted = current.clone();
+        submitted.civitai.api_keys = vec![masked[0].clone(), masked[2].clone(), masked[3].clone()];
+        submitted.restore_secrets(&current).unwrap();
+        assert_eq!(
+            submitted.civitai.api_keys,
+            ["key-aaaa", "long-key-2222", "long-key-3333"]
+        );
+
+        // Keys are reversed
+        let mut submitted = current.clone();
+        submitted.civitai.api_keys = masked.iter().rev().cloned().collect();
+        submitted.restore_secrets(&current).unwrap();
+        assert_eq!(
+            submitted.civitai.api_keys,
+            ["long-key-3333", "long-key-2222", "long-key-1111", "key-aaaa"]
+        );
+    }
+
+    #[test]
+    fn unmatched_masked_api_key_is_rejected() {
+        let mut current = Config::default();
+        current.civitai.api_keys = ["long-key-1111", "long-key-2222", "other-key-1111"]
+            .map(String::from)
+            .to_vec();
+        let masked = current.redacted().civitai.api_keys;
+
+        // A mask of no saved key
+        let mut submitted = current.clone();
+        submitted.civitai.api_keys = vec!["****9999".to_string()];
+        assert!(submitted.restore_secrets(&current).is_err());
+
+        // Both unused keys have the same mask, so the moved key is ambiguous
+        let mut submitted = current.clone();
+        submitted.civitai.api_keys = vec![masked[1].clone(), masked[0].clone()];
+        assert!(submitted.restore_secrets(&current).is_err());
+    }
+}
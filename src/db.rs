@@ -3,25 +3,135 @@
 pub mod item;
 pub mod tag;
 pub mod job;
+pub mod audit;
+pub mod event;
 
-use crate::config::DBConfig;
-use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
-use sqlx::SqlitePool;
+use crate::config::{DBConfig, JournalMode, Synchronous};
+use crate::db::item::CountCache;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use sqlx::{Sqlite, SqlitePool, Transaction};
 use std::str::FromStr;
+use std::time::Duration;
 
 pub struct DBPool {
     pub sqlite_pool: SqlitePool,
+    pub count_cache: CountCache,
 }
 
 impl DBPool {
     pub async fn init(config: &DBConfig) -> anyhow::Result<Self> {
+        let journal_mode = match config.sqlite.journal_mode {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off,
+        };
+        let synchronous = match config.sqlite.synchronous {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        };
         let opts = SqliteConnectOptions::from_str(&config.sqlite.db_path)?
             .foreign_keys(true)
-            .journal_mode(SqliteJournalMode::Wal)
+            .journal_mode(journal_mode)
+            .busy_timeout(Duration::from_millis(config.sqlite.busy_timeout_ms))
+            .synchronous(synchronous)
             .create_if_missing(true);
         let sqlite_pool = SqlitePool::connect_with(opts).await?;
         sqlx::migrate!("./migrations").run(&sqlite_pool).await?;
 
-        Ok(Self { sqlite_pool })
+        Ok(Self {
+            sqlite_pool,
+            count_cache: CountCache::default(),
+        })
+    }
+
+    /// Begin a transaction which takes the write lock at once. A deferred transaction which reads before it writes
+    /// fails with "database is locked" without waiting for `busy_timeout` if another connection writes meanwhile.
+    pub async fn begin_write(&self) -> Result<Transaction<'static, Sqlite>, sqlx::Error> {
+        self.sqlite_pool.begin_with("BEGIN IMMEDIATE").await
+    }
+}
+
+#[cfg(test)]
+impl DBPool {
+    /// Empty in-memory database with all migrations applied
+    pub async fn memory() -> Self {
+        let opts = SqliteConnectOptions::from_str("sqlite::memory:").unwrap().foreign_keys(true);
+        // Each connection has its own in-memory database, so keep only one open
+        let sqlite_pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(opts)
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&sqlite_pool).await.unwrap();
+
+        Self {
+            sqlite_pool,
+            count_cache: CountCache::default(),
+        }
+    }
+}
+
+/// Insert a lora item named after its path and return its id
+#[cfg(test)]
+pub async fn insert_test_item(db_pool: &DBPool, path: &str, blake3: &str, updated_at_ms: i64) -> i64 {
+    let item = item::ItemUpsert {
+        name: Some(path),
+        model_name: "",
+        path,
+        base_label: "test",
+        blake3,
+        sha256: None,
+        source_url: None,
+        updated_at_ms,
+        model_type: "lora",
+    };
+    item::insert_or_update(db_pool, &item).await.unwrap().id()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SQLiteConfig;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes_wait_for_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DBConfig {
+            sqlite: SQLiteConfig {
+                db_path: dir.path().join("sdmm.sqlite").to_string_lossy().to_string(),
+                ..Default::default()
+            },
+        };
+        let db_pool = std::sync::Arc::new(DBPool::init(&config).await.unwrap());
+
+        let mut handles = Vec::new();
+        for task in 0..8 {
+            let db_pool = db_pool.clone();
+            handles.push(tokio::spawn(async move {
+                for i in 0..20 {
+                    let path = format!("{task}/{i}.safetensors");
+                    let id = insert_test_item(&db_pool, &path, &path, i).await;
+                    // A transaction which reads before it writes
+                    tag::update_tags_and_note(&db_pool, id, &format!("style task_{task}"), "note").await?;
+                }
+                Ok::<_, sqlx::Error>(())
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM item")
+            .fetch_one(&db_pool.sqlite_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 160);
     }
 }
@@ -0,0 +1,85 @@
+//! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+pub enum AuditAction {
+    Delete,
+    UpdateTags,
+    UpdateNote,
+    Download,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Delete => "delete",
+            AuditAction::UpdateTags => "update-tags",
+            AuditAction::UpdateNote => "update-note",
+            AuditAction::Download => "download",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub action: String,
+    pub item: Option<i64>,
+    /// Basic auth user who made the change
+    pub actor: Option<String>,
+    pub detail: String,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+pub async fn add(
+    pool: &SqlitePool,
+    action: AuditAction,
+    item: Option<i64>,
+    actor: Option<&str>,
+    detail: &str,
+) -> Result<(), sqlx::Error> {
+    let action = action.as_str();
+    sqlx::query!(
+        "INSERT INTO audit_log (action, item, actor, detail) VALUES (?, ?, ?, ?)",
+        action,
+        item,
+        actor,
+        detail
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// History of `item`, newest first. History of all items if `item` is `None`.
+pub async fn get(
+    pool: &SqlitePool,
+    item: Option<i64>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<AuditEntry>, i64), sqlx::Error> {
+    let entries = sqlx::query_as!(
+        AuditEntry,
+        r#"SELECT id, action, item, actor, detail, created_at FROM audit_log
+            WHERE ? IS NULL OR item = ?
+            ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?"#,
+        item,
+        item,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let total = sqlx::query_scalar!(
+        "SELECT count(id) FROM audit_log WHERE ? IS NULL OR item = ?",
+        item,
+        item
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((entries, total))
+}
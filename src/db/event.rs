@@ -0,0 +1,42 @@
+//! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
+
+use sqlx::SqlitePool;
+
+pub struct EventLog {
+    pub level: i64,
+    pub msg: String,
+    pub created_at: i64,
+}
+
+/// Save an event and keep only the newest `keep` events.
+pub async fn add(pool: &SqlitePool, level: i64, msg: &str, created_at: i64, keep: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO event_log (level, msg, created_at) VALUES (?, ?, ?)",
+        level,
+        msg,
+        created_at
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM event_log WHERE id NOT IN (SELECT id FROM event_log ORDER BY id DESC LIMIT ?)",
+        keep
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Newest `limit` events, oldest first.
+pub async fn get_recent(pool: &SqlitePool, limit: i64) -> Result<Vec<EventLog>, sqlx::Error> {
+    let mut events = sqlx::query_as!(
+        EventLog,
+        "SELECT level, msg, created_at FROM event_log ORDER BY id DESC LIMIT ?",
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    events.reverse();
+    Ok(events)
+}
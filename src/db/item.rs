@@ -1,8 +1,23 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
-use indexmap::IndexSet;
-use sqlx::sqlite::SqliteQueryResult;
-use sqlx::SqlitePool;
+use crate::db;
+use crate::db::tag::TagCount;
+use crate::db::DBPool;
+use parking_lot::Mutex;
+use serde::Serialize;
+use sqlx::sqlite::{SqliteArguments, SqliteQueryResult};
+use sqlx::{Arguments, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Total count of search results by search key, with the time it was counted
+#[derive(Default)]
+pub struct CountCache {
+    counts: Mutex<HashMap<String, (i64, Instant)>>,
+    /// Changed on every invalidation, so that a count which was started before a write is not cached after it
+    generation: AtomicU64,
+}
 
 #[derive(sqlx::FromRow, Eq, PartialEq, Hash)]
 pub struct Item {
@@ -11,74 +26,379 @@ pub struct Item {
     pub path: String,
     pub base_label: String,
     pub note: String,
+    pub updated_at: Option<i64>,
+    pub blake3: String,
+    pub model_type: String,
+    pub nsfw_level: Option<i64>,
+    /// Name of the model on Civitai. Empty if it's unknown.
+    pub model_name: String,
+    /// Gallery image which is pinned as the main preview. 0 is the original preview.
+    pub preview_index: i64,
 }
 
-pub async fn mark_obsolete_all(pool: &SqlitePool) -> Result<SqliteQueryResult, sqlx::Error> {
-    sqlx::query!(r#"UPDATE item SET is_checked = false WHERE is_checked = true AND path != ''"#)
-        .execute(pool)
-        .await
+impl CountCache {
+    /// Must be called whenever items or their tags are changed, after the change is committed
+    pub fn invalidate(&self) {
+        let mut counts = self.counts.lock();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        counts.clear();
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    fn get(&self, key: &str, ttl: Duration) -> Option<i64> {
+        let mut counts = self.counts.lock();
+        match counts.get(key) {
+            Some((count, counted_at)) if counted_at.elapsed() < ttl => Some(*count),
+            Some(_) => {
+                counts.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache `count` unless the cache was invalidated since `generation`, then the count may be stale already
+    fn insert(&self, key: String, count: i64, generation: u64) {
+        let mut counts = self.counts.lock();
+        if self.generation() == generation {
+            counts.insert(key, (count, Instant::now()));
+        }
+    }
 }
 
-/// Return (path, label)
-pub async fn mark_obsolete(pool: &SqlitePool, id: i64) -> Result<(String, String), sqlx::Error> {
-    sqlx::query!(r#"UPDATE item SET is_checked = false WHERE id = ?"#, id)
-        .execute(pool)
+pub async fn mark_obsolete_all(db_pool: &DBPool) -> Result<SqliteQueryResult, sqlx::Error> {
+    let result = sqlx::query!(r#"UPDATE item SET is_checked = false WHERE is_checked = true AND path != ''"#)
+        .execute(&db_pool.sqlite_pool)
         .await?;
+    db_pool.count_cache.invalidate();
+    Ok(result)
+}
 
-    struct Temp {
-        path: String,
-        base_label: String,
+/// Mark items as deleted in one transaction. Return (path, base label) of each marked item by its id.
+pub async fn mark_obsolete(db_pool: &DBPool, ids: &[i64]) -> Result<HashMap<i64, (String, String)>, sqlx::Error> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
     }
-    let ret = sqlx::query_as!(Temp, r#"SELECT path, base_label FROM item WHERE id = ?"#, id)
-        .fetch_one(pool)
-        .await?;
 
-    Ok((ret.path, ret.base_label))
+    let placeholders = vec!["?"; ids.len()].join(",");
+    let query = format!(
+        "UPDATE item SET is_checked = false, deleted_at = strftime('%s', 'now') WHERE id IN ({placeholders})
+        RETURNING id, path, base_label"
+    );
+    let mut args = SqliteArguments::default();
+    for id in ids {
+        args.add(*id).map_err(sqlx::Error::Encode)?;
+    }
+
+    let mut tx = db_pool.begin_write().await?;
+    let marked: Vec<(i64, String, String)> = sqlx::query_as_with(&query, args).fetch_all(&mut *tx).await?;
+    tx.commit().await?;
+    db_pool.count_cache.invalidate();
+
+    Ok(marked
+        .into_iter()
+        .map(|(id, path, base_label)| (id, (path, base_label)))
+        .collect())
 }
 
-pub async fn insert_or_update(
-    pool: &SqlitePool,
-    name: Option<&str>,
+/// Undo `mark_obsolete` for items whose files could not be moved to trash
+pub async fn unmark_obsolete(db_pool: &DBPool, ids: &[i64]) -> Result<(), sqlx::Error> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let placeholders = vec!["?"; ids.len()].join(",");
+    let query = format!("UPDATE item SET is_checked = true, deleted_at = NULL WHERE id IN ({placeholders})");
+    let mut args = SqliteArguments::default();
+    for id in ids {
+        args.add(*id).map_err(sqlx::Error::Encode)?;
+    }
+    sqlx::query_with(&query, args).execute(&db_pool.sqlite_pool).await?;
+    db_pool.count_cache.invalidate();
+    Ok(())
+}
+
+pub async fn mark_obsolete_by_path(
+    db_pool: &DBPool,
     path: &str,
     base_label: &str,
-    blake3: &str,
-    updated_at_ms: i64,
-) -> Result<i64, sqlx::Error> {
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE item SET is_checked = false WHERE path = ? AND base_label = ?"#,
+        path,
+        base_label
+    )
+    .execute(&db_pool.sqlite_pool)
+    .await?;
+    db_pool.count_cache.invalidate();
+    Ok(result)
+}
+
+/// Result of `insert_or_update` with the item id
+pub enum UpsertResult {
+    Inserted(i64),
+    Updated(i64),
+    /// Same file as before, only marked as checked
+    Unchanged(i64),
+}
+
+impl UpsertResult {
+    pub fn id(&self) -> i64 {
+        match self {
+            UpsertResult::Inserted(id) | UpsertResult::Updated(id) | UpsertResult::Unchanged(id) => *id,
+        }
+    }
+}
+
+/// Fields of an item which are written by [`insert_or_update`]
+pub struct ItemUpsert<'a> {
+    pub name: Option<&'a str>,
+    /// Name of the model on Civitai
+    pub model_name: &'a str,
+    pub path: &'a str,
+    pub base_label: &'a str,
+    pub blake3: &'a str,
+    pub sha256: Option<&'a str>,
+    /// Kept if it's `None`
+    pub source_url: Option<&'a str>,
+    /// Modified time of the file
+    pub updated_at_ms: i64,
+    pub model_type: &'a str,
+}
+
+/// Stored fields of an item which are compared by [`insert_or_update`]
+#[derive(sqlx::FromRow)]
+struct StoredItem {
+    id: i64,
+    /// Checked and not deleted
+    is_checked: bool,
+    name: Option<String>,
+    model_name: String,
+    blake3: String,
+    sha256: Option<String>,
+    source_url: Option<String>,
+    updated_at: Option<i64>,
+    model_type: String,
+}
+
+/// Insert or update an item in one write transaction, so that a concurrent scan doesn't change it in between.
+/// An unchanged item is only marked as checked. Counts are not invalidated for it, so a caller which checks many
+/// unchanged items again invalidates them once when it's done.
+pub async fn insert_or_update(db_pool: &DBPool, item: &ItemUpsert<'_>) -> Result<UpsertResult, sqlx::Error> {
+    let mut tx = db_pool.begin_write().await?;
+    let previous: Option<StoredItem> = sqlx::query_as(
+        r#"SELECT id, is_checked AND deleted_at IS NULL AS is_checked, name, model_name, blake3, sha256, source_url,
+            updated_at, model_type
+        FROM item WHERE path = ? AND base_label = ?"#,
+    )
+    .bind(item.path)
+    .bind(item.base_label)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let unchanged = previous.as_ref().is_some_and(|prev| {
+        prev.blake3 == item.blake3
+            && prev.updated_at == Some(item.updated_at_ms)
+            && prev.name.as_deref() == item.name
+            && prev.model_name == item.model_name
+            && prev.model_type == item.model_type
+            && (item.sha256.is_none() || prev.sha256.as_deref() == item.sha256)
+            && (item.source_url.is_none() || prev.source_url.as_deref() == item.source_url)
+    });
+    if let Some(prev) = &previous
+        && prev.is_checked
+        && unchanged
+    {
+        return Ok(UpsertResult::Unchanged(prev.id));
+    }
+
     let ret_id = sqlx::query!(
         r#"
-        INSERT INTO item (name, path, base_label, blake3, updated_at) VALUES (?, ?, ?, ?, ?)
+        INSERT INTO item (name, model_name, path, base_label, blake3, sha256, source_url, updated_at, model_type)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
         ON CONFLICT (path, base_label) DO UPDATE SET
             is_checked=true,
             blake3=excluded.blake3,
+            sha256=excluded.sha256,
+            source_url=COALESCE(excluded.source_url, source_url),
             base_label=excluded.base_label,
             name=excluded.name,
-            updated_at = excluded.updated_at
+            model_name=excluded.model_name,
+            updated_at = excluded.updated_at,
+            model_type = excluded.model_type,
+            deleted_at = NULL
         RETURNING id"#,
-        name,
+        item.name,
+        item.model_name,
+        item.path,
+        item.base_label,
+        item.blake3,
+        item.sha256,
+        item.source_url,
+        item.updated_at_ms,
+        item.model_type,
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .id;
+    tx.commit().await?;
+
+    let ret = match previous {
+        None => UpsertResult::Inserted(ret_id),
+        Some(_) if unchanged => UpsertResult::Unchanged(ret_id),
+        Some(_) => UpsertResult::Updated(ret_id),
+    };
+    if !matches!(ret, UpsertResult::Unchanged(_)) {
+        db_pool.count_cache.invalidate();
+    }
+    Ok(ret)
+}
+
+/// Stored (BLAKE3, SHA256) of a file which is not modified since it was indexed
+pub async fn get_unchanged_hashes(
+    pool: &SqlitePool,
+    path: &str,
+    base_label: &str,
+    updated_at_ms: i64,
+) -> Result<Option<(String, Option<String>)>, sqlx::Error> {
+    let hashes = sqlx::query!(
+        r#"SELECT blake3, sha256 FROM item WHERE path = ? AND base_label = ? AND updated_at = ? AND blake3 != ''"#,
         path,
         base_label,
+        updated_at_ms
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(hashes.map(|h| (h.blake3, h.sha256)))
+}
+
+pub async fn get_source_url(pool: &SqlitePool, id: i64) -> Result<Option<String>, sqlx::Error> {
+    let source_url = sqlx::query_scalar!(r#"SELECT source_url FROM item WHERE id = ?"#, id)
+        .fetch_one(pool)
+        .await?;
+    Ok(source_url)
+}
+
+/// Keep the source which is already known, e.g. Civitai page from metadata
+pub async fn set_source_url_if_missing(pool: &SqlitePool, id: i64, source_url: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET source_url = ? WHERE id = ? AND source_url IS NULL"#,
+        source_url,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Trigger words from a `.txt` file next to the model, separated by commas
+pub async fn get_trigger_words(pool: &SqlitePool, id: i64) -> Result<Option<String>, sqlx::Error> {
+    let trigger_words = sqlx::query_scalar!(r#"SELECT trigger_words FROM item WHERE id = ?"#, id)
+        .fetch_one(pool)
+        .await?;
+    Ok(trigger_words)
+}
+
+/// Return whether the stored trigger words are changed
+pub async fn set_trigger_words(pool: &SqlitePool, id: i64, trigger_words: Option<&str>) -> Result<bool, sqlx::Error> {
+    let changed = sqlx::query("UPDATE item SET trigger_words = ? WHERE id = ? AND trigger_words IS NOT ?")
+        .bind(trigger_words)
+        .bind(id)
+        .bind(trigger_words)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(changed > 0)
+}
+
+pub async fn set_preview_index(pool: &SqlitePool, id: i64, preview_index: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"UPDATE item SET preview_index = ? WHERE id = ?"#, preview_index, id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_sha256(pool: &SqlitePool, id: i64) -> Result<Option<String>, sqlx::Error> {
+    let sha256 = sqlx::query_scalar!(r#"SELECT sha256 FROM item WHERE id = ?"#, id)
+        .fetch_one(pool)
+        .await?;
+    Ok(sha256)
+}
+
+/// Keep the stored SHA256 if `sha256` is `None`
+pub async fn set_hashes(db_pool: &DBPool, id: i64, blake3: &str, sha256: Option<&str>) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET blake3 = ?, sha256 = COALESCE(?, sha256) WHERE id = ?"#,
         blake3,
-        updated_at_ms,
+        sha256,
+        id
     )
-    .fetch_one(pool)
-    .await?
-    .id;
+    .execute(&db_pool.sqlite_pool)
+    .await?;
+    db_pool.count_cache.invalidate();
+    Ok(())
+}
+
+/// `None` means unknown
+pub async fn set_nsfw_level(db_pool: &DBPool, id: i64, nsfw_level: Option<i64>) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"UPDATE item SET nsfw_level = ? WHERE id = ?"#, nsfw_level, id)
+        .execute(&db_pool.sqlite_pool)
+        .await?;
+    db_pool.count_cache.invalidate();
+    Ok(())
+}
+
+/// List items which were deleted by user before `deleted_before` (unix timestamp in seconds)
+pub async fn list_deleted_before(pool: &SqlitePool, deleted_before: i64) -> Result<Vec<Item>, sqlx::Error> {
+    sqlx::query_as!(
+        Item,
+        r#"SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index
+        FROM item WHERE is_checked = false AND deleted_at < ?"#,
+        deleted_before
+    )
+    .fetch_all(pool)
+    .await
+}
 
-    Ok(ret_id)
+pub async fn delete(db_pool: &DBPool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM item WHERE id = ?"#, id)
+        .execute(&db_pool.sqlite_pool)
+        .await?;
+    db_pool.count_cache.invalidate();
+    Ok(())
 }
 
-pub async fn clean(pool: &SqlitePool) -> Result<u64, sqlx::Error> {
+pub async fn clean(db_pool: &DBPool) -> Result<u64, sqlx::Error> {
     let count = sqlx::query!(r#"DELETE FROM item WHERE is_checked = false"#)
-        .execute(pool)
+        .execute(&db_pool.sqlite_pool)
+        .await?
+        .rows_affected();
+    db_pool.count_cache.invalidate();
+    Ok(count)
+}
+
+/// Move items and previews of label `old` to label `new`. Return number of moved items.
+pub async fn rename_label(db_pool: &DBPool, old: &str, new: &str) -> Result<u64, sqlx::Error> {
+    let mut tx = db_pool.begin_write().await?;
+    let count = sqlx::query!("UPDATE item SET base_label = ? WHERE base_label = ?", new, old)
+        .execute(&mut *tx)
         .await?
         .rows_affected();
+    sqlx::query!("UPDATE preview SET base_label = ? WHERE base_label = ?", new, old)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    db_pool.count_cache.invalidate();
     Ok(count)
 }
 
 pub async fn get_by_id(pool: &SqlitePool, id: i64) -> Result<Item, sqlx::Error> {
     let item = sqlx::query_as!(
         Item,
-        "SELECT id, name, path, base_label, note FROM item WHERE id = ?",
+        "SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index FROM item WHERE id = ?",
         id
     )
     .fetch_one(pool)
@@ -87,106 +407,872 @@ pub async fn get_by_id(pool: &SqlitePool, id: i64) -> Result<Item, sqlx::Error>
     Ok(item)
 }
 
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    pub tag_only: bool,
+    pub duplicate_only: bool,
+    /// Only the oldest matched item of each group of identical files. Items which are not hashed yet are not grouped.
+    pub group_duplicates: bool,
+    /// Only items which are updated at or after this time (epoch millis)
+    pub added_after: Option<i64>,
+    /// Only items which are updated before this time (epoch millis)
+    pub added_before: Option<i64>,
+    pub model_type: Option<String>,
+    /// Only items whose NSFW level is at most this
+    pub max_nsfw_level: Option<i64>,
+    /// Include items without NSFW level when `max_nsfw_level` is set
+    pub show_unknown_nsfw: bool,
+    /// Only items in these model paths. All if empty.
+    pub base_labels: Vec<String>,
+    /// Only items whose file has this extension. Lowercase without the leading dot.
+    pub ext: Option<String>,
+    /// Only items without any tag
+    pub untagged_only: bool,
+}
+
+/// Plan and duration of a query which is run by [`search`]
+#[derive(Serialize)]
+pub struct QueryDebug {
+    /// `items`, `name_count`, `tag_count` or `count`
+    pub query: String,
+    pub sql: String,
+    /// Details of `EXPLAIN QUERY PLAN`
+    pub plan: Vec<String>,
+    pub millis: f64,
+}
+
+/// `after` is the `(updated_at, id)` of the last seen item. If it's set, items are paginated by keyset instead of
+/// `offset`.
+///
+/// Total count is cached for `count_ttl`. If `debug` is set, counts are not cached and plans of all queries are
+/// pushed to it.
+#[allow(clippy::too_many_arguments)]
 pub async fn search(
-    pool: &SqlitePool,
+    db_pool: &DBPool,
     search: &str,
     limit: i64,
     offset: i64,
-    tag_only: bool,
-    duplicate_only: bool,
+    after: Option<(i64, i64)>,
+    filter: &SearchFilter,
+    count_ttl: Duration,
+    mut debug: Option<&mut Vec<QueryDebug>>,
 ) -> Result<(Vec<Item>, i64), sqlx::Error> {
     //TODO: Search in note too
-    let mut items = IndexSet::new();
+    let pool = &db_pool.sqlite_pool;
+    let mut selects = Vec::new();
+    // Arguments of the page query, which has the conditions of each branch
+    let mut page_args = SqliteArguments::default();
     let mut count = 0;
-    let mut exclude_name = String::new();
+    let count_key = format!("{}|{:?}", search.to_lowercase(), filter);
+    let count_generation = db_pool.count_cache.generation();
+    let cached_count = match debug {
+        Some(_) => None,
+        None => db_pool.count_cache.get(&count_key, count_ttl),
+    };
+    // Items matched by name are not matched by tags again
+    let mut exclude_search = None;
 
-    let duplicate_cond = if duplicate_only {
-        "AND blake3 IN (
-            SELECT blake3 FROM item
-            WHERE is_checked = true
-            GROUP BY blake3
-            HAVING COUNT(*) > 1
-        )"
-    } else {
-        ""
+    let (keyset_cond, offset) = match after {
+        Some((updated_at, id)) => (
+            format!("AND (COALESCE(item.updated_at, 0), item.id) < ({}, {})", updated_at, id),
+            0,
+        ),
+        None => (String::new(), offset),
     };
+    // Copies are grouped over all matched items before the page is cut, so the cursor is applied after grouping
+    let (keyset_cond, group_keyset_cond) = match (filter.group_duplicates, after) {
+        (true, Some((updated_at, id))) => (String::new(), format!("AND (sort_key, id) < ({}, {})", updated_at, id)),
+        _ => (keyset_cond, String::new()),
+    };
+    // Matched ids and hashes to count when copies are grouped
+    let mut group_count_selects = Vec::new();
+    let mut group_count_args = SqliteArguments::default();
 
-    if !tag_only {
-        let cond = format!(
-            "FROM item
-            WHERE is_checked = true
-                AND (name COLLATE NOCASE LIKE '%' || '{}' || '%'
-                  OR model_name COLLATE NOCASE LIKE '%' || '{}' || '%')
-                {}",
-            search, search, &duplicate_cond,
+    if !filter.tag_only {
+        selects.push(format!(
+            "SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index,
+                COALESCE(updated_at, 0) as sort_key
+            {} {}",
+            name_condition(search, filter, &mut page_args)?,
+            keyset_cond
+        ));
+
+        if cached_count.is_none() && filter.group_duplicates {
+            group_count_selects.push(format!(
+                "SELECT id, blake3 {}",
+                name_condition(search, filter, &mut group_count_args)?
+            ));
+        } else if cached_count.is_none() {
+            let mut count_args = SqliteArguments::default();
+            let count_query = format!("SELECT count(id) {}", name_condition(search, filter, &mut count_args)?);
+            let started = Instant::now();
+            let count_by_name: i64 = sqlx::query_scalar_with(&count_query, count_args.clone())
+                .fetch_one(pool)
+                .await?;
+            if let Some(debug) = debug.as_deref_mut() {
+                debug.push(explain(pool, "name_count", &count_query, count_args, started.elapsed()).await?);
+            }
+            count += count_by_name;
+        }
+
+        exclude_search = Some(search);
+    }
+
+    let tags = search_tags(pool, search).await?;
+    if !tags.is_empty() {
+        selects.push(format!(
+            "SELECT item.id as id, item.name as name, item.path as path, item.base_label as base_label,
+                item.note as note, item.updated_at as updated_at, item.blake3 as blake3,
+                item.model_type as model_type, item.nsfw_level as nsfw_level, item.model_name as model_name,
+                item.preview_index as preview_index, COALESCE(item.updated_at, 0) as sort_key
+            {}",
+            tags_condition(&tags, exclude_search, filter, &keyset_cond, &mut page_args)?
+        ));
+
+        if cached_count.is_none() && filter.group_duplicates {
+            group_count_selects.push(format!(
+                "SELECT item.id as id, item.blake3 as blake3 {}",
+                tags_condition(&tags, exclude_search, filter, "", &mut group_count_args)?
+            ));
+        } else if cached_count.is_none() {
+            let mut count_args = SqliteArguments::default();
+            let count_query = format!(
+                "SELECT COUNT(*) FROM (SELECT item.id {})",
+                tags_condition(&tags, exclude_search, filter, "", &mut count_args)?
+            );
+            let started = Instant::now();
+            let tags_count: i64 = sqlx::query_scalar_with(&count_query, count_args.clone())
+                .fetch_one(pool)
+                .await?;
+            if let Some(debug) = debug.as_deref_mut() {
+                debug.push(explain(pool, "tag_count", &count_query, count_args, started.elapsed()).await?);
+            }
+            count += tags_count;
+        }
+    }
+
+    if !group_count_selects.is_empty() {
+        let count_query = format!(
+            "SELECT COUNT(*) FROM ({})",
+            group_copies(&group_count_selects.join(" UNION ALL "))
         );
+        let started = Instant::now();
+        count = sqlx::query_scalar_with(&count_query, group_count_args.clone())
+            .fetch_one(pool)
+            .await?;
+        if let Some(debug) = debug.as_deref_mut() {
+            debug.push(explain(pool, "count", &count_query, group_count_args, started.elapsed()).await?);
+        }
+    }
+
+    // Items matched by name or by tags are paginated together, so a page has at most `limit` items. Items matched by
+    // name are excluded from tag matches, so `UNION ALL` has no duplicates. They are sorted by the same key as the
+    // cursor, so items without `updated_at` keep their order across pages. Name matches are read in the order of
+    // `item_is_checked_updated_at_index` and merged with tag matches without sorting all items.
+    let items = if selects.is_empty() {
+        Vec::new()
+    } else {
+        let matched = selects.join(" UNION ALL ");
+        let matched = match filter.group_duplicates {
+            true => format!("{} {}", group_copies(&matched), group_keyset_cond),
+            false => matched,
+        };
         let query = format!(
-            "SELECT id,name, path, base_label, note
-            {}
-            ORDER BY updated_at DESC
+            "{}
+            ORDER BY sort_key DESC, id DESC
             LIMIT {} OFFSET {}",
-            &cond, limit, offset
+            matched, limit, offset
         );
-        let items_by_name = sqlx::query_as(&query).fetch_all(pool).await?;
+        let started = Instant::now();
+        let items: Vec<Item> = sqlx::query_as_with(&query, page_args.clone()).fetch_all(pool).await?;
+        if let Some(debug) = debug {
+            debug.push(explain(pool, "items", &query, page_args, started.elapsed()).await?);
+        }
+        items
+    };
 
-        let count_query = format!("SELECT count(id) {}", &cond);
-        let count_by_name: i64 = sqlx::query_scalar(&count_query).fetch_one(pool).await?;
+    let count = match cached_count {
+        Some(count) => count,
+        None => {
+            db_pool.count_cache.insert(count_key, count, count_generation);
+            count
+        }
+    };
 
-        exclude_name = format!(
-            "AND NOT (item.name COLLATE NOCASE LIKE '%{}%'
-                      OR item.model_name COLLATE NOCASE LIKE '%{}%')",
-            &search, &search
-        );
+    Ok((items, count))
+}
 
-        items.extend(items_by_name);
-        count += count_by_name;
+/// Only the lowest id of each group of identical files among `matched` rows, which have `id` and `blake3` columns.
+/// Items which are not hashed yet are a group of one.
+fn group_copies(matched: &str) -> String {
+    format!(
+        "SELECT * FROM (
+            SELECT *, ROW_NUMBER() OVER (
+                PARTITION BY blake3, CASE WHEN blake3 = '' THEN id END ORDER BY id
+            ) AS copy_rank
+            FROM ({matched})
+        ) WHERE copy_rank = 1"
+    )
+}
+
+/// Run `EXPLAIN QUERY PLAN` of `sql`, which took `elapsed` to run
+async fn explain(
+    pool: &SqlitePool,
+    query: &str,
+    sql: &str,
+    args: SqliteArguments<'_>,
+    elapsed: Duration,
+) -> Result<QueryDebug, sqlx::Error> {
+    let explain_sql = format!("EXPLAIN QUERY PLAN {sql}");
+    let rows: Vec<(i64, i64, i64, String)> = sqlx::query_as_with(&explain_sql, args).fetch_all(pool).await?;
+    Ok(QueryDebug {
+        query: query.to_string(),
+        sql: sql.to_string(),
+        plan: rows.into_iter().map(|(_, _, _, detail)| detail).collect(),
+        millis: elapsed.as_secs_f64() * 1000.0,
+    })
+}
+
+/// Get items in the order of `ids`. Ids which do not exist are skipped.
+pub async fn get_by_ids(pool: &SqlitePool, ids: &[i64]) -> Result<Vec<Item>, sqlx::Error> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
     }
 
-    let tags: Vec<String> = search
-        .split_whitespace()
-        .map(|s| s.to_string().to_lowercase())
+    let placeholders = vec!["?"; ids.len()].join(",");
+    let query = format!(
+        "SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index
+        FROM item WHERE id IN ({placeholders})"
+    );
+    let mut args = SqliteArguments::default();
+    for id in ids {
+        args.add(*id).map_err(sqlx::Error::Encode)?;
+    }
+    let mut items: HashMap<i64, Item> = sqlx::query_as_with(&query, args)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|item: Item| (item.id, item))
         .collect();
 
+    Ok(ids.iter().filter_map(|id| items.remove(id)).collect())
+}
+
+/// Valid items whose base label is not one of `labels`
+pub async fn list_by_other_labels(pool: &SqlitePool, labels: &[&String]) -> Result<Vec<Item>, sqlx::Error> {
+    let placeholders = vec!["?"; labels.len()].join(",");
+    let query = format!(
+        "SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index
+        FROM item WHERE is_checked = true AND base_label NOT IN ({placeholders})
+        ORDER BY base_label, path"
+    );
+    let mut args = SqliteArguments::default();
+    for label in labels {
+        args.add(label.as_str()).map_err(sqlx::Error::Encode)?;
+    }
+    sqlx::query_as_with(&query, args).fetch_all(pool).await
+}
+
+/// Number of valid items with each of `hashes`. Hashes without any item are not returned.
+pub async fn count_by_hashes(pool: &SqlitePool, hashes: &HashSet<String>) -> Result<HashMap<String, i64>, sqlx::Error> {
+    if hashes.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let placeholders = vec!["?"; hashes.len()].join(",");
+    let query = format!(
+        "SELECT blake3, COUNT(id) FROM item
+        WHERE is_checked = true AND blake3 IN ({placeholders})
+        GROUP BY blake3"
+    );
+    let mut args = SqliteArguments::default();
+    for hash in hashes {
+        args.add(hash.clone()).map_err(sqlx::Error::Encode)?;
+    }
+    let counts: Vec<(String, i64)> = sqlx::query_as_with(&query, args).fetch_all(pool).await?;
+    Ok(counts.into_iter().collect())
+}
+
+/// Return a page of tag counts, most used first, and the total number of tags. Tags are counted over all items
+/// matched by `search` and `filter`, not only over a page of items. All tags from `offset` are returned if `limit`
+/// is `None`.
+pub async fn search_facets(
+    pool: &SqlitePool,
+    search: &str,
+    filter: &SearchFilter,
+    limit: Option<i64>,
+    offset: i64,
+) -> Result<(Vec<TagCount>, i64), sqlx::Error> {
+    let mut args = SqliteArguments::default();
+    let mut matched = Vec::new();
+    if !filter.tag_only {
+        matched.push(format!(
+            "SELECT id, blake3 {}",
+            name_condition(search, filter, &mut args)?
+        ));
+    }
+
+    let tags = search_tags(pool, search).await?;
     if !tags.is_empty() {
-        let condition = format!(
-            "FROM item
-          LEFT JOIN tag_item ON item.id = tag_item.item
-          LEFT JOIN tag ON tag.id = tag_item.tag
-          WHERE item.is_checked = true
-            AND tag.name IN ('{}')
-            {}
-            {}
-          GROUP BY item.id
-          HAVING COUNT(DISTINCT tag.id) = {}",
-            tags.join("','"),
-            &exclude_name,
-            &duplicate_cond,
-            tags.len()
-        );
-        let query = format!(
-            "SELECT item.id as id, item.name as name, item.note as note, item.path as path, item.base_label as base_label
-            {}
-            ORDER BY item.updated_at DESC LIMIT {} OFFSET {}",
-            condition, limit, offset
+        matched.push(format!(
+            "SELECT item.id as id, item.blake3 as blake3 {}",
+            tags_condition(&tags, None, filter, "", &mut args)?
+        ));
+    }
+
+    if matched.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+    let matched = match filter.group_duplicates {
+        true => group_copies(&matched.join(" UNION ")),
+        false => matched.join(" UNION "),
+    };
+
+    let count_query = format!(
+        "SELECT COUNT(DISTINCT tag_item.tag) FROM ({}) as matched JOIN tag_item ON tag_item.item = matched.id",
+        matched
+    );
+    let total: i64 = sqlx::query_scalar_with(&count_query, args.clone())
+        .fetch_one(pool)
+        .await?;
+
+    // Negative limit means no limit in SQLite
+    args.add(limit.unwrap_or(-1)).map_err(sqlx::Error::Encode)?;
+    args.add(offset).map_err(sqlx::Error::Encode)?;
+    let query = format!(
+        "SELECT tag.name as tag, COUNT(tag_item.item) as count,
+                COALESCE(tag_type.type, 'Uncategorized') as category, tag.color as color
+            FROM ({}) as matched
+            JOIN tag_item ON tag_item.item = matched.id
+            JOIN tag ON tag.id = tag_item.tag
+            LEFT JOIN tag_type ON tag_type.id = tag.type
+            GROUP BY tag.id ORDER BY count DESC, tag.name LIMIT ? OFFSET ?",
+        matched
+    );
+    let tags = sqlx::query_as_with(&query, args).fetch_all(pool).await?;
+    Ok((tags, total))
+}
+
+/// SQL conditions of `filter`. Their arguments are appended to `args`.
+fn filter_condition(filter: &SearchFilter, args: &mut SqliteArguments) -> Result<String, sqlx::Error> {
+    let mut filter_cond = String::new();
+    if filter.duplicate_only {
+        filter_cond.push_str(
+            " AND blake3 IN (
+            SELECT blake3 FROM item
+            WHERE is_checked = true
+            GROUP BY blake3
+            HAVING COUNT(*) > 1
+        )",
         );
-        let search_by_tags: Vec<Item> = sqlx::query_as(&query).fetch_all(pool).await?;
+    }
+    if filter.untagged_only {
+        filter_cond.push_str(" AND NOT EXISTS (SELECT 1 FROM tag_item WHERE tag_item.item = item.id)");
+    }
+    if let Some(added_after) = filter.added_after {
+        filter_cond.push_str(" AND COALESCE(item.updated_at, 0) >= ?");
+        args.add(added_after).map_err(sqlx::Error::Encode)?;
+    }
+    if let Some(added_before) = filter.added_before {
+        filter_cond.push_str(" AND COALESCE(item.updated_at, 0) < ?");
+        args.add(added_before).map_err(sqlx::Error::Encode)?;
+    }
+    if let Some(model_type) = &filter.model_type {
+        filter_cond.push_str(" AND item.model_type = ? COLLATE NOCASE");
+        args.add(model_type.clone()).map_err(sqlx::Error::Encode)?;
+    }
+    if let Some(max_nsfw_level) = filter.max_nsfw_level {
+        if filter.show_unknown_nsfw {
+            filter_cond.push_str(" AND (item.nsfw_level IS NULL OR item.nsfw_level <= ?)");
+        } else {
+            filter_cond.push_str(" AND item.nsfw_level <= ?");
+        }
+        args.add(max_nsfw_level).map_err(sqlx::Error::Encode)?;
+    }
+    if let Some(ext) = &filter.ext {
+        filter_cond.push_str(" AND LOWER(SUBSTR(item.path, -LENGTH(?) - 1)) = '.' || ?");
+        args.add(ext.clone()).map_err(sqlx::Error::Encode)?;
+        args.add(ext.clone()).map_err(sqlx::Error::Encode)?;
+    }
+    if !filter.base_labels.is_empty() {
+        let placeholders = vec!["?"; filter.base_labels.len()].join(", ");
+        filter_cond.push_str(&format!(" AND item.base_label IN ({placeholders})"));
+        for label in &filter.base_labels {
+            args.add(label.clone()).map_err(sqlx::Error::Encode)?;
+        }
+    }
+
+    Ok(filter_cond)
+}
 
-        let count_query = format!("SELECT COUNT(*) FROM (SELECT item.id {})", condition);
-        let tags_count: i64 = sqlx::query_scalar(&count_query).fetch_one(pool).await?;
+/// Items whose name matches `search`. Arguments are appended to `args`.
+fn name_condition(search: &str, filter: &SearchFilter, args: &mut SqliteArguments) -> Result<String, sqlx::Error> {
+    args.add(search.to_string()).map_err(sqlx::Error::Encode)?;
+    args.add(search.to_string()).map_err(sqlx::Error::Encode)?;
+    let filter_cond = filter_condition(filter, args)?;
+    Ok(format!(
+        "FROM item
+            WHERE is_checked = true
+                AND (name COLLATE NOCASE LIKE '%' || ? || '%'
+                  OR model_name COLLATE NOCASE LIKE '%' || ? || '%')
+                {}",
+        filter_cond,
+    ))
+}
 
-        count += tags_count;
-        items.extend(search_by_tags);
+/// Items which have all `tags`, except items whose name matches `exclude_search`. Arguments are appended to `args`.
+/// `extra_cond` must not have arguments.
+fn tags_condition(
+    tags: &[String],
+    exclude_search: Option<&str>,
+    filter: &SearchFilter,
+    extra_cond: &str,
+    args: &mut SqliteArguments,
+) -> Result<String, sqlx::Error> {
+    for tag in tags {
+        args.add(tag.clone()).map_err(sqlx::Error::Encode)?;
     }
+    let exclude_cond = match exclude_search {
+        Some(search) => {
+            args.add(search.to_string()).map_err(sqlx::Error::Encode)?;
+            args.add(search.to_string()).map_err(sqlx::Error::Encode)?;
+            "AND NOT (item.name COLLATE NOCASE LIKE '%' || ? || '%'
+                      OR item.model_name COLLATE NOCASE LIKE '%' || ? || '%')"
+        }
+        None => "",
+    };
+    let filter_cond = filter_condition(filter, args)?;
+    Ok(format!(
+        "FROM item
+              LEFT JOIN tag_item ON item.id = tag_item.item
+              LEFT JOIN tag ON tag.id = tag_item.tag
+              WHERE item.is_checked = true
+                AND tag.name IN ({})
+                {}
+                {}
+                {}
+              GROUP BY item.id
+              HAVING COUNT(DISTINCT tag.id) = {}",
+        vec!["?"; tags.len()].join(", "),
+        exclude_cond,
+        filter_cond,
+        extra_cond,
+        tags.len()
+    ))
+}
 
-    Ok((items.into_iter().collect(), count))
+/// Words of `search` as canonical tags
+async fn search_tags(pool: &SqlitePool, search: &str) -> Result<Vec<String>, sqlx::Error> {
+    let tags: Vec<String> = search.split_whitespace().map(db::tag::normalize_tag).collect();
+    db::tag::resolve_aliases(pool, tags).await
+}
+
+/// List all valid items of `base_label`. List all items if `base_label` is `None`.
+pub async fn list_by_label(pool: &SqlitePool, base_label: Option<&str>) -> Result<Vec<Item>, sqlx::Error> {
+    sqlx::query_as!(
+        Item,
+        r#"SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index FROM item
+        WHERE is_checked = true AND (? IS NULL OR base_label = ?)
+        ORDER BY id"#,
+        base_label,
+        base_label
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn is_indexed(pool: &SqlitePool, path: &str, base_label: &str) -> Result<bool, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        "SELECT count(id) FROM item WHERE is_checked = true AND path = ? AND base_label = ?",
+        path,
+        base_label
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count > 0)
 }
 
 pub async fn get_by_hash(pool: &SqlitePool, blake3: &str) -> Result<Item, sqlx::Error> {
     sqlx::query_as!(
         Item,
-        "SELECT id, name, path, base_label, note FROM item WHERE is_checked = true AND blake3 = ?",
+        "SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index FROM item WHERE is_checked = true AND blake3 = ?",
         blake3
     )
     .fetch_one(pool)
     .await
-}
\ No newline at end of file
+}
+
+pub async fn get_by_sha256(pool: &SqlitePool, sha256: &str) -> Result<Vec<Item>, sqlx::Error> {
+    sqlx::query_as!(
+        Item,
+        "SELECT id, name, path, base_label, note, updated_at, blake3, model_type, nsfw_level, model_name, preview_index FROM item WHERE is_checked = true AND sha256 = ?",
+        sha256
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{insert_test_item, DBPool};
+
+    /// Items matched by name and by tag, newest first
+    async fn search_pool() -> (DBPool, Vec<i64>) {
+        let db_pool = DBPool::memory().await;
+        let mut ids = Vec::new();
+        for (i, path) in ["lora_1", "other_2", "lora_3", "other_4", "lora_5"].iter().enumerate() {
+            let id = insert_test_item(&db_pool, path, path, i as i64 + 1).await;
+            if path.starts_with("other") {
+                db::tag::update_tags_and_note(&db_pool, id, "lora", "").await.unwrap();
+            }
+            ids.push(id);
+        }
+        insert_test_item(&db_pool, "unrelated", "unrelated", 10).await;
+        ids.reverse();
+        (db_pool, ids)
+    }
+
+    #[tokio::test]
+    async fn search_pages_do_not_exceed_limit() {
+        let (db_pool, ids) = search_pool().await;
+        let filter = SearchFilter::default();
+
+        let mut found = Vec::new();
+        for offset in [0, 2, 4] {
+            let (items, total) = search(&db_pool, "lora", 2, offset, None, &filter, Duration::ZERO, None)
+                .await
+                .unwrap();
+            assert!(items.len() <= 2);
+            assert_eq!(total, 5);
+            found.extend(items.iter().map(|item| item.id));
+        }
+        assert_eq!(found, ids);
+
+        // Keyset pagination gives the same pages
+        let mut found = Vec::new();
+        let mut after = None;
+        loop {
+            let (items, _) = search(&db_pool, "lora", 2, 0, after, &filter, Duration::ZERO, None)
+                .await
+                .unwrap();
+            assert!(items.len() <= 2);
+            let Some(last) = items.last() else {
+                break;
+            };
+            after = Some((last.updated_at.unwrap_or_default(), last.id));
+            found.extend(items.iter().map(|item| item.id));
+        }
+        assert_eq!(found, ids);
+    }
+
+    #[tokio::test]
+    async fn search_merges_name_and_tag_matches_in_order() {
+        let (db_pool, mut ids) = search_pool().await;
+        // Matched by both name and tag, with the same time as another item
+        let both = insert_test_item(&db_pool, "lora_both", "lora_both", 3).await;
+        db::tag::update_tags_and_note(&db_pool, both, "lora", "").await.unwrap();
+        ids.insert(2, both);
+
+        let filter = SearchFilter::default();
+        let (items, total) = search(&db_pool, "lora", 10, 0, None, &filter, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(total, 6);
+        assert_eq!(items.iter().map(|item| item.id).collect::<Vec<_>>(), ids);
+    }
+
+    #[tokio::test]
+    async fn cached_count_is_dropped_after_write() {
+        let (db_pool, _) = search_pool().await;
+        let filter = SearchFilter::default();
+        let ttl = Duration::from_secs(60);
+
+        let (_, total) = search(&db_pool, "lora", 2, 0, None, &filter, ttl, None).await.unwrap();
+        assert_eq!(total, 5);
+        insert_test_item(&db_pool, "lora_6", "lora_6", 6).await;
+        let (_, total) = search(&db_pool, "lora", 2, 0, None, &filter, ttl, None).await.unwrap();
+        assert_eq!(total, 6);
+    }
+
+    #[tokio::test]
+    async fn upsert_reports_changes_and_keeps_counts_of_unchanged_items() {
+        let (db_pool, _) = search_pool().await;
+        let filter = SearchFilter::default();
+        let ttl = Duration::from_secs(60);
+        let mut item = ItemUpsert {
+            name: Some("lora_6"),
+            model_name: "",
+            path: "lora_6",
+            base_label: "test",
+            blake3: "lora_6",
+            sha256: None,
+            source_url: None,
+            updated_at_ms: 6,
+            model_type: "lora",
+        };
+        let id = match insert_or_update(&db_pool, &item).await.unwrap() {
+            UpsertResult::Inserted(id) => id,
+            _ => panic!("Item is not inserted"),
+        };
+
+        let (_, total) = search(&db_pool, "lora", 2, 0, None, &filter, ttl, None).await.unwrap();
+        assert_eq!(total, 6);
+        assert!(matches!(
+            insert_or_update(&db_pool, &item).await.unwrap(),
+            UpsertResult::Unchanged(unchanged) if unchanged == id
+        ));
+        assert_eq!(db_pool.count_cache.get(&format!("lora|{filter:?}"), ttl), Some(6));
+
+        item.updated_at_ms = 7;
+        assert!(matches!(
+            insert_or_update(&db_pool, &item).await.unwrap(),
+            UpsertResult::Updated(updated) if updated == id
+        ));
+        assert_eq!(db_pool.count_cache.get(&format!("lora|{filter:?}"), ttl), None);
+    }
+
+    #[tokio::test]
+    async fn updated_at_range_includes_start_and_excludes_end() {
+        let (db_pool, ids) = search_pool().await;
+        let filter = SearchFilter {
+            added_after: Some(2),
+            added_before: Some(4),
+            ..Default::default()
+        };
+
+        let (items, total) = search(&db_pool, "lora", 10, 0, None, &filter, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(total, 2);
+        // Updated at 3 and 2, newest first
+        assert_eq!(items.iter().map(|item| item.id).collect::<Vec<_>>(), ids[2..4]);
+    }
+
+    #[tokio::test]
+    async fn facets_count_all_pages_of_search() {
+        let (db_pool, ids) = search_pool().await;
+        let pool = &db_pool.sqlite_pool;
+        // Newest first: lora_5, other_4, lora_3, other_2, lora_1
+        db::tag::update_tags_and_note(&db_pool, ids[0], "style character", "")
+            .await
+            .unwrap();
+        db::tag::update_tags_and_note(&db_pool, ids[2], "style", "")
+            .await
+            .unwrap();
+        db::tag::update_tags_and_note(&db_pool, ids[4], "character", "")
+            .await
+            .unwrap();
+        let unrelated = insert_test_item(&db_pool, "unmatched", "unmatched", 20).await;
+        db::tag::update_tags_and_note(&db_pool, unrelated, "style unmatched", "")
+            .await
+            .unwrap();
+
+        let filter = SearchFilter::default();
+        let mut page_counts = HashMap::new();
+        for offset in [0, 3] {
+            let (items, _) = search(&db_pool, "lora", 3, offset, None, &filter, Duration::ZERO, None)
+                .await
+                .unwrap();
+            for item in items {
+                let tags: Vec<String> = sqlx::query_scalar(
+                    "SELECT tag.name FROM tag JOIN tag_item ON tag.id = tag_item.tag WHERE item = ?",
+                )
+                .bind(item.id)
+                .fetch_all(pool)
+                .await
+                .unwrap();
+                for tag in tags {
+                    *page_counts.entry(tag).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let (facets, total) = search_facets(pool, "lora", &filter, None, 0).await.unwrap();
+        let facet_counts = facets
+            .into_iter()
+            .map(|tag| (tag.tag, tag.count))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(total, 3);
+        assert_eq!(facet_counts, page_counts);
+        assert_eq!(facet_counts["style"], 2);
+    }
+
+    #[tokio::test]
+    async fn ext_filter_matches_only_that_extension() {
+        let db_pool = DBPool::memory().await;
+        let mut checkpoints = Vec::new();
+        for (i, path) in ["a.ckpt", "b.CKPT", "ckpt.safetensors", "c.xckpt", "d.ckpt.json"]
+            .iter()
+            .enumerate()
+        {
+            let id = insert_test_item(&db_pool, path, path, i as i64).await;
+            if i < 2 {
+                checkpoints.push(id);
+            }
+        }
+        checkpoints.reverse();
+        let filter = SearchFilter {
+            ext: Some("ckpt".to_string()),
+            ..Default::default()
+        };
+
+        let (items, total) = search(&db_pool, "", 10, 0, None, &filter, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(items.iter().map(|item| item.id).collect::<Vec<_>>(), checkpoints);
+    }
+
+    #[tokio::test]
+    async fn untagged_filter_combines_with_search() {
+        let (db_pool, ids) = search_pool().await;
+        let filter = SearchFilter {
+            untagged_only: true,
+            ..Default::default()
+        };
+
+        // Tagged items matched by their tag are left out, as is the untagged item not matched by the search
+        let (items, total) = search(&db_pool, "lora", 10, 0, None, &filter, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(
+            items.iter().map(|item| item.id).collect::<Vec<_>>(),
+            [ids[0], ids[2], ids[4]]
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_groups_are_counted_once() {
+        let db_pool = DBPool::memory().await;
+        let mut ids = HashMap::new();
+        for (i, (path, blake3)) in [
+            ("model_a1", "aaa"),
+            ("model_b1", "bbb"),
+            ("model_a2", "aaa"),
+            ("model_c", "ccc"),
+            ("model_a3", "aaa"),
+            ("model_b2", "bbb"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            ids.insert(path, insert_test_item(&db_pool, path, blake3, i as i64).await);
+        }
+        let found = |duplicate_only, group_duplicates| {
+            let db_pool = &db_pool;
+            async move {
+                let filter = SearchFilter {
+                    duplicate_only,
+                    group_duplicates,
+                    ..Default::default()
+                };
+                let (items, total) = search(db_pool, "model", 10, 0, None, &filter, Duration::ZERO, None)
+                    .await
+                    .unwrap();
+                let mut ids = items.into_iter().map(|item| item.id).collect::<Vec<_>>();
+                ids.sort();
+                (ids, total)
+            }
+        };
+
+        // All copies, or the first copy of each group
+        let (all, total) = found(true, false).await;
+        assert_eq!(total, 5);
+        assert_eq!(all.len(), 5);
+        assert!(!all.contains(&ids["model_c"]));
+        assert_eq!(found(true, true).await, (vec![ids["model_a1"], ids["model_b1"]], 2));
+        // Unique files are a group of one
+        assert_eq!(
+            found(false, true).await,
+            (vec![ids["model_a1"], ids["model_b1"], ids["model_c"]], 3)
+        );
+
+        let hashes = HashSet::from(["aaa".to_string(), "bbb".to_string(), "ccc".to_string()]);
+        let counts = count_by_hashes(&db_pool.sqlite_pool, &hashes).await.unwrap();
+        assert_eq!(
+            counts,
+            HashMap::from([("aaa".to_string(), 3), ("bbb".to_string(), 2), ("ccc".to_string(), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_group_is_represented_by_a_matched_copy() {
+        let db_pool = DBPool::memory().await;
+        let mut ids = Vec::new();
+        for (i, path) in ["foo", "bar", "bar_copy"].into_iter().enumerate() {
+            ids.push(insert_test_item(&db_pool, path, "aaa", i as i64).await);
+        }
+        db::tag::update_tags_and_note(&db_pool, ids[2], "bar", "")
+            .await
+            .unwrap();
+        let filter = SearchFilter {
+            group_duplicates: true,
+            ..Default::default()
+        };
+
+        // The lowest id copy doesn't match, so the lowest id of matched copies is returned
+        let (items, total) = search(&db_pool, "bar", 10, 0, None, &filter, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(items.iter().map(|item| item.id).collect::<Vec<_>>(), [ids[1]]);
+        let (items, _) = search(&db_pool, "bar", 10, 0, Some((10, 10)), &filter, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(items.iter().map(|item| item.id).collect::<Vec<_>>(), [ids[1]]);
+
+        // Tags are counted over the representative of each group only
+        let (facets, total) = search_facets(&db_pool.sqlite_pool, "bar", &filter, None, 0)
+            .await
+            .unwrap();
+        assert_eq!(total, 0);
+        assert!(facets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn searches_use_indexes() {
+        let (db_pool, _) = search_pool().await;
+        let plans = |filter: SearchFilter| {
+            let db_pool = &db_pool;
+            async move {
+                let mut debug = Vec::new();
+                search(db_pool, "lora", 2, 0, None, &filter, Duration::ZERO, Some(&mut debug))
+                    .await
+                    .unwrap();
+                debug
+                    .into_iter()
+                    .map(|query| (query.query, query.plan.join("\n")))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let default_plans = plans(SearchFilter::default()).await;
+        assert!(!default_plans.is_empty());
+        for (query, plan) in default_plans {
+            // Items are never read by a full table scan
+            assert!(!plan.contains("SCAN item"), "{query}: {plan}");
+            assert!(
+                plan.contains("USING INDEX item_is_checked_updated_at_index"),
+                "{query}: {plan}"
+            );
+            if query != "name_count" {
+                assert!(
+                    plan.contains("USING COVERING INDEX tag_item_item_tag_uindex"),
+                    "{query}: {plan}"
+                );
+            }
+        }
+
+        let filter = SearchFilter {
+            group_duplicates: true,
+            ..Default::default()
+        };
+        for (query, plan) in plans(filter).await {
+            // Copies are grouped over matched items, without reading other copies
+            assert!(!plan.contains("SCAN item"), "{query}: {plan}");
+        }
+    }
+}
@@ -19,6 +19,17 @@ pub struct Job {
     pub stopped_at: Option<i64>,
 }
 
+/// Same as `Job`, with times in unix seconds
+#[derive(Serialize)]
+pub struct JobActivity {
+    pub id: i64,
+    pub title: String,
+    pub desc: String,
+    pub state: i64,
+    pub started_at: i64,
+    pub stopped_at: Option<i64>,
+}
+
 pub async fn add_job(pool: &SqlitePool, title: &str, desc: &str) -> Result<i64, Error> {
     let id = sqlx::query!(
         r#"INSERT INTO job (title, desc, state) VALUES (?, ?, ?)
@@ -46,20 +57,70 @@ pub async fn update_job(pool: &SqlitePool, id: i64, desc: &str, state: JobState)
     Ok(())
 }
 
-#[allow(unused)]
-pub async fn get(pool: &SqlitePool, limit: i64, offset: i64) -> Result<(Vec<Job>, i64), sqlx::Error> {
+/// Update description of a running job
+pub async fn update_job_desc(pool: &SqlitePool, id: i64, desc: &str) -> Result<(), anyhow::Error> {
+    sqlx::query!(r#"UPDATE job SET desc = ? WHERE id = ?"#, desc, id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Jobs, newest first. `q` matches title or description of jobs, `state` is a `JobState`.
+pub async fn get(
+    pool: &SqlitePool,
+    q: Option<&str>,
+    state: Option<i64>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<Job>, i64), sqlx::Error> {
     let items = sqlx::query_as!(
         Job,
-        r#"SELECT * FROM job ORDER BY started_at DESC LIMIT ? OFFSET ?"#,
+        r#"SELECT * FROM job
+        WHERE (? IS NULL OR title COLLATE NOCASE LIKE '%' || ? || '%' OR desc COLLATE NOCASE LIKE '%' || ? || '%')
+            AND (? IS NULL OR state = ?)
+        ORDER BY started_at DESC, id DESC LIMIT ? OFFSET ?"#,
+        q,
+        q,
+        q,
+        state,
+        state,
         limit,
         offset
     )
     .fetch_all(pool)
     .await?;
 
-    let total = sqlx::query_scalar!("SELECT count(id) FROM job",)
-        .fetch_one(pool)
-        .await?;
+    let total = sqlx::query_scalar!(
+        r#"SELECT count(id) FROM job
+        WHERE (? IS NULL OR title COLLATE NOCASE LIKE '%' || ? || '%' OR desc COLLATE NOCASE LIKE '%' || ? || '%')
+            AND (? IS NULL OR state = ?)"#,
+        q,
+        q,
+        q,
+        state,
+        state
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((items, total))
+}
+
+/// Jobs, newest first, with their times in unix seconds
+pub async fn get_timeline(pool: &SqlitePool, limit: i64, offset: i64) -> Result<(Vec<JobActivity>, i64), sqlx::Error> {
+    let items = sqlx::query_as!(
+        JobActivity,
+        r#"SELECT id, title, desc, state,
+            CAST(strftime('%s', started_at) AS INTEGER) AS "started_at!: i64",
+            CAST(strftime('%s', stopped_at) AS INTEGER) AS "stopped_at: i64"
+        FROM job ORDER BY started_at DESC, id DESC LIMIT ? OFFSET ?"#,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let total = sqlx::query_scalar!("SELECT count(id) FROM job").fetch_one(pool).await?;
 
     Ok((items, total))
 }
@@ -1,15 +1,22 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
 use crate::civitai::CivitaiFileMetadata;
+use crate::db::DBPool;
+use indexmap::IndexSet;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{FromRow, SqlitePool};
-use std::collections::HashSet;
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
+use std::collections::{HashMap, HashSet};
+
+pub const BASE_MODEL_CATEGORY: &str = "Base Model";
 
 #[derive(Serialize, Deserialize, FromRow)]
 pub struct TagCount {
     pub tag: String,
     pub count: i64,
+    /// "Uncategorized" if tag has no category
+    pub category: String,
+    pub color: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,6 +27,19 @@ pub struct Tag {
     pub deps: Option<String>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct TagAlias {
+    pub alias: String,
+    /// Canonical tag name
+    pub tag: String,
+}
+
+/// Canonical form of a tag name: trimmed, lowercase, inner whitespace collapsed into a single `_`.
+/// Must be used wherever tags are written or queried.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.split_whitespace().collect::<Vec<_>>().join("_").to_lowercase()
+}
+
 pub async fn get_tag_by_name(pool: &SqlitePool, name: &str) -> Result<Tag, sqlx::Error> {
     sqlx::query_as!(Tag, r#"SELECT tmp.id as id, tmp.name as name , tmp.description as description, GROUP_CONCAT(tag.name, ' ') as "deps:_"
 FROM
@@ -35,15 +55,20 @@ LEFT JOIN tag ON tag.id = tmp.dep WHERE tmp.name = ? GROUP BY tmp.id
 //     Ok(())
 // }
 
-pub async fn delete(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
-    sqlx::query!("DELETE FROM tag WHERE id = ?", id).execute(pool).await?;
+pub async fn delete(db_pool: &DBPool, id: i64) -> anyhow::Result<()> {
+    sqlx::query!("DELETE FROM tag WHERE id = ?", id)
+        .execute(&db_pool.sqlite_pool)
+        .await?;
+    db_pool.count_cache.invalidate();
     Ok(())
 }
 
-pub async fn update_tag(pool: &SqlitePool, tag: &Tag) -> Result<(), sqlx::Error> {
+pub async fn update_tag(db_pool: &DBPool, tag: &Tag) -> Result<(), sqlx::Error> {
+    let pool = &db_pool.sqlite_pool;
+    let name = normalize_tag(&tag.name);
     sqlx::query!(
         "UPDATE tag SET name = ?, description = ? WHERE id = ?",
-        tag.name,
+        name,
         tag.description,
         tag.id
     )
@@ -54,9 +79,9 @@ pub async fn update_tag(pool: &SqlitePool, tag: &Tag) -> Result<(), sqlx::Error>
         .deps
         .clone()
         .unwrap_or_default()
-        .to_lowercase()
         .split_whitespace()
-        .collect::<Vec<&str>>()
+        .map(normalize_tag)
+        .collect::<Vec<String>>()
     {
         let dep_id = match sqlx::query_scalar!("SELECT id FROM tag WHERE name = ?", dep)
             .fetch_one(pool)
@@ -74,12 +99,131 @@ pub async fn update_tag(pool: &SqlitePool, tag: &Tag) -> Result<(), sqlx::Error>
             .await?;
     }
 
+    db_pool.count_cache.invalidate();
+    Ok(())
+}
+
+/// Set category of a tag. Empty category means uncategorized.
+pub async fn set_category(pool: &SqlitePool, tag: &str, category: &str) -> Result<(), sqlx::Error> {
+    let category_id = if category.is_empty() { None } else { Some(get_or_add_category(pool, category).await?) };
+
+    let updated = sqlx::query!("UPDATE tag SET type = ? WHERE name = ?", category_id, tag)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+/// Set display color of a tag. `None` to remove the color.
+pub async fn set_color(pool: &SqlitePool, tag: &str, color: Option<&str>) -> Result<(), sqlx::Error> {
+    let updated = sqlx::query!("UPDATE tag SET color = ? WHERE name = ?", color, tag)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
     Ok(())
 }
 
-pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> Result<(), sqlx::Error> {
+/// Map `alias` to canonical tag `tag`.
+/// An alias cannot point to another alias or be a canonical tag itself, so there are no alias cycles.
+pub async fn add_alias(db_pool: &DBPool, alias: &str, tag: &str) -> anyhow::Result<()> {
+    if alias.is_empty() || tag.is_empty() {
+        return Err(anyhow::anyhow!("Alias and tag must not be empty"));
+    }
+    if alias == tag {
+        return Err(anyhow::anyhow!("Alias must be different from tag"));
+    }
+
+    let mut tx = db_pool.begin_write().await?;
+
+    let is_alias = sqlx::query_scalar!("SELECT count(alias) FROM tag_alias WHERE alias = ?", tag)
+        .fetch_one(&mut *tx)
+        .await?;
+    if is_alias > 0 {
+        return Err(anyhow::anyhow!("{tag} is an alias"));
+    }
+
+    let is_canonical = sqlx::query_scalar!(
+        "SELECT count(tag_alias.alias) FROM tag_alias JOIN tag ON tag.id = tag_alias.tag WHERE tag.name = ?",
+        alias
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if is_canonical > 0 {
+        return Err(anyhow::anyhow!("{alias} already has aliases"));
+    }
+
+    let tag_id = match sqlx::query_scalar!("SELECT id FROM tag WHERE name = ?", tag)
+        .fetch_one(&mut *tx)
+        .await
+    {
+        Ok(id) => id,
+        Err(_) => sqlx::query!("INSERT INTO tag (name) VALUES (?)", tag)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid(),
+    };
+
+    sqlx::query!(
+        "INSERT OR REPLACE INTO tag_alias (alias, tag) VALUES (?, ?)",
+        alias,
+        tag_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    db_pool.count_cache.invalidate();
+    Ok(())
+}
+
+pub async fn list_aliases(pool: &SqlitePool) -> Result<Vec<TagAlias>, sqlx::Error> {
+    sqlx::query_as!(
+        TagAlias,
+        r#"SELECT tag_alias.alias as alias, tag.name as tag FROM tag_alias
+            JOIN tag ON tag.id = tag_alias.tag
+            ORDER BY tag.name, tag_alias.alias"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Replace aliases by their canonical tags. Duplicated tags are removed.
+pub async fn resolve_aliases(pool: &SqlitePool, tags: Vec<String>) -> Result<Vec<String>, sqlx::Error> {
+    let mut resolved = IndexSet::new();
+    for tag in tags.iter().map(|tag| normalize_tag(tag)) {
+        let canonical = sqlx::query_scalar!(
+            "SELECT tag.name FROM tag_alias JOIN tag ON tag.id = tag_alias.tag WHERE tag_alias.alias = ?",
+            tag
+        )
+        .fetch_optional(pool)
+        .await?;
+        resolved.insert(canonical.unwrap_or(tag));
+    }
+
+    Ok(resolved.into_iter().collect())
+}
+
+async fn get_or_add_category(pool: &SqlitePool, category: &str) -> Result<i64, sqlx::Error> {
+    sqlx::query!("INSERT OR IGNORE INTO tag_type (type) VALUES (?)", category)
+        .execute(pool)
+        .await?;
+    sqlx::query_scalar!("SELECT id FROM tag_type WHERE type = ?", category)
+        .fetch_one(pool)
+        .await
+}
+
+/// The count cache of the pool must be invalidated after the change is committed
+pub async fn add_tag_item(conn: &mut SqliteConnection, item: i64, tags: &Vec<String>) -> Result<(), sqlx::Error> {
     let added_tags = sqlx::query_scalar!("SELECT tag FROM tag_item WHERE item = ?", item)
-        .fetch_all(pool)
+        .fetch_all(&mut *conn)
         .await?;
     let mut exist_tags = HashSet::new();
     exist_tags.extend(added_tags);
@@ -87,32 +231,31 @@ pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> R
     let mut depend_tags = HashSet::new();
 
     for tag in tags {
+        let tag = normalize_tag(tag);
         if tag.is_empty() {
             continue;
         }
 
-        let tag = tag.to_lowercase().to_string();
-
         let tag_id = match sqlx::query_scalar!("SELECT id FROM tag WHERE name = ?", tag)
-            .fetch_one(pool)
+            .fetch_one(&mut *conn)
             .await
         {
             Ok(id) => id,
             Err(_) => sqlx::query!("INSERT INTO tag (name) VALUES (?)", tag)
-                .execute(pool)
+                .execute(&mut *conn)
                 .await?
                 .last_insert_rowid(),
         };
 
         if !exist_tags.contains(&tag_id) {
             sqlx::query!("INSERT OR IGNORE INTO tag_item (item, tag) VALUES (?, ?)", item, tag_id)
-                .execute(pool)
+                .execute(&mut *conn)
                 .await?;
 
             exist_tags.insert(tag_id);
 
             let deps = sqlx::query_scalar!("SELECT dep FROM tag_tag WHERE tag = ?", tag_id)
-                .fetch_all(pool)
+                .fetch_all(&mut *conn)
                 .await?;
             depend_tags.extend(deps);
         }
@@ -123,12 +266,12 @@ pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> R
         for tag in depend_tags.iter() {
             if !exist_tags.contains(tag) {
                 sqlx::query!("INSERT OR IGNORE INTO tag_item (item, tag) VALUES (?, ?)", item, tag)
-                    .execute(pool)
+                    .execute(&mut *conn)
                     .await?;
                 exist_tags.insert(*tag);
 
                 let deps = sqlx::query_scalar!("SELECT dep FROM tag_tag WHERE tag = ?", tag)
-                    .fetch_all(pool)
+                    .fetch_all(&mut *conn)
                     .await?;
                 tmp_dep_tags.extend(deps);
             }
@@ -140,22 +283,24 @@ pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> R
     Ok(())
 }
 
+/// `base_models` tags are put into "Base Model" category if they are not categorized yet
 pub async fn add_tag_from_model_info(
-    pool: &SqlitePool,
+    db_pool: &DBPool,
     item: i64,
-    extra_tags: &Vec<String>,
+    base_models: &Vec<String>,
     model_info: &Value,
     file_metadata: &CivitaiFileMetadata,
 ) -> Result<(), sqlx::Error> {
     let mut tags = Vec::new();
-    for tag in extra_tags {
-        tags.push(tag.clone().replace(" ", "_").to_lowercase());
+    for tag in base_models {
+        tags.push(normalize_tag(tag));
     }
+    let base_model_tags = tags.clone();
 
     let nsfw = model_info["nsfw"].as_bool().unwrap_or(false);
     let poi = model_info["poi"].as_bool().unwrap_or(false);
     let model_type = model_info["type"].as_str().unwrap_or_default();
-    tags.push(model_type.replace(" ", "_").to_lowercase());
+    tags.push(normalize_tag(model_type));
     if nsfw {
         tags.push(String::from("nsfw"));
     }
@@ -164,46 +309,215 @@ pub async fn add_tag_from_model_info(
     }
     if let Some(json_tags) = model_info["tags"].as_array() {
         for tag in json_tags {
-            tags.push(tag.as_str().unwrap_or_default().replace(" ", "_").to_lowercase());
+            tags.push(normalize_tag(tag.as_str().unwrap_or_default()));
         }
     }
 
-    tags.push(file_metadata.format.clone().replace(" ", "_").to_lowercase());
+    tags.push(normalize_tag(&file_metadata.format));
     if let Some(fp) = &file_metadata.fp {
         tags.push(fp.to_string());
     }
     if let Some(size) = &file_metadata.size {
         tags.push(size.to_string());
     }
-    add_tag_item(pool, item, &tags).await
-}
+    let pool = &db_pool.sqlite_pool;
+    let tags = resolve_aliases(pool, tags).await?;
+    // The connection is released at once, so the queries below don't wait for a second one
+    add_tag_item(&mut *pool.acquire().await?, item, &tags).await?;
+    db_pool.count_cache.invalidate();
 
-pub async fn update_tag_item(pool: &SqlitePool, item: i64, tag_str: &str) -> anyhow::Result<()> {
-    sqlx::query!("DELETE FROM tag_item WHERE item = ?", item)
+    let category_id = get_or_add_category(pool, BASE_MODEL_CATEGORY).await?;
+    for tag in base_model_tags.iter().filter(|t| !t.is_empty()) {
+        sqlx::query!(
+            "UPDATE tag SET type = ? WHERE name = ? AND type IS NULL",
+            category_id,
+            tag
+        )
         .execute(pool)
         .await?;
-
-    let mut tags = Vec::new();
-    for tag in tag_str.split_whitespace() {
-        tags.push(tag.to_lowercase());
     }
-    add_tag_item(pool, item, &tags).await?;
+
     Ok(())
 }
 
-pub async fn update_item_note(pool: &SqlitePool, item: i64, note: &str) -> Result<(), sqlx::Error> {
+/// Set tags and note of an item in one transaction, so that neither is saved if the other fails.
+/// Only links of removed tags are deleted and only new tags are added.
+pub async fn update_tags_and_note(db_pool: &DBPool, item: i64, tag_str: &str, note: &str) -> Result<(), sqlx::Error> {
+    let mut tx = db_pool.begin_write().await?;
+
+    let tags = tag_str
+        .split_whitespace()
+        .map(normalize_tag)
+        .filter(|tag| !tag.is_empty())
+        .collect::<IndexSet<_>>();
+    let linked_tags = sqlx::query!(
+        "SELECT tag.id, tag.name FROM tag JOIN tag_item ON tag.id = tag_item.tag WHERE tag_item.item = ?",
+        item
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for linked_tag in linked_tags.iter().filter(|t| !tags.contains(&t.name)) {
+        sqlx::query!("DELETE FROM tag_item WHERE item = ? AND tag = ?", item, linked_tag.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let linked_names = linked_tags.into_iter().map(|t| t.name).collect::<HashSet<_>>();
+    let new_tags = tags.into_iter().filter(|tag| !linked_names.contains(tag)).collect();
+    add_tag_item(&mut tx, item, &new_tags).await?;
+
     sqlx::query!("UPDATE item SET note = ? WHERE id = ?", note, item)
-        .execute(pool)
+        .execute(&mut *tx)
         .await?;
 
+    tx.commit().await?;
+    db_pool.count_cache.invalidate();
     Ok(())
 }
 
+/// Normalize names of existing tags and aliases. Tags which become the same are merged.
+/// Return number of changed tags.
+pub async fn normalize_all(db_pool: &DBPool) -> Result<usize, sqlx::Error> {
+    let mut tx = db_pool.begin_write().await?;
+    let tags = sqlx::query!("SELECT id, name FROM tag ORDER BY id")
+        .fetch_all(&mut *tx)
+        .await?;
+    let mut tag_ids: HashMap<String, i64> = tags.iter().map(|tag| (tag.name.clone(), tag.id)).collect();
+
+    let mut changed = 0;
+    for tag in tags {
+        let name = normalize_tag(&tag.name);
+        if name == tag.name {
+            continue;
+        }
+        changed += 1;
+
+        if name.is_empty() {
+            sqlx::query!("DELETE FROM tag WHERE id = ?", tag.id)
+                .execute(&mut *tx)
+                .await?;
+            continue;
+        }
+
+        match tag_ids.get(&name).copied() {
+            Some(target) => {
+                sqlx::query!(
+                    "INSERT OR IGNORE INTO tag_item (item, tag) SELECT item, ? FROM tag_item WHERE tag = ?",
+                    target,
+                    tag.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!(
+                    "INSERT OR IGNORE INTO tag_tag (tag, dep) SELECT ?, dep FROM tag_tag WHERE tag = ? AND dep != ?",
+                    target,
+                    tag.id,
+                    target
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!(
+                    "INSERT OR IGNORE INTO tag_tag (tag, dep) SELECT tag, ? FROM tag_tag WHERE dep = ? AND tag != ?",
+                    target,
+                    tag.id,
+                    target
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!("UPDATE tag_alias SET tag = ? WHERE tag = ?", target, tag.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM tag WHERE id = ?", tag.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query!("UPDATE tag SET name = ? WHERE id = ?", name, tag.id)
+                    .execute(&mut *tx)
+                    .await?;
+                tag_ids.insert(name, tag.id);
+            }
+        }
+    }
+
+    let aliases = sqlx::query_scalar!("SELECT alias FROM tag_alias")
+        .fetch_all(&mut *tx)
+        .await?;
+    for alias in aliases {
+        let normalized = normalize_tag(&alias);
+        if normalized != alias {
+            sqlx::query!(
+                "UPDATE OR IGNORE tag_alias SET alias = ? WHERE alias = ?",
+                normalized,
+                alias
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+    db_pool.count_cache.invalidate();
+    Ok(changed)
+}
+
+/// Count tags over all valid items, most used first. If `search` is not empty, only items whose name contains it are
+/// counted.
+pub async fn tag_cloud(pool: &SqlitePool, search: &str, limit: i64) -> Result<Vec<TagCount>, sqlx::Error> {
+    sqlx::query_as!(
+        TagCount,
+        r#"SELECT tag.name as tag, COUNT(item.id) as count,
+                COALESCE(tag_type.type, 'Uncategorized') as "category!: String",
+                tag.color as color
+            FROM tag
+            JOIN tag_item ON tag.id = tag_item.tag
+            JOIN item ON item.id = tag_item.item
+            LEFT JOIN tag_type ON tag_type.id = tag.type
+            WHERE item.is_checked = true
+                AND (? = ''
+                  OR item.name COLLATE NOCASE LIKE '%' || ? || '%'
+                  OR item.model_name COLLATE NOCASE LIKE '%' || ? || '%')
+            GROUP BY tag.id ORDER BY count DESC, tag.name LIMIT ?"#,
+        search,
+        search,
+        search,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Tags which are applied together with `tag` on valid items, most often first. `tag` itself is excluded.
+pub async fn cooccur(pool: &SqlitePool, tag: &str, limit: i64) -> Result<Vec<TagCount>, sqlx::Error> {
+    sqlx::query_as!(
+        TagCount,
+        r#"SELECT other.name as tag, COUNT(item.id) as "count!: i64",
+                COALESCE(tag_type.type, 'Uncategorized') as "category!: String",
+                other.color as color
+            FROM tag
+            JOIN tag_item AS given ON given.tag = tag.id
+            JOIN tag_item AS related ON related.item = given.item AND related.tag != given.tag
+            JOIN tag AS other ON other.id = related.tag
+            JOIN item ON item.id = given.item
+            LEFT JOIN tag_type ON tag_type.id = other.type
+            WHERE tag.name = ? AND item.is_checked = true
+            GROUP BY other.id ORDER BY COUNT(item.id) DESC, other.name LIMIT ?"#,
+        tag,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
 pub async fn list_tags(pool: &SqlitePool, item_ids: HashSet<i64>) -> Result<Vec<TagCount>, sqlx::Error> {
     if item_ids.is_empty() {
         sqlx::query_as!(
             TagCount,
-            r#"SELECT tag.name as tag, COUNT(tag_item.tag) as count FROM tag
+            r#"SELECT tag.name as tag, COUNT(tag_item.tag) as count,
+                    COALESCE(tag_type.type, 'Uncategorized') as "category!: String",
+                    tag.color as color
+                FROM tag
+                LEFT JOIN tag_type ON tag_type.id = tag.type
                 LEFT JOIN tag_item ON tag.id = tag_item.tag
                 LEFT JOIN item ON item.id = tag_item.item
                 GROUP BY tag.name ORDER BY count DESC"#
@@ -213,8 +527,11 @@ pub async fn list_tags(pool: &SqlitePool, item_ids: HashSet<i64>) -> Result<Vec<
     } else {
         let placeholders = vec!["?"; item_ids.len()].join(",");
         let sql = format!(
-            "SELECT name as tag, count
-            FROM (SELECT tag_item.tag as tag_id, tag.name as name, COUNT(tag_item.tag) as count FROM tag
+            "SELECT name as tag, count, category, color
+            FROM (SELECT tag_item.tag as tag_id, tag.name as name, COUNT(tag_item.tag) as count,
+                    COALESCE(tag_type.type, 'Uncategorized') as category, tag.color as color
+                FROM tag
+                LEFT JOIN tag_type ON tag_type.id = tag.type
                 LEFT JOIN tag_item ON tag.id = tag_item.tag
                 LEFT JOIN item ON item.id = tag_item.item
                 WHERE item.is_checked = true
@@ -231,3 +548,178 @@ pub async fn list_tags(pool: &SqlitePool, item_ids: HashSet<i64>) -> Result<Vec<
         query.fetch_all(pool).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::item::{search, SearchFilter};
+    use crate::db::{insert_test_item, DBPool};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn search_by_alias_finds_canonical_tag() {
+        let db_pool = DBPool::memory().await;
+        let id = insert_test_item(&db_pool, "model", "model", 1).await;
+        update_tags_and_note(&db_pool, id, "character", "").await.unwrap();
+        add_alias(&db_pool, "chara", "character").await.unwrap();
+
+        let filter = SearchFilter::default();
+        let (items, total) = search(&db_pool, "chara", 10, 0, None, &filter, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(items[0].id, id);
+
+        // Rejected aliases leave nothing behind
+        assert!(add_alias(&db_pool, "character", "chara").await.is_err());
+        assert_eq!(list_aliases(&db_pool.sqlite_pool).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tags_are_normalized_on_insert() {
+        let db_pool = DBPool::memory().await;
+        let pool = &db_pool.sqlite_pool;
+        let id = insert_test_item(&db_pool, "model", "model", 1).await;
+        let mut conn = pool.acquire().await.unwrap();
+        add_tag_item(
+            &mut conn,
+            id,
+            &vec!["  Anime ".to_string(), "Dark  Fantasy".to_string()],
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        assert!(get_tag_by_name(pool, "anime").await.is_ok());
+        assert!(get_tag_by_name(pool, "dark_fantasy").await.is_ok());
+        let filter = SearchFilter::default();
+        for search_str in ["anime", "ANIME", "dark_fantasy"] {
+            let (items, _) = search(&db_pool, search_str, 10, 0, None, &filter, Duration::ZERO, None)
+                .await
+                .unwrap();
+            assert_eq!(
+                items.iter().map(|item| item.id).collect::<Vec<_>>(),
+                vec![id],
+                "{search_str}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn normalize_all_merges_links_deps_and_aliases() {
+        let db_pool = DBPool::memory().await;
+        let pool = &db_pool.sqlite_pool;
+        let first = insert_test_item(&db_pool, "first", "first", 1).await;
+        let second = insert_test_item(&db_pool, "second", "second", 1).await;
+        // Tags which were saved before names were normalized
+        for (id, name) in [(1, "anime"), (2, " Anime"), (3, "Art Style"), (4, "other")] {
+            sqlx::query("INSERT INTO tag (id, name) VALUES (?, ?)")
+                .bind(id)
+                .bind(name)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+        for (item, tag) in [(first, 1), (first, 2), (second, 2)] {
+            sqlx::query("INSERT INTO tag_item (item, tag) VALUES (?, ?)")
+                .bind(item)
+                .bind(tag)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+        for (tag, dep) in [(2, 3), (4, 2)] {
+            sqlx::query("INSERT INTO tag_tag (tag, dep) VALUES (?, ?)")
+                .bind(tag)
+                .bind(dep)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("INSERT INTO tag_alias (alias, tag) VALUES ('Cartoon Style', 2)")
+            .execute(pool)
+            .await
+            .unwrap();
+
+        assert_eq!(normalize_all(&db_pool).await.unwrap(), 2);
+
+        let tags: Vec<(i64, String)> = sqlx::query_as("SELECT id, name FROM tag ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            tags,
+            vec![
+                (1, "anime".to_string()),
+                (3, "art_style".to_string()),
+                (4, "other".to_string())
+            ]
+        );
+        let links: Vec<(i64, i64)> = sqlx::query_as("SELECT item, tag FROM tag_item ORDER BY item, tag")
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        assert_eq!(links, vec![(first, 1), (second, 1)]);
+        let deps: Vec<(i64, i64)> = sqlx::query_as("SELECT tag, dep FROM tag_tag ORDER BY tag")
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        assert_eq!(deps, vec![(1, 3), (4, 1)]);
+        let aliases = list_aliases(pool).await.unwrap();
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(
+            (aliases[0].alias.as_str(), aliases[0].tag.as_str()),
+            ("cartoon_style", "anime")
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_note_update_keeps_tags() {
+        let db_pool = DBPool::memory().await;
+        let pool = &db_pool.sqlite_pool;
+        let id = insert_test_item(&db_pool, "model", "model", 1).await;
+        update_tags_and_note(&db_pool, id, "old", "note").await.unwrap();
+        sqlx::query("CREATE TRIGGER fail_note BEFORE UPDATE OF note ON item BEGIN SELECT RAISE(ABORT, 'no'); END")
+            .execute(pool)
+            .await
+            .unwrap();
+
+        assert!(update_tags_and_note(&db_pool, id, "new", "changed").await.is_err());
+        let tags = list_tags(pool, HashSet::from([id])).await.unwrap();
+        assert_eq!(tags.iter().map(|tag| tag.tag.as_str()).collect::<Vec<_>>(), vec!["old"]);
+    }
+
+    #[tokio::test]
+    async fn cooccurring_tags_are_ranked_by_count() {
+        let db_pool = DBPool::memory().await;
+        let mut ids = Vec::new();
+        for (path, tags) in [
+            ("a", "anime girl style"),
+            ("b", "anime girl"),
+            ("c", "anime girl outdoor"),
+            ("d", "girl realistic"),
+            ("obsolete", "anime style"),
+        ] {
+            let id = insert_test_item(&db_pool, path, path, 1).await;
+            update_tags_and_note(&db_pool, id, tags, "").await.unwrap();
+            ids.push(id);
+        }
+        crate::db::item::mark_obsolete(&db_pool, &ids[4..]).await.unwrap();
+
+        let pool = &db_pool.sqlite_pool;
+        let ranked = |limit| async move {
+            let tags = cooccur(pool, "anime", limit).await.unwrap();
+            tags.into_iter().map(|tag| (tag.tag, tag.count)).collect::<Vec<_>>()
+        };
+        // Ties are ordered by name. The given tag, tags which never co-occur with it and obsolete items are left out.
+        assert_eq!(
+            ranked(10).await,
+            [
+                ("girl".to_string(), 3),
+                ("outdoor".to_string(), 1),
+                ("style".to_string(), 1)
+            ]
+        );
+        assert_eq!(ranked(1).await, [("girl".to_string(), 3)]);
+    }
+}
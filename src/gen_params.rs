@@ -0,0 +1,165 @@
+//! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
+//!
+//! Read generation parameters which A1111/ComfyUI embed in PNG text chunks.
+
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+#[derive(Serialize, Default)]
+pub struct GenParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    /// Other settings like `Steps`, `Sampler`, `CFG scale`, `Seed`, `Size`, `Model`
+    #[serde(skip_serializing_if = "IndexMap::is_empty")]
+    pub settings: IndexMap<String, String>,
+    /// ComfyUI workflow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workflow: Option<Value>,
+}
+
+/// Parse generation parameters from PNG data. Return empty params if there is no embedded metadata.
+pub fn parse_png(data: &[u8]) -> GenParams {
+    let chunks = read_text_chunks(data);
+    let mut params = GenParams::default();
+
+    if let Some(text) = chunks.get("parameters") {
+        parse_parameters(text, &mut params);
+    }
+    if let Some(workflow) = chunks.get("workflow") {
+        params.workflow = serde_json::from_str(workflow).ok();
+    }
+
+    params
+}
+
+/// Read uncompressed text chunks (`tEXt` and `iTXt`). Malformed chunks are skipped.
+fn read_text_chunks(data: &[u8]) -> HashMap<String, String> {
+    let mut chunks = HashMap::new();
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return chunks;
+    }
+
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let chunk_type = &data[pos + 4..pos + 8];
+        let start = pos + 8;
+        let Some(end) = start.checked_add(len).filter(|end| *end <= data.len()) else {
+            break;
+        };
+        let body = &data[start..end];
+
+        match chunk_type {
+            b"tEXt" => {
+                if let Some((keyword, text)) = split_at_null(body) {
+                    // tEXt is Latin-1 encoded
+                    let text = text.iter().map(|&c| c as char).collect();
+                    chunks.insert(String::from_utf8_lossy(keyword).to_string(), text);
+                }
+            }
+            b"iTXt" => {
+                if let Some((keyword, text)) = parse_itxt(body) {
+                    chunks.insert(keyword, text);
+                }
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+
+        // Skip CRC
+        pos = end + 4;
+    }
+
+    chunks
+}
+
+/// Return (keyword, text) of an uncompressed iTXt chunk
+fn parse_itxt(body: &[u8]) -> Option<(String, String)> {
+    let (keyword, rest) = split_at_null(body)?;
+    // Compression flag and compression method
+    if rest.len() < 2 || rest[0] != 0 {
+        return None;
+    }
+    let (_language, rest) = split_at_null(&rest[2..])?;
+    let (_translated_keyword, text) = split_at_null(rest)?;
+
+    Some((
+        String::from_utf8_lossy(keyword).to_string(),
+        String::from_utf8_lossy(text).to_string(),
+    ))
+}
+
+fn split_at_null(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let pos = data.iter().position(|&c| c == 0)?;
+    Some((&data[..pos], &data[pos + 1..]))
+}
+
+/// Parse A1111 parameters:
+/// ```text
+/// prompt
+/// Negative prompt: negative prompt
+/// Steps: 20, Sampler: Euler a, CFG scale: 7, Seed: 1234, Size: 512x768
+/// ```
+fn parse_parameters(text: &str, params: &mut GenParams) {
+    let mut lines: Vec<&str> = text.lines().collect();
+    let settings_line = match lines.last() {
+        Some(line) if line.trim_start().starts_with("Steps:") => lines.pop(),
+        _ => None,
+    };
+
+    let mut prompt = Vec::new();
+    let mut negative_prompt = Vec::new();
+    for line in lines {
+        if let Some(negative) = line.strip_prefix("Negative prompt:") {
+            negative_prompt.push(negative.trim_start());
+        } else if !negative_prompt.is_empty() {
+            negative_prompt.push(line);
+        } else {
+            prompt.push(line);
+        }
+    }
+
+    let prompt = prompt.join("\n").trim().to_string();
+    if !prompt.is_empty() {
+        params.prompt = Some(prompt);
+    }
+    let negative_prompt = negative_prompt.join("\n").trim().to_string();
+    if !negative_prompt.is_empty() {
+        params.negative_prompt = Some(negative_prompt);
+    }
+
+    if let Some(line) = settings_line {
+        for setting in split_settings(line) {
+            if let Some((key, value)) = setting.split_once(':') {
+                let value = value.trim().trim_matches('"');
+                params.settings.insert(key.trim().to_string(), value.to_string());
+            }
+        }
+    }
+}
+
+/// Split settings by comma, except commas inside quotes like `Lora hashes: "a: 123, b: 456"`
+fn split_settings(line: &str) -> Vec<&str> {
+    let mut settings = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                settings.push(line[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    settings.push(line[start..].trim());
+
+    settings.into_iter().filter(|s| !s.is_empty()).collect()
+}
@@ -17,10 +17,13 @@ mod api;
 mod civitai;
 mod config;
 mod db;
+mod gen_params;
+mod render;
 mod ui;
+mod watcher;
 
 use crate::civitai::update_model_info;
-use crate::config::Config;
+use crate::config::{Config, CorsConfig};
 use crate::db::DBPool;
 use crate::ui::Broadcaster;
 use actix_cors::Cors;
@@ -39,6 +42,7 @@ use parking_lot::Mutex;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::error;
 use tracing_subscriber::EnvFilter;
 
 const BASE_PATH_PREFIX: &str = "base_";
@@ -58,6 +62,8 @@ struct Cli {
 struct ConfigData {
     config: RwLock<Config>,
     config_path: PathBuf,
+    /// Address which the server is listening on. Config changes take effect after restart.
+    bind_addr: String,
 }
 
 #[tokio::main]
@@ -92,14 +98,34 @@ async fn main() -> anyhow::Result<()> {
             }
         };
 
-        let listen_addr = format!("{}:{}", &config.listen_addr, &config.listen_port);
+        let listen_addr = config.bind_addr();
         let model_paths = config.model_paths.clone();
+        let real_model_paths = Arc::new(
+            model_paths
+                .values()
+                .filter_map(|base_path| std::fs::canonicalize(base_path).ok())
+                .collect::<Vec<_>>(),
+        );
         let ref_db_pool = Arc::new(db_pool);
         let config_data = Arc::new(ConfigData {
             config: RwLock::new(config.clone()),
             config_path: args.config.clone(),
+            bind_addr: listen_addr.clone(),
         });
-        let broadcaster = Broadcaster::create();
+        let broadcaster = Broadcaster::create(&ref_db_pool).await;
+        let templates = Arc::new(ui::load_templates()?);
+
+        // Keep the watcher alive until the server stops
+        let _watcher = if config.watch {
+            watcher::spawn(&config, ref_db_pool.clone(), broadcaster.clone())
+                .inspect_err(|e| error!("Failed to watch model paths: {e}"))
+                .ok()
+        } else {
+            None
+        };
+        let trash_purger = api::spawn_trash_purger(&config, ref_db_pool.clone());
+        let scheduled_scan =
+            api::spawn_scheduled_scan(&config, config_data.clone(), ref_db_pool.clone(), broadcaster.clone());
 
         let srv = HttpServer::new({
             let stop_handle = stop_handle.clone();
@@ -107,20 +133,31 @@ async fn main() -> anyhow::Result<()> {
                 let enable_basic_auth =
                     !config.api.basic_auth_user.is_empty() || !config.api.basic_auth_pass.is_empty();
                 let mut app = App::new()
-                    .wrap(Cors::default().allow_any_origin())
                     .wrap(Condition::new(
                         enable_basic_auth,
-                        HttpAuthentication::basic(basic_auth_validator),
+                        HttpAuthentication::with_fn(basic_auth_validator),
                     ))
                     .wrap(middleware::NormalizePath::trim())
+                    .wrap(Condition::new(config.api.compress, middleware::Compress::default()))
+                    // Outermost so that preflight requests are not rejected by basic auth
+                    .wrap(Condition::new(
+                        !config.cors.allowed_origins.is_empty(),
+                        cors(&config.cors),
+                    ))
                     .app_data(Data::from(stop_handle.clone()))
                     .app_data(Data::from(ref_db_pool.clone()))
                     .app_data(Data::from(config_data.clone()))
-                    .app_data(Data::from(Arc::clone(&broadcaster)));
+                    .app_data(Data::from(Arc::clone(&broadcaster)))
+                    .app_data(Data::from(templates.clone()));
+                // Base paths are resolved when the server starts
                 for (label, base_path) in model_paths.iter() {
-                    app = app.service(
-                        Files::new(format!("/{}{}", BASE_PATH_PREFIX, label).as_str(), base_path).show_files_listing(),
-                    );
+                    let trash_path = config.trash_path(label).unwrap_or_default();
+                    app = app.service(preview_files(
+                        label,
+                        Path::new(base_path),
+                        trash_path,
+                        real_model_paths.clone(),
+                    ));
                 }
 
                 app = app.service(web::scope("").configure(api::scope_config).configure(ui::scope_config));
@@ -135,6 +172,9 @@ async fn main() -> anyhow::Result<()> {
 
         // run server until stopped (either by ctrl-c or stop endpoint)
         let _ = srv.await;
+        for task in [trash_purger, scheduled_scan].into_iter().flatten() {
+            task.abort();
+        }
 
         if !stop_handle.read().await.is_restarted {
             break;
@@ -167,6 +207,25 @@ impl StopHandle {
     }
 }
 
+fn cors(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(vec!["GET", "POST", "DELETE"])
+        .allowed_headers(vec![
+            header::AUTHORIZATION,
+            header::ACCEPT,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+        ])
+        .expose_headers(vec![header::ETAG])
+        // Requests from the built-in UI are same origin and must not be blocked
+        .block_on_origin_mismatch(false)
+        .max_age(3600);
+    for origin in config.allowed_origins.iter() {
+        cors = cors.allowed_origin(origin);
+    }
+    cors
+}
+
 fn load_config(config_path: &Path) -> anyhow::Result<Config> {
     if config_path.exists() {
         match Config::load(config_path) {
@@ -180,13 +239,41 @@ fn load_config(config_path: &Path) -> anyhow::Result<Config> {
     }
 }
 
+/// Serve previews of a model path at the URLs built by `api::get_abs_path`. `Files` handles range requests and
+/// rejects `..` segments. Directories are not listed and trashed files are not served.
+fn preview_files(label: &str, base_path: &Path, trash_path: PathBuf, real_model_paths: Arc<Vec<PathBuf>>) -> Files {
+    let base = base_path.to_path_buf();
+    Files::new(format!("/{}{}", BASE_PATH_PREFIX, label).as_str(), base_path).path_filter(move |path, _| {
+        let path = base.join(path);
+        !path.starts_with(&trash_path) && is_in_model_paths(&real_model_paths, &path)
+    })
+}
+
+/// Whether `path` resolves inside one of `real_model_paths`. Files outside are not served even if they are reached
+/// by a symlink. Missing files are rejected as well, which gives 404 like `Files` does.
+fn is_in_model_paths(real_model_paths: &[PathBuf], path: &Path) -> bool {
+    std::fs::canonicalize(path).is_ok_and(|real_path| {
+        real_model_paths
+            .iter()
+            .any(|base_path| real_path.starts_with(base_path))
+    })
+}
+
+/// Credentials are optional to the middleware, so that public paths are served without them
 async fn basic_auth_validator(
     req: ServiceRequest,
-    credentials: BasicAuth,
+    credentials: Option<BasicAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    // Version is public for bug reports and upgrade checks
+    if req.path() == "/api/version" {
+        return Ok(req);
+    }
+
     let mut user_ok = false;
     let mut pass_ok = false;
-    if let Some(config) = req.app_data::<Data<ConfigData>>() {
+    if let Some(credentials) = credentials
+        && let Some(config) = req.app_data::<Data<ConfigData>>()
+    {
         let config = config.config.read().await;
         user_ok = config.api.basic_auth_user.is_empty() || credentials.user_id() == config.api.basic_auth_user;
         pass_ok = config.api.basic_auth_pass.is_empty()
@@ -202,3 +289,90 @@ async fn basic_auth_validator(
         Err((err, req))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn version_is_public_with_basic_auth() {
+        let mut config = Config::default();
+        config.api.basic_auth_user = "user".to_string();
+        config.api.basic_auth_pass = "pass".to_string();
+        let config = Data::new(ConfigData {
+            config: RwLock::new(config),
+            config_path: PathBuf::new(),
+            bind_addr: String::new(),
+        });
+        let app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::with_fn(basic_auth_validator))
+                .app_data(config)
+                .configure(api::scope_config),
+        )
+        .await;
+        let get = |uri: &str, auth: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(uri);
+            if let Some(auth) = auth {
+                req = req.insert_header((header::AUTHORIZATION, auth));
+            }
+            req.to_request()
+        };
+        // "user:pass" and "user:wrong"
+        let (valid, invalid) = ("Basic dXNlcjpwYXNz", "Basic dXNlcjp3cm9uZw==");
+
+        let res = test::call_service(&app, get("/api/version", None)).await;
+        assert!(res.status().is_success());
+        for auth in [None, Some(invalid)] {
+            let res = test::call_service(&app, get("/api/config", auth)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        let res = test::call_service(&app, get("/api/config", Some(valid))).await;
+        assert!(res.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn preview_files_serves_only_model_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("models");
+        let trash_path = model_path.join(".trash");
+        std::fs::create_dir_all(&trash_path).unwrap();
+        std::fs::write(model_path.join("model.preview.png"), b"preview").unwrap();
+        std::fs::write(trash_path.join("old.preview.png"), b"trashed").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), b"secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path(), model_path.join("link")).unwrap();
+
+        let real_model_paths = Arc::new(vec![std::fs::canonicalize(&model_path).unwrap()]);
+        let app = test::init_service(App::new().service(preview_files(
+            "test",
+            &model_path,
+            trash_path,
+            real_model_paths,
+        )))
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}test/model.preview.png", BASE_PATH_PREFIX))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(test::read_body(res).await, "preview");
+
+        for uri in [
+            "../secret.txt",
+            "%2e%2e/secret.txt",
+            "link/secret.txt",
+            ".trash/old.preview.png",
+            "",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/{}test/{}", BASE_PATH_PREFIX, uri))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert!(!res.status().is_success(), "{uri} should not be served");
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
+
+use pulldown_cmark::{html, Options, Parser};
+
+/// Render markdown (which may contain raw HTML) to HTML.
+/// Scripts and dangerous attributes are stripped so it's safe to display Civitai content.
+pub fn markdown_to_html(text: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let parser = Parser::new_ext(text, options);
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::clean(&unsafe_html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_is_rendered() {
+        let html = markdown_to_html("**bold** [link](https://civitai.com)");
+        assert!(html.contains("<strong>bold</strong>"));
+        assert!(html.contains(r#"href="https://civitai.com""#));
+    }
+
+    #[test]
+    fn scripts_and_handlers_are_stripped() {
+        let html = markdown_to_html(
+            r#"<script>alert(1)</script><img src="x.png" onerror="alert(2)"><a href="javascript:alert(3)">x</a>
+<iframe src="https://example.com"></iframe><p style="color: red" onclick="alert(4)">text</p>"#,
+        );
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("alert"));
+        assert!(!html.contains("onerror"));
+        assert!(!html.contains("onclick"));
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("<iframe"));
+        assert!(html.contains("text"));
+    }
+
+    #[test]
+    fn markdown_links_are_sanitized() {
+        let html = markdown_to_html("[x](javascript:alert(1)) ![y](data:text/html;base64,PHNjcmlwdD4=)");
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("data:text/html"));
+    }
+}
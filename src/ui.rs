@@ -1,6 +1,7 @@
 //! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
 
-use crate::api::SearchQuery;
+use crate::api::{SearchQuery, VERSION};
+use crate::db::{event, DBPool};
 use crate::ConfigData;
 use actix_files::Files;
 use actix_web::rt::time::interval;
@@ -14,18 +15,31 @@ use actix_web_lab::{
 use futures_util::future;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::VecDeque;
+use std::error::Error;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tera::Tera;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tracing::{error, info, warn};
 
-pub fn scope_config(cfg: &mut web::ServiceConfig) {
-    let tera = Tera::new("res/html/**/*").unwrap();
+/// Load all templates. Fail with the reason of the first broken template.
+pub fn load_templates() -> anyhow::Result<Tera> {
+    Tera::new("res/html/**/*").map_err(|e| {
+        let mut msg = e.to_string();
+        let mut source = e.source();
+        while let Some(cause) = source {
+            msg.push_str(&format!(": {cause}"));
+            source = cause.source();
+        }
+        anyhow::anyhow!("Failed to load templates: {msg}")
+    })
+}
 
-    cfg.app_data(Data::new(tera))
-        .service(index)
+pub fn scope_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(index)
         .service(get_item)
         .service(maintenance)
         .service(civitai)
@@ -38,32 +52,83 @@ pub fn scope_config(cfg: &mut web::ServiceConfig) {
         .service(Files::new("/js", "res/js"));
 }
 
-#[derive(Serialize, Deserialize)]
+/// Number of recent events which are replayed to new clients
+const EVENT_HISTORY_SIZE: usize = 50;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[repr(i64)]
 pub enum EventMsgLevel {
     Info,
     Warn,
     Error,
 }
 
-#[derive(Serialize)]
+impl EventMsgLevel {
+    fn from_i64(level: i64) -> Self {
+        match level {
+            0 => EventMsgLevel::Info,
+            1 => EventMsgLevel::Warn,
+            _ => EventMsgLevel::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct EventMsg {
     pub level: EventMsgLevel,
     pub msg: String,
+    /// Unix timestamp in seconds
+    pub time: i64,
+}
+
+impl EventMsg {
+    fn new(level: EventMsgLevel, msg: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        Self {
+            level,
+            msg: msg.to_string(),
+            time,
+        }
+    }
 }
 
 pub struct Broadcaster {
     inner: Mutex<BroadcasterInner>,
+    /// Error events are saved so that they are still shown after a restart
+    sqlite_pool: SqlitePool,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct BroadcasterInner {
     clients: Vec<mpsc::Sender<sse::Event>>,
+    history: VecDeque<EventMsg>,
 }
 impl Broadcaster {
-    /// Constructs new broadcaster and spawns ping loop.
-    pub fn create() -> Arc<Self> {
+    /// Constructs new broadcaster with the saved events and spawns ping loop.
+    pub async fn create(db_pool: &DBPool) -> Arc<Self> {
+        let history = match event::get_recent(&db_pool.sqlite_pool, EVENT_HISTORY_SIZE as i64).await {
+            Ok(events) => events
+                .into_iter()
+                .map(|e| EventMsg {
+                    level: EventMsgLevel::from_i64(e.level),
+                    msg: e.msg,
+                    time: e.created_at,
+                })
+                .collect(),
+            Err(e) => {
+                error!("Failed to load event history: {e}");
+                VecDeque::new()
+            }
+        };
         let this = Arc::new(Broadcaster {
-            inner: Mutex::new(BroadcasterInner::default()),
+            inner: Mutex::new(BroadcasterInner {
+                clients: Vec::new(),
+                history,
+            }),
+            sqlite_pool: db_pool.sqlite_pool.clone(),
         });
 
         Broadcaster::spawn_ping(Arc::clone(&this));
@@ -100,19 +165,40 @@ impl Broadcaster {
     }
 
     /// Registers client with broadcaster, returning an SSE response body.
+    ///
+    /// Recent events are sent first as `history` events, so they are not shown as new notifications.
     pub async fn new_client(&self) -> Sse<InfallibleStream<ReceiverStream<sse::Event>>> {
-        let (tx, rx) = mpsc::channel(10);
+        let (tx, rx) = mpsc::channel(EVENT_HISTORY_SIZE + 10);
 
         tx.send(sse::Data::new("connected").into()).await.unwrap();
 
+        let history = self.inner.lock().history.clone();
+        for msg in history {
+            if let Ok(data) = sse::Data::new_json(msg) {
+                let _ = tx.send(data.event("history").into()).await;
+            }
+        }
+
         self.inner.lock().clients.push(tx);
 
         Sse::from_infallible_receiver(rx)
     }
 
+    /// Recent events, oldest first
+    pub fn history(&self) -> Vec<EventMsg> {
+        self.inner.lock().history.iter().cloned().collect()
+    }
+
     /// Broadcasts `msg` to all clients.
     pub async fn broadcast(&self, msg: EventMsg) {
-        let clients = self.inner.lock().clients.clone();
+        let clients = {
+            let mut inner = self.inner.lock();
+            if inner.history.len() >= EVENT_HISTORY_SIZE {
+                inner.history.pop_front();
+            }
+            inner.history.push_back(msg.clone());
+            inner.clients.clone()
+        };
 
         if let Ok(msg) = sse::Data::new_json(msg) {
             let send_futures = clients.iter().map(|client| client.send(msg.clone().into()));
@@ -125,32 +211,51 @@ impl Broadcaster {
 
     pub async fn info(&self, msg: &str) {
         info!(msg);
-        let msg = EventMsg {
-            level: EventMsgLevel::Info,
-            msg: msg.to_string(),
-        };
-        self.broadcast(msg).await;
+        self.broadcast(EventMsg::new(EventMsgLevel::Info, msg)).await;
     }
 
     pub async fn warn(&self, msg: &str) {
         warn!(msg);
-        let msg = EventMsg {
-            level: EventMsgLevel::Warn,
-            msg: msg.to_string(),
-        };
-        self.broadcast(msg).await;
+        self.broadcast(EventMsg::new(EventMsgLevel::Warn, msg)).await;
     }
 
     pub async fn error(&self, msg: &str) {
         error!(msg);
-        let msg = EventMsg {
-            level: EventMsgLevel::Error,
-            msg: msg.to_string(),
-        };
+        let msg = EventMsg::new(EventMsgLevel::Error, msg);
+        if let Err(e) = event::add(
+            &self.sqlite_pool,
+            msg.level as i64,
+            &msg.msg,
+            msg.time,
+            EVENT_HISTORY_SIZE as i64,
+        )
+        .await
+        {
+            error!("Failed to save event: {e}");
+        }
         self.broadcast(msg).await;
     }
 }
 
+/// Context with values which are used by all templates
+fn new_context() -> tera::Context {
+    let mut ctx = tera::Context::new();
+    ctx.insert("version", VERSION);
+    ctx
+}
+
+fn render(tmpl: &Tera, name: &str, ctx: &tera::Context) -> HttpResponse {
+    match tmpl.render(name, ctx) {
+        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
+        Err(e) => {
+            error!("Failed to render {name}: {e:?}");
+            HttpResponse::InternalServerError()
+                .content_type("text/html")
+                .body(format!("Template error: {e}"))
+        }
+    }
+}
+
 #[get("/events")]
 async fn event_stream(broadcaster: Data<Broadcaster>) -> impl Responder {
     broadcaster.new_client().await
@@ -158,82 +263,47 @@ async fn event_stream(broadcaster: Data<Broadcaster>) -> impl Responder {
 
 #[get("/")]
 async fn index(tmpl: Data<Tera>, query_params: Query<SearchQuery>) -> impl Responder {
-    let mut ctx = tera::Context::new();
+    let mut ctx = new_context();
     ctx.insert("search", &query_params.search);
 
-    match tmpl.render("index.html", &ctx) {
-        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
-        Err(e) => HttpResponse::Ok()
-            .content_type("text/html")
-            .body(format!("Template error: {e}")),
-    }
+    render(&tmpl, "index.html", &ctx)
 }
 
 #[get("/item/{id}")]
 async fn get_item(tmpl: Data<Tera>, id: web::Path<i64>) -> impl Responder {
-    let mut ctx = tera::Context::new();
+    let mut ctx = new_context();
     ctx.insert("id", &id.into_inner());
-    match tmpl.render("item.html", &ctx) {
-        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
-        Err(e) => HttpResponse::Ok()
-            .content_type("text/html")
-            .body(format!("Template error: {e}")),
-    }
+    render(&tmpl, "item.html", &ctx)
 }
 
 #[get("/maintenance")]
 async fn maintenance(tmpl: Data<Tera>) -> impl Responder {
-    let ctx = tera::Context::new();
-    match tmpl.render("maintenance.html", &ctx) {
-        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
-        Err(e) => HttpResponse::Ok()
-            .content_type("text/html")
-            .body(format!("Template error: {e}")),
-    }
+    let ctx = new_context();
+    render(&tmpl, "maintenance.html", &ctx)
 }
 
 #[get("/civitai")]
 async fn civitai(tmpl: Data<Tera>, config_data: Data<ConfigData>) -> impl Responder {
-    let mut ctx = tera::Context::new();
+    let mut ctx = new_context();
     let config = config_data.config.read().await;
-    ctx.insert("config", &config.civitai);
-    match tmpl.render("civitai.html", &ctx) {
-        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
-        Err(e) => HttpResponse::Ok()
-            .content_type("text/html")
-            .body(format!("Template error: {e}")),
-    }
+    ctx.insert("config", &config.civitai.masked());
+    render(&tmpl, "civitai.html", &ctx)
 }
 
 #[get("/tag/{name}")]
 async fn tag(tmpl: Data<Tera>) -> impl Responder {
-    let ctx = tera::Context::new();
-    match tmpl.render("tag.html", &ctx) {
-        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
-        Err(e) => HttpResponse::Ok()
-            .content_type("text/html")
-            .body(format!("Template error: {e}")),
-    }
+    let ctx = new_context();
+    render(&tmpl, "tag.html", &ctx)
 }
 
 #[get("/setting")]
 async fn setting(tmpl: Data<Tera>) -> impl Responder {
-    let ctx = tera::Context::new();
-    match tmpl.render("config.html", &ctx) {
-        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
-        Err(e) => HttpResponse::Ok()
-            .content_type("text/html")
-            .body(format!("Template error: {e}")),
-    }
+    let ctx = new_context();
+    render(&tmpl, "config.html", &ctx)
 }
 
 #[get("/job")]
 async fn job(tmpl: Data<Tera>) -> impl Responder {
-    let ctx = tera::Context::new();
-    match tmpl.render("job.html", &ctx) {
-        Ok(template) => HttpResponse::Ok().content_type("text/html").body(template),
-        Err(e) => HttpResponse::Ok()
-            .content_type("text/html")
-            .body(format!("Template error: {e}")),
-    }
+    let ctx = new_context();
+    render(&tmpl, "job.html", &ctx)
 }
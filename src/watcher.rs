@@ -0,0 +1,91 @@
+//! Copyright (c) 2025 Trung Do <dothanhtrung@pm.me>.
+
+use crate::api::{get_relative_path, save_model_info};
+use crate::config::Config;
+use crate::db;
+use crate::db::DBPool;
+use crate::ui::Broadcaster;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Watch all model paths and index model files when they are created, modified or deleted.
+///
+/// Watching stops when the returned debouncer is dropped.
+pub fn spawn(
+    config: &Config,
+    db_pool: Arc<DBPool>,
+    broadcaster: Arc<Broadcaster>,
+) -> anyhow::Result<Debouncer<RecommendedWatcher>> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<PathBuf>();
+    let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, move |res: DebounceEventResult| match res {
+        Ok(events) => {
+            for event in events {
+                let _ = tx.send(event.path);
+            }
+        }
+        Err(e) => error!("Watch error: {e}"),
+    })?;
+
+    let watcher = debouncer.watcher();
+    for (label, base_path) in config.model_paths.iter() {
+        match watcher.watch(Path::new(base_path), RecursiveMode::Recursive) {
+            Ok(_) => info!("Watching {} ({})", label, base_path),
+            Err(e) => error!("Failed to watch {}: {}", base_path, e),
+        }
+    }
+
+    let config = config.clone();
+    tokio::spawn(async move {
+        while let Some(path) = rx.recv().await {
+            handle_change(&db_pool, &broadcaster, &config, &path).await;
+        }
+    });
+
+    Ok(debouncer)
+}
+
+async fn handle_change(db_pool: &DBPool, broadcaster: &Broadcaster, config: &Config, path: &Path) {
+    if !config.is_model_file(path) {
+        return;
+    }
+
+    for (label, base_path) in config.model_paths.iter() {
+        let Ok(relative_path) = get_relative_path(base_path, path) else {
+            continue;
+        };
+
+        // Same as scanning, ignore hidden and system directories (including trash)
+        let is_hidden = Path::new(&relative_path).components().any(|c| match c {
+            Component::Normal(name) => config.is_ignored_name(name.to_str().unwrap_or_default()),
+            _ => false,
+        });
+        if is_hidden {
+            return;
+        }
+
+        if path.is_file() {
+            if save_model_info(config, db_pool, path, label, relative_path.as_str(), false)
+                .await
+                .is_some()
+            {
+                broadcaster.info(&format!("Indexed {}", path.display())).await;
+            }
+        } else {
+            match db::item::mark_obsolete_by_path(db_pool, relative_path.as_str(), label).await {
+                Ok(result) if result.rows_affected() > 0 => {
+                    broadcaster.info(&format!("Marked {} obsolete", path.display())).await;
+                }
+                Ok(_) => {}
+                Err(e) => error!("Failed to mark {} obsolete: {}", path.display(), e),
+            }
+        }
+        return;
+    }
+}